@@ -0,0 +1,1286 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Settings for `combine` and the combine step of `pipeline`
+#[derive(clap::Args, Debug, Clone)]
+pub struct CombineOptions {
+    /// Output format of the combined book
+    #[arg(long, value_enum, default_value_t = OutputFormat::Markdown)]
+    pub format: OutputFormat,
+
+    /// Book title for EPUB, HTML and mdBook output (defaults to the PDF's title, then the book name)
+    #[arg(long)]
+    pub title: Option<String>,
+
+    /// Author for EPUB, HTML and mdBook output (defaults to the PDF's author)
+    #[arg(long)]
+    pub author: Option<String>,
+
+    /// Language code for EPUB, HTML and mdBook output
+    #[arg(long, default_value = "en")]
+    pub language: String,
+
+    /// Use the first page image as the EPUB cover
+    #[arg(long)]
+    pub cover: bool,
+
+    /// Renderer whose heading anchors the table of contents links to
+    #[arg(long, value_enum, default_value_t = AnchorStyle::Github)]
+    pub anchors: AnchorStyle,
+
+    /// Deepest heading level listed in the table of contents
+    #[arg(long, default_value_t = 6)]
+    pub toc_depth: usize,
+
+    /// Build the table of contents from transcribed headings even when the document has an outline
+    #[arg(long)]
+    pub toc_from_headings: bool,
+
+    /// Rewrite the levels of headings that match an outline entry to the outline's nesting
+    #[arg(long)]
+    pub outline_levels: bool,
+
+    /// Make heading levels consistent using the outline, text-layer font sizes and wording
+    /// ("Chapter 3", "3.2"), and turn headings that repeat like running heads into plain text
+    #[arg(long)]
+    pub normalize_headings: bool,
+
+    /// Keep running headers, footers and page numbers in the combined book
+    #[arg(long)]
+    pub keep_running_heads: bool,
+
+    /// Join sentences, code blocks and tables split across pages, and mend hyphenated words,
+    /// instead of separating pages with rules
+    #[arg(long)]
+    pub reflow: bool,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A single Markdown file with a table of contents
+    Markdown,
+    /// An EPUB 3 e-book, one chapter per top-level heading
+    Epub,
+    /// A single self-contained HTML page with a sidebar table of contents
+    Html,
+    /// An mdBook project directory, one file per top-level heading
+    Mdbook,
+}
+
+impl OutputFormat {
+    /// Default output name for a book: a file, or a directory for mdBook
+    pub fn output_name(&self, book_name: &str) -> String {
+        match self {
+            OutputFormat::Markdown => format!("{}.md", book_name),
+            OutputFormat::Epub => format!("{}.epub", book_name),
+            OutputFormat::Html => format!("{}.html", book_name),
+            OutputFormat::Mdbook => book_name.to_string(),
+        }
+    }
+}
+
+/// Document information manifest written by `extract` next to the page images
+pub const METADATA_FILE: &str = "metadata.json";
+
+/// Title and author from the source document's info dictionary
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DocumentInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+}
+
+impl DocumentInfo {
+    pub fn load(images_dir: &Path) -> Result<Self> {
+        let path = images_dir.join(METADATA_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        serde_json::from_str(&std::fs::read_to_string(&path)?)
+            .with_context(|| format!("Invalid metadata manifest {:?}", path))
+    }
+}
+
+// --- Table of Contents ---
+
+/// How a Markdown renderer derives heading IDs
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnchorStyle {
+    /// GitHub (and most CommonMark renderers using github-slugger)
+    Github,
+    /// GitLab: like GitHub, but runs of hyphens collapse into one
+    Gitlab,
+    /// Pandoc `auto_identifiers`: keeps `.`, drops anything before the first letter
+    Pandoc,
+    /// mdBook
+    Mdbook,
+}
+
+/// Generates heading IDs the way the target renderer does, numbering duplicates `-1`, `-2`, ...
+/// in document order. Every heading must go through it, including ones left out of the TOC.
+pub struct Slugger {
+    style: AnchorStyle,
+    seen: HashSet<String>,
+}
+
+impl Slugger {
+    pub fn new(style: AnchorStyle) -> Self {
+        Slugger { style, seen: HashSet::new() }
+    }
+
+    /// ID for a heading, given its display text (see `ParsedHeading::text`)
+    pub fn slug(&mut self, heading: &str) -> String {
+        // mdBook only lowercases ASCII
+        let text = match self.style {
+            AnchorStyle::Mdbook => heading.trim().to_ascii_lowercase(),
+            _ => heading.trim().to_lowercase(),
+        };
+        let mut base: String = match self.style {
+            // Pandoc drops disallowed punctuation first, then joins the words that are left
+            AnchorStyle::Pandoc => text
+                .chars()
+                .filter(|c| c.is_whitespace() || c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                .collect::<String>()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join("-"),
+            _ => text
+                .chars()
+                .filter_map(|c| match c {
+                    c if c.is_whitespace() => Some('-'),
+                    c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+                    _ => None,
+                })
+                .collect(),
+        };
+        match self.style {
+            AnchorStyle::Gitlab => {
+                while base.contains("--") {
+                    base = base.replace("--", "-");
+                }
+            }
+            AnchorStyle::Pandoc => {
+                // Identifiers must start with a letter
+                base = base.trim_start_matches(|c: char| !c.is_alphabetic()).to_string();
+                if base.is_empty() {
+                    base = "section".to_string();
+                }
+            }
+            AnchorStyle::Github | AnchorStyle::Mdbook => {}
+        }
+
+        let mut slug = base.clone();
+        let mut n = 0;
+        while self.seen.contains(&slug) {
+            n += 1;
+            slug = format!("{}-{}", base, n);
+        }
+        self.seen.insert(slug.clone());
+        slug
+    }
+}
+
+/// The only extensions that change where blocks, and so headings, begin. Every pass that looks
+/// for headings parses with these, and the renderers enable them too.
+pub fn block_options() -> Options {
+    Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES
+}
+
+/// Whether a heading's source is written with `#` markers rather than underlined
+pub fn is_atx(source: &str) -> bool {
+    let marker = source.len() - source.trim_start_matches('#').len();
+    (1..=6).contains(&marker) && source[marker..].chars().next().is_none_or(char::is_whitespace)
+}
+
+/// A heading as pulldown-cmark parses it
+#[derive(Debug, Clone)]
+pub struct ParsedHeading {
+    /// Byte offset of the heading (its first `#`, for ATX headings) in the source
+    pub start: usize,
+    pub level: usize,
+    pub atx: bool,
+    /// Inside a block quote, list item or footnote rather than at the top level
+    pub nested: bool,
+    /// Text as a renderer displays it: link targets, HTML tags, emphasis and code markers
+    /// and the closing `#` sequence removed, escaped or unpaired `*` and `_` kept
+    pub text: String,
+}
+
+/// Every heading in the Markdown, in document order. Collecting headings, splitting chapters
+/// and rendering all go through this parser, so they agree on which lines are headings and
+/// the IDs handed out in order land on the right ones.
+pub fn parse_headings(markdown: &str) -> Vec<ParsedHeading> {
+    let mut headings = Vec::new();
+    let mut containers = 0usize;
+    let mut current: Option<ParsedHeading> = None;
+    for (event, range) in Parser::new_ext(markdown, block_options()).into_offset_iter() {
+        match (event, &mut current) {
+            (Event::Start(Tag::BlockQuote(_) | Tag::Item | Tag::FootnoteDefinition(_)), _) => containers += 1,
+            (Event::End(TagEnd::BlockQuote(_) | TagEnd::Item | TagEnd::FootnoteDefinition), _) => {
+                containers = containers.saturating_sub(1)
+            }
+            (Event::Start(Tag::Heading { level, .. }), _) => {
+                current = Some(ParsedHeading {
+                    start: range.start,
+                    level: level as usize,
+                    atx: is_atx(&markdown[range]),
+                    nested: containers > 0,
+                    text: String::new(),
+                });
+            }
+            (Event::Text(t) | Event::Code(t), Some(heading)) => heading.text.push_str(&t),
+            (Event::SoftBreak | Event::HardBreak, Some(heading)) => heading.text.push(' '),
+            (Event::End(TagEnd::Heading(_)), _) => {
+                if let Some(mut heading) = current.take() {
+                    heading.text = heading.text.trim().to_string();
+                    headings.push(heading);
+                }
+            }
+            _ => {}
+        }
+    }
+    headings
+}
+
+/// Outline manifest written by `extract` next to the page images
+pub const OUTLINE_FILE: &str = "outline.json";
+
+/// One document outline (bookmark) entry, flattened in reading order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutlineEntry {
+    pub title: String,
+    /// Nesting depth, 1 for top-level entries
+    pub level: usize,
+    /// 1-based target page
+    pub page: usize,
+}
+
+pub fn load_outline(images_dir: &Path) -> Result<Option<Vec<OutlineEntry>>> {
+    let path = images_dir.join(OUTLINE_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let outline: Vec<OutlineEntry> = serde_json::from_str(&std::fs::read_to_string(&path)?)
+        .with_context(|| format!("Invalid outline manifest {:?}", path))?;
+    Ok((!outline.is_empty()).then_some(outline))
+}
+
+/// A Markdown heading in the combined book
+#[derive(Debug, Clone)]
+pub struct Heading {
+    /// Position in the page list
+    pub pos: usize,
+    pub page: usize,
+    /// Line index within the page
+    pub line: usize,
+    /// Byte offset of the `#` marker within its line, after any indent or container markers
+    pub column: usize,
+    pub level: usize,
+    pub title: String,
+    pub slug: String,
+}
+
+/// Join the pages as the combined book does without --reflow: each page after its anchor and
+/// followed by a rule. Also returns where each page's content starts.
+pub fn join_pages(pages: &[(usize, String)]) -> (String, Vec<usize>) {
+    let mut combined = String::new();
+    let mut starts = Vec::with_capacity(pages.len());
+    for (page_num, content) in pages {
+        combined.push_str(&format!("\n<a id='page_{}'></a>\n", page_num));
+        starts.push(combined.len());
+        combined.push_str(content);
+        combined.push_str("\n\n---\n\n");
+    }
+    (combined, starts)
+}
+
+/// All ATX headings in document order, with their anchors. The pages are parsed as one
+/// document, as the renderers see them, so a heading in a block quote counts and a code
+/// block left open at the end of a page hides the headings after it. Underlined headings
+/// aren't listed, but still take their IDs so duplicates are numbered as the renderer does.
+pub fn collect_headings(pages: &[(usize, String)], slugger: &mut Slugger) -> Vec<Heading> {
+    let (markdown, starts) = join_pages(pages);
+    parse_headings(&markdown)
+        .into_iter()
+        .filter_map(|h| {
+            let slug = slugger.slug(&h.text);
+            if !h.atx || starts.first().is_none_or(|&first| h.start < first) {
+                return None;
+            }
+            let pos = starts.partition_point(|&s| s <= h.start) - 1;
+            let before = &markdown[starts[pos]..h.start];
+            Some(Heading {
+                pos,
+                page: pages[pos].0,
+                line: before.matches('\n').count(),
+                column: before.len() - before.rfind('\n').map_or(0, |i| i + 1),
+                level: h.level,
+                title: h.text,
+                slug,
+            })
+        })
+        .collect()
+}
+
+/// Lowercase words only, for comparing outline titles with transcribed headings
+fn title_key(title: &str) -> String {
+    title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether two title keys name the same heading: equal, one containing the other
+/// ("chapter 3 regions" and "regions"), or nearly equal
+fn titles_match(a: &str, b: &str) -> bool {
+    a == b || (a.len().min(b.len()) >= 4 && (a.contains(b) || b.contains(a))) || similarity(a, b) >= MIN_SIMILARITY
+}
+
+/// For each outline entry, the transcribed heading it refers to. Headings are looked for on
+/// the target page and its neighbours, in order, so repeated titles match the right one.
+pub fn match_outline(outline: &[OutlineEntry], headings: &[Heading]) -> Vec<Option<usize>> {
+    let mut next = 0;
+    outline
+        .iter()
+        .map(|entry| {
+            let key = title_key(&entry.title);
+            let found = (next..headings.len())
+                .take_while(|&h| headings[h].page <= entry.page + 1)
+                .filter(|&h| headings[h].page + 1 >= entry.page)
+                .find(|&h| titles_match(&title_key(&headings[h].title), &key));
+            if let Some(h) = found {
+                next = h + 1;
+            }
+            found
+        })
+        .collect()
+}
+
+/// Rewrite matched headings to the outline's nesting depth. Returns the number changed.
+pub fn apply_outline_levels(
+    pages: &mut [(usize, String)],
+    headings: &mut [Heading],
+    outline: &[OutlineEntry],
+    matches: &[Option<usize>],
+) -> usize {
+    let mut changed = 0;
+    for (entry, h) in outline.iter().zip(matches) {
+        let Some(h) = *h else { continue };
+        let level = entry.level.min(6);
+        if headings[h].level != level {
+            set_heading_level(&mut pages[headings[h].pos].1, headings[h].line, headings[h].column, level);
+            headings[h].level = level;
+            changed += 1;
+        }
+    }
+    changed
+}
+
+/// Replace the `#` marker at `column` of the given line, keeping any indent or block quote
+/// and list markers before it; level 0 turns the heading into plain text
+pub fn set_heading_level(content: &mut String, line: usize, column: usize, level: usize) {
+    *content = content
+        .lines()
+        .enumerate()
+        .map(|(i, text)| {
+            if i != line || column > text.len() {
+                return text.to_string();
+            }
+            let (prefix, heading) = text.split_at(column);
+            let rest = heading.trim_start_matches('#').trim_start();
+            match level {
+                0 => format!("{}{}", prefix, rest),
+                _ => format!("{}{} {}", prefix, "#".repeat(level), rest),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+}
+
+/// One table of contents line: a heading (or outline entry) and where it links to
+#[derive(Debug, Clone)]
+pub struct TocEntry {
+    pub level: usize,
+    pub title: String,
+    /// Anchor ID, without the `#`
+    pub target: String,
+    pub page: usize,
+}
+
+/// Escape `title` for use as the text of a Markdown link, where a bracket would end it early
+pub fn link_text(title: &str) -> String {
+    title.replace('\\', "\\\\").replace('[', "\\[").replace(']', "\\]")
+}
+
+impl TocEntry {
+    /// Markdown list item, indented by level
+    pub fn to_markdown(&self) -> String {
+        let indent = "  ".repeat(self.level.saturating_sub(1));
+        format!(
+            "{}- [{}](#{}) *([Page {}](#page_{}))*",
+            indent,
+            link_text(&self.title),
+            self.target,
+            self.page,
+            self.page
+        )
+    }
+}
+
+/// Table of contents from the transcribed headings
+pub fn heading_toc(headings: &[Heading], depth: usize) -> Vec<TocEntry> {
+    headings
+        .iter()
+        .filter(|h| h.level <= depth)
+        .map(|h| TocEntry { level: h.level, title: h.title.clone(), target: h.slug.clone(), page: h.page })
+        .collect()
+}
+
+/// Table of contents from the document outline. Entries link to their matching heading, or to
+/// the page anchor when the transcription has no such heading. Entries for pages that weren't
+/// transcribed are left out, since there is nothing to link to.
+pub fn outline_toc(
+    outline: &[OutlineEntry],
+    headings: &[Heading],
+    matches: &[Option<usize>],
+    pages: &[(usize, String)],
+    depth: usize,
+) -> Vec<TocEntry> {
+    outline
+        .iter()
+        .zip(matches)
+        .filter(|(entry, h)| entry.level <= depth && (h.is_some() || pages.iter().any(|(n, _)| *n == entry.page)))
+        .map(|(entry, h)| TocEntry {
+            level: entry.level,
+            title: entry.title.trim().to_string(),
+            target: match h {
+                Some(h) => headings[*h].slug.clone(),
+                None => format!("page_{}", entry.page),
+            },
+            page: entry.page,
+        })
+        .collect()
+}
+
+// --- Heading Levels ---
+
+/// Lines of a page's text layer set larger than its body text, written by `extract` as
+/// `page_NNNN.fonts.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FontHints {
+    /// Most common font size on the page
+    pub body_size: f32,
+    pub lines: Vec<FontLine>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FontLine {
+    pub text: String,
+    pub size: f32,
+}
+
+/// Font hints for the given pages, keyed by page number. Pages without hints (scans, or
+/// books extracted before hints existed) are left out.
+pub fn load_font_hints(images_dir: &Path, pages: &[(usize, String)]) -> HashMap<usize, FontHints> {
+    pages
+        .iter()
+        .filter_map(|(page, _)| {
+            let path = images_dir.join(format!("page_{:04}.fonts.json", page));
+            let hints = serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+            Some((*page, hints))
+        })
+        .collect()
+}
+
+/// What the heading normalization pass did
+#[derive(Debug, Default)]
+pub struct HeadingStats {
+    pub from_outline: usize,
+    pub from_fonts: usize,
+    pub from_patterns: usize,
+    pub demoted: usize,
+    pub changed: usize,
+}
+
+const NUMBER_WORDS: &[&str] = &[
+    "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten", "eleven", "twelve",
+];
+
+/// Structural level implied by a heading's wording: parts, chapters and appendices, and
+/// numbered sections ("3.2 Regions" sits one level below its chapter)
+fn pattern_level(title: &str, chapter_level: usize) -> Option<usize> {
+    let lower = title.to_lowercase();
+    let mut words = lower.split_whitespace();
+    let first = words.next()?;
+    let second = words.next().unwrap_or("").trim_end_matches(['.', ':']);
+    let ordinal = !second.is_empty()
+        && (second.chars().all(|c| c.is_ascii_digit())
+            || second.chars().all(|c| "ivxlc".contains(c))
+            || NUMBER_WORDS.contains(&second));
+    match first {
+        "part" | "book" if ordinal => return Some(1),
+        "chapter" if ordinal => return Some(chapter_level),
+        "appendix" if ordinal || second.chars().count() == 1 => return Some(chapter_level),
+        _ => {}
+    }
+    let number = first.trim_end_matches(['.', ':', ')']);
+    let parts: Vec<&str> = number.split('.').collect();
+    let numbered = parts.len() > 1 && parts.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()));
+    numbered.then(|| chapter_level + parts.len() - 1)
+}
+
+/// Make heading levels consistent across the book. Headings that repeat like running heads
+/// (the same title on `MIN_REPEATS` or more nearby pages) become plain text. The rest take
+/// their level from, in order of preference: the matching outline entry, their wording
+/// ("Part II", "Chapter 3", "3.2"), or the font size of their line in the text layer.
+/// Headings with none of these nest below the closest heading before them that has one.
+pub fn normalize_headings(
+    pages: &mut [(usize, String)],
+    outline: Option<&[OutlineEntry]>,
+    fonts: &HashMap<usize, FontHints>,
+) -> HeadingStats {
+    let mut stats = HeadingStats::default();
+    let headings = collect_headings(pages, &mut Slugger::new(AnchorStyle::Github));
+    let matches = outline.map(|o| match_outline(o, &headings)).unwrap_or_default();
+    let mut levels: Vec<Option<usize>> = vec![None; headings.len()];
+    for (entry, h) in outline.unwrap_or_default().iter().zip(&matches) {
+        if let Some(h) = *h {
+            levels[h] = Some(entry.level.min(6));
+            stats.from_outline += 1;
+        }
+    }
+
+    // Running heads the model marked up as headings
+    let mut by_title: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, h) in headings.iter().enumerate() {
+        by_title.entry(title_key(&h.title)).or_default().push(i);
+    }
+    let mut demote = vec![false; headings.len()];
+    for group in by_title.values() {
+        let mut positions: Vec<usize> = group.iter().map(|&i| headings[i].pos).collect();
+        positions.dedup();
+        let repeated = pages_in_runs(&positions);
+        for &i in group {
+            if levels[i].is_none() && repeated.contains(&headings[i].pos) {
+                demote[i] = true;
+            }
+        }
+    }
+
+    // Wording: chapters sit under parts when the book has any
+    let has_parts = headings.iter().any(|h| pattern_level(&h.title, 2) == Some(1));
+    let chapter_level = if has_parts { 2 } else { 1 };
+    for (i, h) in headings.iter().enumerate() {
+        if levels[i].is_none() && !demote[i] {
+            if let Some(level) = pattern_level(&h.title, chapter_level) {
+                levels[i] = Some(level.min(6));
+                stats.from_patterns += 1;
+            }
+        }
+    }
+
+    // Font sizes, largest first. A size shared with a heading whose level is already known
+    // takes that level; any other size sits one level below the next larger size.
+    let mut body_sizes: Vec<f32> = fonts.values().map(|f| f.body_size).collect();
+    body_sizes.sort_by(f32::total_cmp);
+    let body = body_sizes.get(body_sizes.len() / 2).copied().unwrap_or(0.0);
+    let font_size = |h: &Heading| -> Option<f32> {
+        let key = title_key(&h.title);
+        fonts.get(&h.page)?.lines.iter()
+            .filter(|l| l.size >= body * 1.15)
+            .find(|l| titles_match(&title_key(&l.text), &key))
+            .map(|l| (l.size * 2.0).round() / 2.0)
+    };
+    let sizes: Vec<Option<f32>> = headings.iter().map(font_size).collect();
+    let mut ranks: Vec<f32> = sizes.iter().flatten().copied().collect();
+    ranks.sort_by(|a, b| b.total_cmp(a));
+    ranks.dedup();
+    let mut size_levels = Vec::with_capacity(ranks.len());
+    let mut current = 0;
+    for size in &ranks {
+        let known = (0..headings.len())
+            .filter(|&i| sizes[i] == Some(*size))
+            .filter_map(|i| levels[i])
+            .min();
+        current = known.unwrap_or(current + 1).min(6);
+        size_levels.push(current);
+    }
+    for (i, size) in sizes.iter().enumerate() {
+        if let (None, false, Some(size)) = (levels[i], demote[i], size) {
+            let rank = ranks.iter().position(|r| r == size).unwrap_or(0);
+            levels[i] = Some(size_levels[rank]);
+            stats.from_fonts += 1;
+        }
+    }
+
+    // Everything else keeps the model's level, but nests under the closest structured heading
+    // before it (or below the top level, before the first one)
+    let top = levels.iter().flatten().min().copied();
+    let mut parent = None;
+    for (i, h) in headings.iter().enumerate() {
+        let level = match (demote[i], levels[i], parent.or(top)) {
+            (true, _, _) => 0,
+            (false, Some(level), _) => {
+                parent = Some(level);
+                level
+            }
+            (false, None, Some(parent)) => h.level.max(parent + 1).min(6),
+            (false, None, None) => h.level,
+        };
+        if level != h.level {
+            set_heading_level(&mut pages[h.pos].1, h.line, h.column, level);
+            if level == 0 {
+                stats.demoted += 1;
+            } else {
+                stats.changed += 1;
+            }
+        }
+    }
+    stats
+}
+
+// --- Running Heads ---
+
+/// Non-blank lines at the top and at the bottom of each page checked for running heads
+const EDGE_LINES: usize = 3;
+
+/// A header or footer must repeat on at least this many pages in a row to be removed
+const MIN_REPEATS: usize = 3;
+
+/// Pages further apart than this break a run, so a "Summary" heading at the top of every
+/// chapter's last page is left alone. Two allows for alternating left/right page heads.
+const MAX_PAGE_GAP: usize = 2;
+
+/// Edit-distance similarity at which two edge lines count as the same running head
+const MIN_SIMILARITY: f64 = 0.8;
+
+/// Running heads are short; anything longer is content
+const MAX_HEAD_CHARS: usize = 80;
+
+/// Comparison key for a line at a page edge, or None if it can't be a running head.
+/// Markdown emphasis and heading markers are dropped and digit runs become `#`, so
+/// "**INSIDE MACINTOSH** I-123" and "INSIDE MACINTOSH I-124" share a key.
+fn edge_key(line: &str) -> Option<String> {
+    let line = line.trim();
+    let structural = line.starts_with('|')
+        || line.starts_with("<a ")
+        || line.starts_with("![")
+        || line.chars().all(|c| matches!(c, '-' | '*' | '_' | ' '));
+    if line.is_empty() || line.chars().count() > MAX_HEAD_CHARS || structural {
+        return None;
+    }
+
+    let mut key = String::new();
+    let mut last = ' ';
+    for c in line.trim_start_matches(['#', '>']).chars() {
+        let c = match c {
+            '*' | '_' | '`' => continue,
+            c if c.is_ascii_digit() => '#',
+            c if c.is_whitespace() => ' ',
+            c => c.to_ascii_lowercase(),
+        };
+        if (c == '#' && last == '#') || (c == ' ' && last == ' ') {
+            continue;
+        }
+        key.push(c);
+        last = c;
+    }
+    let key = key.trim().to_string();
+    key.chars().any(|c| c.is_alphanumeric() || c == '#').then_some(key)
+}
+
+/// Levenshtein similarity in 0..=1
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut row = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != cb);
+            row[j + 1] = substitution.min(prev[j + 1] + 1).min(row[j] + 1);
+        }
+        prev = row;
+    }
+    1.0 - prev[b.len()] as f64 / longest as f64
+}
+
+/// Indices of the first few non-blank lines, top down, and of the last few, bottom up
+fn edge_lines(lines: &[&str]) -> (Vec<usize>, Vec<usize>) {
+    let non_blank = || lines.iter().enumerate().filter(|(_, l)| !l.trim().is_empty()).map(|(i, _)| i);
+    (non_blank().take(EDGE_LINES).collect(), non_blank().rev().take(EDGE_LINES).collect())
+}
+
+/// A group of near-identical edge lines and the pages (by position) they appear on
+struct Cluster {
+    key: String,
+    pages: Vec<usize>,
+}
+
+/// The positions (sorted, deduplicated) that belong to a run of at least `MIN_REPEATS` pages
+/// no more than `MAX_PAGE_GAP` apart
+fn pages_in_runs(positions: &[usize]) -> Vec<usize> {
+    let mut keep = Vec::new();
+    let mut run: Vec<usize> = Vec::new();
+    for &p in positions {
+        if run.last().is_some_and(|&last| p - last > MAX_PAGE_GAP) {
+            if run.len() >= MIN_REPEATS {
+                keep.append(&mut run);
+            }
+            run.clear();
+        }
+        run.push(p);
+    }
+    if run.len() >= MIN_REPEATS {
+        keep.append(&mut run);
+    }
+    keep
+}
+
+/// Value of a roman numeral already known to be well-formed
+fn roman_value(numeral: &str) -> usize {
+    let digit = |c: char| match c {
+        'i' => 1,
+        'v' => 5,
+        'x' => 10,
+        'l' => 50,
+        'c' => 100,
+        'd' => 500,
+        _ => 1000,
+    };
+    let values: Vec<usize> = numeral.chars().map(digit).collect();
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| if values.get(i + 1).is_some_and(|&next| next > v) { -(v as isize) } else { v as isize })
+        .sum::<isize>() as usize
+}
+
+/// Remove running headers, footers and bare page numbers from the top and bottom of each
+/// page. Lines are grouped by fuzzy match, and a group counts as a running head where it
+/// repeats on `MIN_REPEATS` or more nearby pages. A bare number only counts as a page number
+/// when a nearby page has one that follows on from it. Lines are peeled off each edge until
+/// the first line that isn't one, so body text is never cut from the middle of a page.
+/// Returns the number of lines removed.
+pub fn strip_running_heads(pages: &mut [(usize, String)]) -> usize {
+    let page_number = Regex::new(
+        r"^[\s\-–—.·\[\]()]*(?:page\s+)?(?P<number>#|m{0,3}(?:cm|cd|d?c{0,3})(?:xc|xl|l?x{0,3})(?:ix|iv|v?i{0,3}))(?:\s+of\s+#)?[\s\-–—.·\[\]()]*$",
+    )
+    .expect("valid regex");
+    let digits = Regex::new(r"\d+").expect("valid regex");
+
+    // (page position, line index, cluster) for every edge line that could be a running head
+    let mut candidates = Vec::new();
+    // (page position, line index, value) for every edge line that could be a page number
+    let mut numbers = Vec::new();
+    let mut clusters: Vec<Cluster> = Vec::new();
+    let mut exact: HashMap<String, usize> = HashMap::new();
+    let mut removable = HashSet::new();
+    let mut edges = Vec::with_capacity(pages.len());
+
+    for (pos, (_, content)) in pages.iter().enumerate() {
+        let lines: Vec<&str> = content.lines().collect();
+        let (top, bottom) = edge_lines(&lines);
+        let mut seen = HashSet::new();
+        for &line_idx in top.iter().chain(&bottom) {
+            if !seen.insert(line_idx) {
+                continue;
+            }
+            let Some(key) = edge_key(lines[line_idx]) else { continue };
+            // The roman alternative also matches nothing at all, as in "Page" alone
+            if let Some(number) = page_number.captures(&key).map(|c| c["number"].to_string()) {
+                if !number.is_empty() {
+                    let value = match number.as_str() {
+                        "#" => digits.find(lines[line_idx]).and_then(|d| d.as_str().parse().ok()),
+                        numeral => Some(roman_value(numeral)),
+                    };
+                    if let Some(value) = value {
+                        numbers.push((pos, line_idx, value));
+                    }
+                    continue;
+                }
+            }
+
+            // Exact keys first; otherwise fuzzy-match only against groups seen on recent pages
+            let cluster = exact.get(&key).copied().or_else(|| {
+                clusters.iter().rposition(|c| {
+                    c.pages.last().is_some_and(|&p| pos - p <= MAX_PAGE_GAP)
+                        && similarity(&c.key, &key) >= MIN_SIMILARITY
+                })
+            });
+            let cluster = cluster.unwrap_or_else(|| {
+                clusters.push(Cluster { key: key.clone(), pages: Vec::new() });
+                clusters.len() - 1
+            });
+            exact.insert(key, cluster);
+            if clusters[cluster].pages.last() != Some(&pos) {
+                clusters[cluster].pages.push(pos);
+            }
+            candidates.push((pos, line_idx, cluster));
+        }
+        edges.push((top, bottom));
+    }
+
+    // A page number must step with the page: a nearby page's number differs by as many pages
+    // as the pages themselves. A lone "I" or a footnote "1" on every page doesn't.
+    for &(pos, line_idx, value) in &numbers {
+        let in_sequence = numbers.iter().any(|&(other, _, other_value)| {
+            other != pos
+                && other.abs_diff(pos) <= MAX_PAGE_GAP
+                && other_value as isize - value as isize == pages[other].0 as isize - pages[pos].0 as isize
+        });
+        if in_sequence {
+            removable.insert((pos, line_idx));
+        }
+    }
+
+    let repeated: Vec<Vec<usize>> = clusters.iter().map(|c| pages_in_runs(&c.pages)).collect();
+
+    for (pos, line_idx, cluster) in candidates {
+        if repeated[cluster].contains(&pos) {
+            removable.insert((pos, line_idx));
+        }
+    }
+
+    let remove: Vec<Vec<usize>> = edges
+        .into_iter()
+        .enumerate()
+        .map(|(pos, (top, bottom))| {
+            let peel = |edge: Vec<usize>| edge.into_iter().take_while(|&i| removable.contains(&(pos, i)));
+            let mut lines: Vec<usize> = peel(top).chain(peel(bottom)).collect();
+            lines.sort_unstable();
+            lines.dedup();
+            lines
+        })
+        .collect();
+
+    let mut removed = 0;
+    for ((_, content), lines) in pages.iter_mut().zip(remove) {
+        if lines.is_empty() {
+            continue;
+        }
+        removed += lines.len();
+        *content = content
+            .lines()
+            .enumerate()
+            .filter(|(i, _)| !lines.contains(i))
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .trim()
+            .to_string();
+    }
+    removed
+}
+
+// --- Reflow ---
+
+/// How a page's last block continues onto the next page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Continuation {
+    /// Sentence runs on; `hyphenated` when the last word is split with a hyphen
+    Paragraph { hyphenated: bool },
+    /// A fenced code block closed at the page end and reopened at the next page start
+    Code,
+    /// Table rows continue, usually under a repeated header
+    Table,
+}
+
+pub fn is_fence(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+fn is_table_row(line: &str) -> bool {
+    line.trim_start().starts_with('|')
+}
+
+fn is_table_separator(line: &str) -> bool {
+    let line = line.trim();
+    line.contains('-') && line.chars().all(|c| matches!(c, '|' | '-' | ':' | ' '))
+}
+
+fn table_columns(row: &str) -> usize {
+    row.trim().trim_matches('|').split('|').count()
+}
+
+/// Text that can carry a sentence across a page break: paragraphs, and at the end of a page,
+/// list items
+fn is_running_text(line: &str, allow_list_item: bool) -> bool {
+    let line = line.trim_start();
+    let Some(first) = line.chars().next() else { return false };
+    let list_item = matches!(first, '-' | '*' | '+') && line[1..].starts_with(' ')
+        || line.split_once(". ").is_some_and(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+    !matches!(first, '#' | '>' | '<' | '|' | '!' | '[')
+        && !is_fence(line)
+        && !line.chars().all(|c| matches!(c, '-' | '*' | '_' | ' '))
+        && (allow_list_item || !list_item)
+}
+
+/// Whether the text ends a sentence, ignoring closing quotes, brackets and emphasis
+fn ends_sentence(line: &str) -> bool {
+    line.trim_end()
+        .trim_end_matches(['"', '\'', '”', '’', ')', ']', '*', '_'])
+        .ends_with(['.', '!', '?', ':', '…'])
+}
+
+fn continuation(prev: &str, next: &str) -> Option<Continuation> {
+    let prev_lines: Vec<&str> = prev.lines().filter(|l| !l.trim().is_empty()).collect();
+    let tail = *prev_lines.last()?;
+    let head = next.lines().find(|l| !l.trim().is_empty())?;
+
+    if is_fence(tail) && is_fence(head) {
+        // The page must end with a closed block, not an unmatched fence
+        let fences = prev_lines.iter().filter(|l| is_fence(l)).count();
+        return (fences % 2 == 0).then_some(Continuation::Code);
+    }
+    if is_table_row(tail) && is_table_row(head) {
+        return (table_columns(tail) == table_columns(head)).then_some(Continuation::Table);
+    }
+    if is_running_text(tail, true) && is_running_text(head, false) && !ends_sentence(tail) {
+        let tail = tail.trim_end();
+        let hyphenated = tail.ends_with('-')
+            && tail[..tail.len() - 1].chars().last().is_some_and(char::is_alphabetic)
+            && head.trim_start().chars().next().is_some_and(char::is_alphabetic);
+        return Some(Continuation::Paragraph { hyphenated });
+    }
+    None
+}
+
+/// Lowercase word without surrounding punctuation
+fn word_key(word: &str) -> String {
+    word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase()
+}
+
+/// Join pages into one flowing text. Sentences, code blocks and tables cut by a page break are
+/// rejoined. A word hyphenated across the break loses its hyphen only when the book spells it
+/// without one elsewhere, so "exam-" + "ple" mends but "well-" + "known" stays a compound.
+/// Every page keeps its `<a id='page_N'></a>` anchor: inline at the join for running text, or
+/// just after the rejoined block for code and tables. Returns the text and the number of page
+/// breaks joined.
+pub fn reflow_pages(pages: &[(usize, String)]) -> (String, usize) {
+    let mut out = String::new();
+    let mut joined = 0;
+    let vocabulary: HashSet<String> = pages.iter().flat_map(|(_, c)| c.split_whitespace().map(word_key)).collect();
+
+    for (i, (page_num, content)) in pages.iter().enumerate() {
+        let anchor = format!("<a id='page_{}'></a>", page_num);
+        let content = content.trim();
+        let kind = match i {
+            0 => None,
+            _ => continuation(&pages[i - 1].1, content),
+        };
+        if kind.is_some() {
+            joined += 1;
+        }
+        let lines: Vec<&str> = content.lines().skip_while(|l| l.trim().is_empty()).collect();
+
+        match kind {
+            None => {
+                out.push_str(&format!("\n\n{}\n\n", anchor));
+                out.push_str(content);
+            }
+            Some(Continuation::Paragraph { hyphenated }) => {
+                let text = lines.join("\n");
+                if hyphenated {
+                    // "exam-" + "ple of" -> "example<a ...></a> of"; "Jean-" + "Paul" keeps its hyphen
+                    let word_end = text.find(char::is_whitespace).unwrap_or(text.len());
+                    let start = out.strip_suffix('-').unwrap_or(&out).split_whitespace().next_back().unwrap_or("");
+                    let joined_word = word_key(&format!("{}{}", start, &text[..word_end]));
+                    let compound = word_key(&format!("{}-{}", start, &text[..word_end]));
+                    if text.starts_with(char::is_lowercase)
+                        && vocabulary.contains(&joined_word)
+                        && !vocabulary.contains(&compound)
+                    {
+                        out.pop();
+                    }
+                    out.push_str(&text[..word_end]);
+                    out.push_str(&anchor);
+                    out.push_str(&text[word_end..]);
+                } else {
+                    if !out.ends_with(['—', '–']) {
+                        out.push(' ');
+                    }
+                    out.push_str(&anchor);
+                    out.push_str(&text);
+                }
+            }
+            Some(Continuation::Code) => {
+                // Drop the closing fence of the previous page and the reopening fence of this one
+                let trimmed = out.trim_end().len();
+                out.truncate(trimmed);
+                out.truncate(out.rfind('\n').map_or(0, |i| i + 1));
+                let close = lines.iter().skip(1).position(|l| is_fence(l)).map_or(lines.len(), |i| i + 2);
+                out.push_str(&lines[1..close].join("\n"));
+                out.push_str(&format!("\n\n{}\n\n", anchor));
+                out.push_str(lines[close..].join("\n").trim_start());
+            }
+            Some(Continuation::Table) => {
+                // Drop the repeated header and separator row; a header that differs from the
+                // previous page's is really the first continued row
+                let prev_header = pages[i - 1].1.lines().rev().take_while(|l| is_table_row(l)).last();
+                let mut rows = &lines[..];
+                if rows.get(1).is_some_and(|l| is_table_separator(l)) {
+                    if prev_header.is_none_or(|h| h.trim() != rows[0].trim()) {
+                        out.push('\n');
+                        out.push_str(rows[0]);
+                    }
+                    rows = &rows[2..];
+                }
+                let end = rows.iter().take_while(|l| is_table_row(l)).count();
+                for row in &rows[..end] {
+                    out.push('\n');
+                    out.push_str(row);
+                }
+                out.push_str(&format!("\n\n{}\n\n", anchor));
+                out.push_str(rows[end..].join("\n").trim_start());
+            }
+        }
+    }
+
+    (out.trim_start().to_string(), joined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(pages: &[&str]) -> Vec<(usize, String)> {
+        pages.iter().enumerate().map(|(i, p)| (i + 1, p.to_string())).collect()
+    }
+
+    fn outline_entry(title: &str, level: usize, page: usize) -> OutlineEntry {
+        OutlineEntry { title: title.to_string(), level, page }
+    }
+
+    #[test]
+    fn toc_lines_escape_brackets_in_titles() {
+        let entry = TocEntry { level: 2, title: r"Notes [draft] \ more".to_string(), target: "notes".to_string(), page: 4 };
+        assert_eq!(entry.to_markdown(), r"  - [Notes \[draft\] \\ more](#notes) *([Page 4](#page_4))*");
+    }
+
+    #[test]
+    fn outline_toc_skips_pages_that_were_not_transcribed() {
+        let pages = vec![(1, "# Preface\n\nText.".to_string()), (3, "Untitled page.".to_string())];
+        let headings = collect_headings(&pages, &mut Slugger::new(AnchorStyle::Github));
+        let outline = vec![
+            outline_entry("Preface", 1, 1),
+            outline_entry("Missing chapter", 1, 2),
+            outline_entry("Section", 2, 2),
+            outline_entry("Chapter Three", 1, 3),
+            outline_entry("Deep", 3, 3),
+        ];
+        let matches = match_outline(&outline, &headings);
+        let toc = outline_toc(&outline, &headings, &matches, &pages, 2);
+        let targets: Vec<(&str, &str)> = toc.iter().map(|e| (e.title.as_str(), e.target.as_str())).collect();
+        assert_eq!(targets, vec![("Preface", "preface"), ("Chapter Three", "page_3")]);
+    }
+
+    #[test]
+    fn strips_page_numbers_in_sequence() {
+        let mut pages = book(&["Alpha text.\n\n12", "Beta text.\n\n13", "Gamma text.\n\n- 14 -"]);
+        assert_eq!(strip_running_heads(&mut pages), 3);
+        assert_eq!(pages[0].1, "Alpha text.");
+        assert_eq!(pages[2].1, "Gamma text.");
+    }
+
+    #[test]
+    fn strips_roman_page_numbers_in_sequence() {
+        let mut pages = book(&["ix\n\nPreface text.", "x\n\nMore preface.", "Page xi\n\nEnd of preface."]);
+        assert_eq!(strip_running_heads(&mut pages), 3);
+        assert_eq!(pages[1].1, "More preface.");
+    }
+
+    #[test]
+    fn keeps_single_letter_lines_that_look_like_numerals() {
+        // The pronoun "I", a list marker and a drop cap, each appearing once
+        let mut pages = book(&["I\n\nwent home early.", "Body text.\n\nC", "D\n\nrop cap paragraph."]);
+        assert_eq!(strip_running_heads(&mut pages), 0);
+        assert!(pages[0].1.starts_with("I\n"));
+
+        // Matches nothing at all in the roman branch
+        let mut pages = book(&["Page\n\nOne.", "Page\n\nTwo."]);
+        assert_eq!(strip_running_heads(&mut pages), 0);
+    }
+
+    #[test]
+    fn keeps_numbers_that_dont_follow_the_pages() {
+        // A footnote marker repeated at the bottom of consecutive pages
+        let mut pages = book(&["First.\n\n1", "Second.\n\n1", "Third.\n\n1"]);
+        assert_eq!(strip_running_heads(&mut pages), 0);
+
+        // A number at the edge of one page only
+        let mut pages = book(&["Totals for the year:\n\n1984", "Unrelated page."]);
+        assert_eq!(strip_running_heads(&mut pages), 0);
+    }
+
+    /// Display text of a heading written as `# {source}`
+    fn heading_text(source: &str) -> String {
+        parse_headings(&format!("# {}", source)).remove(0).text
+    }
+
+    fn slugs(style: AnchorStyle, headings: &[&str]) -> Vec<String> {
+        let mut slugger = Slugger::new(style);
+        headings.iter().map(|h| slugger.slug(&heading_text(h))).collect()
+    }
+
+    #[test]
+    fn slugs_match_each_renderer() {
+        use AnchorStyle::*;
+        let cases: &[(&str, [&str; 4])] = &[
+            // Heading, then its ID on GitHub, GitLab, Pandoc and mdBook
+            ("C++ & Rust", ["c--rust", "c-rust", "c-rust", "c--rust"]),
+            ("Héllo Wörld", ["héllo-wörld", "héllo-wörld", "héllo-wörld", "héllo-wörld"]),
+            ("ÜBER Straße", ["über-straße", "über-straße", "über-straße", "Über-straße"]),
+            ("a -- b", ["a----b", "a-b", "a----b", "a----b"]),
+            ("a---b", ["a---b", "a-b", "a---b", "a---b"]),
+            ("🚀 Launch Day", ["-launch-day", "-launch-day", "launch-day", "-launch-day"]),
+            ("3.2 Regions", ["32-regions", "32-regions", "regions", "32-regions"]),
+            ("snake_case name", ["snake_case-name", "snake_case-name", "snake_case-name", "snake_case-name"]),
+            ("2024", ["2024", "2024", "section", "2024"]),
+            ("*Why* `unsafe`?", ["why-unsafe", "why-unsafe", "why-unsafe", "why-unsafe"]),
+        ];
+        for (heading, expected) in cases {
+            for (style, expected) in [Github, Gitlab, Pandoc, Mdbook].into_iter().zip(expected) {
+                assert_eq!(slugs(style, &[heading]), vec![expected.to_string()], "{:?} for {:?}", heading, style);
+            }
+        }
+    }
+
+    #[test]
+    fn numbers_duplicate_slugs() {
+        for style in [AnchorStyle::Github, AnchorStyle::Gitlab, AnchorStyle::Pandoc, AnchorStyle::Mdbook] {
+            assert_eq!(slugs(style, &["Intro", "Intro", "Intro"]), vec!["intro", "intro-1", "intro-2"]);
+            // A heading whose own slug is taken by a numbered duplicate gets numbered in turn
+            assert_eq!(slugs(style, &["Intro", "Intro", "Intro 1"]), vec!["intro", "intro-1", "intro-1-1"]);
+        }
+    }
+
+    #[test]
+    fn heading_text_drops_markup_only() {
+        assert_eq!(heading_text("_Note_"), "Note");
+        assert_eq!(heading_text("__Bold__ and *em*"), "Bold and em");
+        assert_eq!(heading_text("2 * 3 = 6"), "2 * 3 = 6");
+        assert_eq!(heading_text("snake_case names"), "snake_case names");
+        assert_eq!(heading_text("[Links](https://example.com) and `code` ##"), "Links and code");
+        assert_eq!(heading_text("<a id='x'></a>Anchored"), "Anchored");
+        assert_eq!(heading_text("1. Introduction"), "1. Introduction");
+    }
+
+    #[test]
+    fn collects_headings_in_block_quotes_and_lists() {
+        let pages = book(&["> # Quoted\n\nBody text.\n\n   # Indented", "- # Listed\n\n# Normal"]);
+        let headings = collect_headings(&pages, &mut Slugger::new(AnchorStyle::Github));
+        let found: Vec<(usize, usize, usize, &str)> =
+            headings.iter().map(|h| (h.pos, h.line, h.column, h.slug.as_str())).collect();
+        assert_eq!(found, vec![(0, 0, 2, "quoted"), (0, 4, 3, "indented"), (1, 0, 2, "listed"), (1, 2, 0, "normal")]);
+    }
+
+    #[test]
+    fn headings_after_a_fence_left_open_are_code() {
+        let pages = book(&["# Before\n\n```\nlet a = 1;", "# Hidden", "```\n\n# After"]);
+        let headings = collect_headings(&pages, &mut Slugger::new(AnchorStyle::Github));
+        let titles: Vec<&str> = headings.iter().map(|h| h.title.as_str()).collect();
+        assert_eq!(titles, vec!["Before", "After"]);
+    }
+
+    #[test]
+    fn underlined_headings_take_ids_but_are_not_listed() {
+        let pages = book(&["Intro\n=====\n\n# Intro"]);
+        let headings = collect_headings(&pages, &mut Slugger::new(AnchorStyle::Github));
+        assert_eq!(headings.len(), 1);
+        assert_eq!(headings[0].slug, "intro-1");
+    }
+
+    #[test]
+    fn sets_levels_after_container_markers() {
+        let mut content = "> # Quoted\n   ## Indented".to_string();
+        set_heading_level(&mut content, 0, 2, 3);
+        set_heading_level(&mut content, 1, 3, 0);
+        assert_eq!(content, "> ### Quoted\n   Indented");
+    }
+
+    #[test]
+    fn reflow_joins_sentences_with_the_anchor_inline() {
+        let (text, joined) = reflow_pages(&book(&["The quick brown", "fox jumps."]));
+        assert_eq!(joined, 1);
+        assert_eq!(text, "<a id='page_1'></a>\n\nThe quick brown <a id='page_2'></a>fox jumps.");
+    }
+
+    #[test]
+    fn reflow_keeps_page_breaks_after_finished_sentences() {
+        let (text, joined) = reflow_pages(&book(&["Done.", "Next page."]));
+        assert_eq!(joined, 0);
+        assert_eq!(text, "<a id='page_1'></a>\n\nDone.\n\n<a id='page_2'></a>\n\nNext page.");
+    }
+
+    #[test]
+    fn reflow_mends_words_hyphenated_across_the_break() {
+        let (text, _) = reflow_pages(&book(&["Take an exam-", "ple of an example."]));
+        assert_eq!(text, "<a id='page_1'></a>\n\nTake an example<a id='page_2'></a> of an example.");
+    }
+
+    #[test]
+    fn reflow_keeps_the_hyphen_of_compounds() {
+        let (text, _) = reflow_pages(&book(&["It is well-", "known that it works."]));
+        assert_eq!(text, "<a id='page_1'></a>\n\nIt is well-known<a id='page_2'></a> that it works.");
+
+        let (text, _) = reflow_pages(&book(&["Written by Jean-", "Paul and others"]));
+        assert!(text.contains("Jean-Paul<a id='page_2'></a> and others"));
+    }
+
+    #[test]
+    fn reflow_rejoins_code_blocks() {
+        let (text, joined) = reflow_pages(&book(&[
+            "Intro:\n\n```rust\nlet a = 1;\n```",
+            "```rust\nlet b = 2;\n```\n\nAfter.",
+        ]));
+        assert_eq!(joined, 1);
+        assert_eq!(
+            text,
+            "<a id='page_1'></a>\n\nIntro:\n\n```rust\nlet a = 1;\nlet b = 2;\n```\n\n<a id='page_2'></a>\n\nAfter."
+        );
+    }
+
+    #[test]
+    fn reflow_leaves_an_unclosed_fence_alone() {
+        let (_, joined) = reflow_pages(&book(&["```\nlet a = 1;", "```\nlet b = 2;\n```"]));
+        assert_eq!(joined, 0);
+    }
+
+    #[test]
+    fn reflow_rejoins_tables_without_the_repeated_header() {
+        let (text, joined) = reflow_pages(&book(&[
+            "| a | b |\n|---|---|\n| 1 | 2 |",
+            "| a | b |\n|---|---|\n| 3 | 4 |\n\nText.",
+        ]));
+        assert_eq!(joined, 1);
+        assert_eq!(
+            text,
+            "<a id='page_1'></a>\n\n| a | b |\n|---|---|\n| 1 | 2 |\n| 3 | 4 |\n\n<a id='page_2'></a>\n\nText."
+        );
+    }
+
+    #[test]
+    fn strips_repeated_running_heads() {
+        let mut pages = book(&[
+            "**INSIDE MACINTOSH**\n\nOne.",
+            "INSIDE MACINTOSH\n\nTwo.",
+            "Inside Macintosh\n\nThree.",
+        ]);
+        assert_eq!(strip_running_heads(&mut pages), 3);
+        assert_eq!(pages[2].1, "Three.");
+    }
+}
@@ -0,0 +1,192 @@
+use crate::validate::word_overlap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// One of the signals a page's confidence is scored from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Signal {
+    TextLayer,
+    Consensus,
+    TokenProbability,
+    SelfCheck,
+}
+
+impl Signal {
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::TextLayer => "text layer",
+            Signal::Consensus => "consensus",
+            Signal::TokenProbability => "token probability",
+            Signal::SelfCheck => "self-check",
+        }
+    }
+}
+
+/// How much to trust a transcribed page, from whichever signals were available
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Confidence {
+    /// Lowest of the signals below, from 0 to 1: a page is only as trustworthy as its weakest signal
+    pub score: f64,
+    /// Signal the score came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weakest: Option<Signal>,
+    /// Word agreement between the transcription and the PDF text layer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_layer: Option<f64>,
+    /// Share of lines all --consensus candidates read the same way
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub consensus: Option<f64>,
+    /// Geometric mean probability of the output tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_probability: Option<f64>,
+    /// The model's own accuracy rating from a --self-check pass
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_check: Option<f64>,
+}
+
+impl Confidence {
+    /// Fill in the overall score, or None when there is no signal to score the page by
+    pub fn scored(mut self) -> Option<Confidence> {
+        let signals = [
+            (Signal::TextLayer, self.text_layer),
+            (Signal::Consensus, self.consensus),
+            (Signal::TokenProbability, self.token_probability),
+            (Signal::SelfCheck, self.self_check),
+        ];
+        let (signal, score) = signals
+            .into_iter()
+            .filter_map(|(signal, value)| Some((signal, value?)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+        self.score = score;
+        self.weakest = Some(signal);
+        Some(self)
+    }
+
+    /// Name of the signal the score came from
+    pub fn weakest(&self) -> &'static str {
+        self.weakest.map_or("none", Signal::name)
+    }
+}
+
+/// Agreement between a transcription and a usable text layer: the harmonic mean of the share
+/// of transcribed words found in the layer and the share of the layer's words transcribed
+pub fn text_agreement(text: &str, layer: &str) -> f64 {
+    let precision = word_overlap(text, layer);
+    let recall = word_overlap(layer, text);
+    if precision + recall == 0.0 {
+        return 0.0;
+    }
+    2.0 * precision * recall / (precision + recall)
+}
+
+/// Prompt for the --self-check pass, sent with the page image
+pub fn self_check_prompt(transcription: &str) -> String {
+    format!(
+        "Below is a transcription of the attached page. Compare it with the image line by line and \
+        rate how accurately and completely it reproduces the printed text, from 0 (unrelated or mostly \
+        missing) to 100 (every word correct). Ignore Markdown formatting choices. Reply with the number only.\
+        \n<transcription>\n{}\n</transcription>",
+        transcription
+    )
+}
+
+/// Read the 0-100 rating out of a self-check reply, as a fraction. The rating has to be the
+/// reply's only number, either alone on its line or following "rating" or "score"; any other
+/// reply, or one on a scale other than /100, is too ambiguous to score the page by.
+pub fn parse_self_check(reply: &str) -> Option<f64> {
+    let number = Regex::new(r"(?i)(\d+(?:\.\d+)?)(?:\s*(?:/|out of)\s*(\d+(?:\.\d+)?)|\s*%)?")
+        .expect("valid regex");
+    let mut found = number.captures_iter(reply);
+    let rating = found.next()?;
+    if found.next().is_some() || rating.get(2).is_some_and(|scale| scale.as_str() != "100") {
+        return None;
+    }
+
+    let whole = rating.get(0)?;
+    let markup = |c: char| c.is_whitespace() || "*_`#>:=-.".contains(c);
+    // The rest of the rating's line on either side
+    let before = reply[..whole.start()].rsplit('\n').next().unwrap_or_default();
+    let before = before.trim_matches(markup).to_lowercase();
+    let after = reply[whole.end()..].split('\n').next().unwrap_or_default().trim_matches(markup);
+    let labelled = before.ends_with("rating") || before.ends_with("score");
+    let stands_alone = before.is_empty() && after.is_empty();
+    if !labelled && !stands_alone {
+        return None;
+    }
+
+    let rating: f64 = rating[1].parse().ok()?;
+    (0.0..=100.0).contains(&rating).then_some(rating / 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_lone_or_labelled_rating() {
+        for (reply, rating) in [
+            ("85", 0.85),
+            ("  **92**\n", 0.92),
+            ("70.5.", 0.705),
+            ("85/100", 0.85),
+            ("85 out of 100", 0.85),
+            ("85%", 0.85),
+            ("Rating: 60", 0.6),
+            ("Accuracy score - 40/100\nA footnote is missing.", 0.4),
+            ("I compared every line.\n\n100", 1.0),
+        ] {
+            assert_eq!(parse_self_check(reply), Some(rating), "{:?}", reply);
+        }
+    }
+
+    #[test]
+    fn rejects_ambiguous_replies() {
+        for reply in [
+            "",
+            "Looks accurate.",
+            // Not a rating
+            "The transcription has 3 errors.",
+            "I'd say 85.",
+            // More than one number
+            "Rating: 85. Line 12 is missing a word.",
+            "Between 80 and 90",
+            "Score (0-100): 85",
+            // Another scale
+            "8.5/10",
+            "Rating: 4 out of 5",
+            // Out of range
+            "150",
+        ] {
+            assert_eq!(parse_self_check(reply), None, "{:?}", reply);
+        }
+    }
+
+    #[test]
+    fn score_is_the_weakest_signal() {
+        let signals = Confidence {
+            text_layer: Some(0.9),
+            token_probability: Some(0.7),
+            self_check: Some(0.8),
+            ..Default::default()
+        };
+        let confidence = signals.scored().unwrap();
+        assert_eq!(confidence.score, 0.7);
+        assert_eq!(confidence.weakest, Some(Signal::TokenProbability));
+        assert_eq!(confidence.weakest(), "token probability");
+
+        // Ties go to the first signal
+        let tied = Confidence { consensus: Some(0.5), self_check: Some(0.5), ..Default::default() }.scored().unwrap();
+        assert_eq!(tied.weakest(), "consensus");
+
+        assert_eq!(Confidence::default().scored(), None);
+    }
+
+    #[test]
+    fn records_the_weakest_signal_in_the_sidecar() {
+        let confidence = Confidence { text_layer: Some(0.25), consensus: Some(0.75), ..Default::default() }.scored().unwrap();
+        let json = serde_json::to_string(&confidence).unwrap();
+        assert_eq!(json, r#"{"score":0.25,"weakest":"text_layer","text_layer":0.25,"consensus":0.75}"#);
+        assert_eq!(serde_json::from_str::<Confidence>(&json).unwrap(), confidence);
+    }
+}
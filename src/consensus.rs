@@ -0,0 +1,435 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+/// Lines at least this similar (by shared words) can be aligned as readings of the same line
+const MIN_LINE_SIMILARITY: f64 = 0.5;
+
+/// How the candidates disagreed on a line
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisagreementKind {
+    /// The line was read differently
+    Words,
+    /// Some candidates have the line and others don't
+    Presence,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Disagreement {
+    /// Line number in the merged page, or the line it would have followed when it was left out
+    pub line: usize,
+    pub kind: DisagreementKind,
+    /// What the merged page has; null when the line was left out
+    pub chosen: Option<String>,
+    /// Each candidate's reading, in candidate order; null where a candidate has no such line
+    pub readings: Vec<Option<String>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CandidateInfo {
+    pub model: String,
+    /// Raw transcription, relative to the markdown directory
+    pub file: String,
+}
+
+/// Per-page consensus report (`page_NNNN.consensus.json`)
+#[derive(Serialize, Debug, Clone)]
+pub struct ConsensusReport {
+    pub candidates: Vec<CandidateInfo>,
+    /// Candidate the others were aligned to: the one closest to all the others
+    pub reference: usize,
+    /// Fraction of the reference's lines that every candidate read the same way
+    pub agreement: f64,
+    pub disagreements: Vec<Disagreement>,
+}
+
+impl ConsensusReport {
+    pub fn write(&self, output_dir: &Path, file_stem: &str) -> Result<()> {
+        use std::io::Write;
+        let mut tmp_file = NamedTempFile::new_in(output_dir)?;
+        tmp_file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        tmp_file.persist(output_dir.join(format!("{}.consensus.json", file_stem)))?;
+        Ok(())
+    }
+}
+
+/// Result of merging the candidates of one page
+pub struct Merged {
+    pub text: String,
+    pub reference: usize,
+    pub agreement: f64,
+    pub disagreements: Vec<Disagreement>,
+}
+
+fn words(line: &str) -> Vec<&str> {
+    line.split_whitespace().collect()
+}
+
+fn normalize(line: &str) -> String {
+    words(line).join(" ")
+}
+
+/// Longest common subsequence of two word lists, as matched index pairs in order
+fn lcs<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let mut table = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i][j] = if a[i] == b[j] { table[i + 1][j + 1] + 1 } else { table[i + 1][j].max(table[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut pairs = Vec::new();
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if table[i + 1][j] >= table[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+/// Share of words two texts have in common, in order (1.0 for identical, 0.0 for disjoint)
+fn similarity(a: &[&str], b: &[&str]) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    2.0 * lcs(a, b).len() as f64 / (a.len() + b.len()) as f64
+}
+
+/// Align the candidate's lines to the reference's, keeping order and maximizing the total
+/// similarity of aligned pairs. Returns the candidate line for each reference line, and the
+/// unaligned candidate lines with the number of reference lines before them.
+fn align_lines(reference: &[Vec<&str>], candidate: &[Vec<&str>]) -> (Vec<Option<usize>>, Vec<(usize, usize)>) {
+    let (n, m) = (reference.len(), candidate.len());
+    let mut score = vec![vec![0.0f64; m + 1]; n + 1];
+    let mut sim = vec![vec![0.0f64; m]; n];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            sim[i][j] = similarity(&reference[i], &candidate[j]);
+            let mut best = score[i + 1][j].max(score[i][j + 1]);
+            if sim[i][j] >= MIN_LINE_SIMILARITY {
+                best = best.max(score[i + 1][j + 1] + sim[i][j]);
+            }
+            score[i][j] = best;
+        }
+    }
+
+    let mut aligned = vec![None; n];
+    let mut extra = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if sim[i][j] >= MIN_LINE_SIMILARITY && score[i][j] == score[i + 1][j + 1] + sim[i][j] {
+            aligned[i] = Some(j);
+            i += 1;
+            j += 1;
+        } else if score[i][j] == score[i + 1][j] {
+            i += 1;
+        } else {
+            extra.push((i, j));
+            j += 1;
+        }
+    }
+    extra.extend((j..m).map(|j| (n, j)));
+    (aligned, extra)
+}
+
+/// Each candidate's word for each reference word: the same word where they match, the word in
+/// the same position where both sides differ by the same number of words, and nothing otherwise
+fn align_words<'a>(reference: &[&str], candidate: &[&'a str]) -> Vec<Option<&'a str>> {
+    let mut aligned = vec![None; reference.len()];
+    let mut anchors = lcs(reference, candidate);
+    anchors.push((reference.len(), candidate.len()));
+    let (mut i, mut j) = (0, 0);
+    for (ai, aj) in anchors {
+        if ai - i == aj - j {
+            for (slot, word) in aligned[i..ai].iter_mut().zip(&candidate[j..aj]) {
+                *slot = Some(*word);
+            }
+        }
+        if ai < reference.len() {
+            aligned[ai] = Some(candidate[aj]);
+        }
+        (i, j) = (ai + 1, aj + 1);
+    }
+    aligned
+}
+
+/// Merge several transcriptions of one page by majority vote. The candidate most similar to
+/// the others is the reference: lines are aligned to its lines, a line is kept or dropped by
+/// majority, and lines read differently are voted on word by word. Ties go to the reference: with
+/// two candidates, a line only one of them has is kept if it is the reference's.
+pub fn merge(candidates: &[&str]) -> Merged {
+    let count = candidates.len();
+    let lines: Vec<Vec<&str>> = candidates.iter().map(|c| c.lines().collect()).collect();
+    let line_words: Vec<Vec<Vec<&str>>> = lines.iter().map(|ls| ls.iter().map(|l| words(l)).collect()).collect();
+
+    let all_words: Vec<Vec<&str>> = candidates.iter().map(|c| words(c)).collect();
+    let reference = (0..count)
+        .max_by(|&a, &b| {
+            let total = |x: usize| (0..count).filter(|&y| y != x).map(|y| similarity(&all_words[x], &all_words[y])).sum::<f64>();
+            // Prefer the earlier candidate on equal scores
+            total(a).total_cmp(&total(b)).then(b.cmp(&a))
+        })
+        .unwrap_or(0);
+
+    // For every reference line, each candidate's line aligned to it
+    let mut readings: Vec<Vec<Option<usize>>> = (0..lines[reference].len()).map(|i| {
+        let mut r = vec![None; count];
+        r[reference] = Some(i);
+        r
+    }).collect();
+    // Candidate lines with no counterpart, keyed by how many reference lines precede them
+    let mut extras: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    for c in (0..count).filter(|&c| c != reference) {
+        let (aligned, extra) = align_lines(&line_words[reference], &line_words[c]);
+        for (i, j) in aligned.into_iter().enumerate() {
+            readings[i][c] = j;
+        }
+        for (slot, j) in extra {
+            extras.entry(slot).or_default().push((c, j));
+        }
+    }
+
+    let mut merged: Vec<String> = Vec::new();
+    let mut disagreements = Vec::new();
+    let mut agreed = 0;
+    let reading_texts = |r: &[Option<usize>]| -> Vec<Option<String>> {
+        r.iter().enumerate().map(|(c, j)| j.map(|j| normalize(lines[c][j]))).collect()
+    };
+
+    for slot in 0..=lines[reference].len() {
+        // Lines the reference doesn't have are added when most candidates agree on them
+        if let Some(extra) = extras.get(&slot) {
+            let mut seen: Vec<(String, Vec<Option<usize>>)> = Vec::new();
+            for &(c, j) in extra {
+                let text = normalize(lines[c][j]);
+                if text.is_empty() {
+                    continue;
+                }
+                match seen.iter_mut().find(|(t, _)| *t == text) {
+                    Some((_, r)) => r[c] = Some(j),
+                    None => {
+                        let mut r = vec![None; count];
+                        r[c] = Some(j);
+                        seen.push((text, r));
+                    }
+                }
+            }
+            for (text, r) in seen {
+                let keep = r.iter().flatten().count() * 2 > count;
+                if keep {
+                    merged.push(text.clone());
+                }
+                disagreements.push(Disagreement {
+                    line: merged.len(),
+                    kind: DisagreementKind::Presence,
+                    chosen: keep.then_some(text),
+                    readings: reading_texts(&r),
+                });
+            }
+        }
+
+        let Some(r) = readings.get(slot) else { break };
+        let original = lines[reference][slot];
+        let present = r.iter().flatten().count();
+        if present * 2 < count {
+            // Most candidates don't have this line. Blank lines only matter for layout.
+            if !original.trim().is_empty() {
+                disagreements.push(Disagreement {
+                    line: merged.len(),
+                    kind: DisagreementKind::Presence,
+                    chosen: None,
+                    readings: reading_texts(r),
+                });
+            }
+            continue;
+        }
+
+        let texts = reading_texts(r);
+        let reference_text = normalize(original);
+        if texts.iter().flatten().all(|t| *t == reference_text) {
+            merged.push(original.to_string());
+            if present == count {
+                agreed += 1;
+            } else if !original.trim().is_empty() {
+                // Everyone who has the line reads it the same way; only its presence is in question
+                disagreements.push(Disagreement {
+                    line: merged.len(),
+                    kind: DisagreementKind::Presence,
+                    chosen: Some(reference_text),
+                    readings: texts,
+                });
+            }
+            continue;
+        }
+
+        let reference_words = &line_words[reference][slot];
+        let mut votes: Vec<HashMap<&str, usize>> = reference_words.iter().map(|w| HashMap::from([(*w, 1)])).collect();
+        for (c, j) in r.iter().enumerate().filter(|(c, _)| *c != reference) {
+            let Some(j) = j else { continue };
+            for (k, word) in align_words(reference_words, &line_words[c][*j]).into_iter().enumerate() {
+                if let Some(word) = word {
+                    *votes[k].entry(word).or_default() += 1;
+                }
+            }
+        }
+        let chosen: Vec<&str> = reference_words
+            .iter()
+            .zip(&votes)
+            .map(|(w, v)| {
+                let best = v.values().copied().max().unwrap_or(0);
+                if v[w] == best {
+                    *w
+                } else {
+                    v.iter().filter(|(_, n)| **n == best).map(|(w, _)| *w).min().unwrap_or(w)
+                }
+            })
+            .collect();
+        let indent = &original[..original.len() - original.trim_start().len()];
+        let line = format!("{}{}", indent, chosen.join(" "));
+        merged.push(line.clone());
+        disagreements.push(Disagreement {
+            line: merged.len(),
+            kind: DisagreementKind::Words,
+            chosen: Some(line.trim().to_string()),
+            readings: texts,
+        });
+    }
+
+    let total = lines[reference].len().max(1);
+    Merged {
+        text: merged.join("\n"),
+        reference,
+        agreement: agreed as f64 / total as f64,
+        disagreements,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line_words(text: &str) -> Vec<Vec<&str>> {
+        text.lines().map(words).collect()
+    }
+
+    #[test]
+    fn identical_candidates_agree_fully() {
+        let page = "# Title\n\nFirst line of text.\n  Indented second line.";
+        let merged = merge(&[page, page, page]);
+        assert_eq!(merged.text, page);
+        assert_eq!(merged.reference, 0);
+        assert_eq!(merged.agreement, 1.0);
+        assert!(merged.disagreements.is_empty());
+    }
+
+    #[test]
+    fn keeps_a_line_one_candidate_left_out() {
+        let full = "alpha beta gamma delta\nepsilon zeta eta theta\niota kappa lambda mu";
+        let short = "alpha beta gamma delta\niota kappa lambda mu";
+        let merged = merge(&[full, full, short]);
+        assert_eq!(merged.text, full);
+        assert_eq!(merged.agreement, 2.0 / 3.0);
+        assert_eq!(merged.disagreements.len(), 1);
+        let d = &merged.disagreements[0];
+        assert_eq!((d.line, d.kind), (2, DisagreementKind::Presence));
+        assert_eq!(d.chosen.as_deref(), Some("epsilon zeta eta theta"));
+        assert_eq!(d.readings[2], None);
+    }
+
+    #[test]
+    fn adds_a_line_the_majority_has_and_the_reference_lacks() {
+        let a = "alpha beta gamma delta\nepsilon zeta eta theta\niota kappa lambda mu";
+        let b = "one two three four\nnew line of text\nepsilon zeta eta theta\niota kappa lambda mu";
+        let c = "alpha beta gamma delta\nnew line of text\nepsilon zeta eta theta\nnu xi omicron pi";
+        let merged = merge(&[a, b, c]);
+        assert_eq!(merged.reference, 0);
+        assert_eq!(
+            merged.text,
+            "alpha beta gamma delta\nnew line of text\nepsilon zeta eta theta\niota kappa lambda mu"
+        );
+        let added = merged
+            .disagreements
+            .iter()
+            .find(|d| d.chosen.as_deref() == Some("new line of text"))
+            .expect("added line is reported");
+        assert_eq!((added.line, added.kind), (2, DisagreementKind::Presence));
+        assert_eq!(added.readings, vec![None, Some("new line of text".to_string()), Some("new line of text".to_string())]);
+        // Lines only one candidate has are reported but left out
+        for line in ["one two three four", "nu xi omicron pi"] {
+            let d = merged.disagreements.iter().find(|d| d.readings.contains(&Some(line.to_string()))).unwrap();
+            assert_eq!((d.kind, d.chosen.as_deref()), (DisagreementKind::Presence, None));
+        }
+    }
+
+    #[test]
+    fn votes_out_a_misread_word() {
+        // Each candidate misreads a different line, so the first is the reference
+        let merged = merge(&[
+            "the quikc brown fox\njumps over the\nlazy dog",
+            "the quick brown fox\njumsp over the\nlazy dog",
+            "the quick brown fox\njumps over the\nlazy dgo",
+        ]);
+        assert_eq!(merged.reference, 0);
+        assert_eq!(merged.text, "the quick brown fox\njumps over the\nlazy dog");
+        assert_eq!(merged.agreement, 0.0);
+        let d = &merged.disagreements[0];
+        assert_eq!((d.line, d.kind), (1, DisagreementKind::Words));
+        assert_eq!(d.chosen.as_deref(), Some("the quick brown fox"));
+        assert_eq!(d.readings[0].as_deref(), Some("the quikc brown fox"));
+    }
+
+    #[test]
+    fn ties_go_to_the_reference() {
+        let merged = merge(&["the quikc brown fox", "the quick brown fox"]);
+        assert_eq!(merged.reference, 0);
+        assert_eq!(merged.text, "the quikc brown fox");
+        assert_eq!(merged.disagreements[0].kind, DisagreementKind::Words);
+    }
+
+    #[test]
+    fn two_candidates_keep_the_references_lines_and_drop_the_others() {
+        // With two candidates every presence vote is a tie, so the reference decides: its line is
+        // kept (present * 2 == count is not a minority) and a line only the other has is dropped
+        let with = "alpha beta gamma delta\nepsilon zeta eta theta\niota kappa lambda mu";
+        let without = "alpha beta gamma delta\niota kappa lambda mu";
+        let merged = merge(&[with, without]);
+        assert_eq!(merged.reference, 0);
+        assert_eq!(merged.text, with);
+        assert_eq!(merged.disagreements[0].chosen.as_deref(), Some("epsilon zeta eta theta"));
+
+        let merged = merge(&[without, with]);
+        assert_eq!(merged.reference, 0);
+        assert_eq!(merged.text, without);
+        let d = &merged.disagreements[0];
+        assert_eq!((d.line, d.kind, d.chosen.as_deref()), (1, DisagreementKind::Presence, None));
+    }
+
+    #[test]
+    fn aligns_lines_around_an_inserted_one() {
+        let reference = line_words("one two three\nfour five six");
+        let candidate = line_words("one two three\ninserted words here\nfour five sixx");
+        assert_eq!(align_lines(&reference, &candidate), (vec![Some(0), Some(2)], vec![(1, 1)]));
+
+        // Unmatched lines on both sides: the candidate's come after the reference's
+        let candidate = line_words("one two three\nsomething else entirely\nmore trailing text");
+        assert_eq!(align_lines(&reference, &candidate), (vec![Some(0), None], vec![(2, 1), (2, 2)]));
+    }
+
+    #[test]
+    fn aligns_words_only_where_the_gaps_match() {
+        let reference = ["a", "b", "c"];
+        assert_eq!(align_words(&reference, &["a", "x", "c"]), vec![Some("a"), Some("x"), Some("c")]);
+        assert_eq!(align_words(&reference, &["a", "x", "y", "c"]), vec![Some("a"), None, Some("c")]);
+        assert_eq!(align_words(&reference, &["a", "c"]), vec![Some("a"), None, Some("c")]);
+        assert_eq!(align_words(&reference, &[]), vec![None, None, None]);
+    }
+}
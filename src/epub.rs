@@ -0,0 +1,285 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::combine::TocEntry;
+use crate::render::{escape_xml, markdown_to_xhtml, nested_list, BookMetadata, Chapter};
+
+const STYLESHEET: &str = "\
+body { font-family: serif; line-height: 1.5; margin: 0 1em; }
+h1, h2, h3, h4, h5, h6 { font-family: sans-serif; line-height: 1.2; page-break-after: avoid; }
+h1 { page-break-before: always; }
+pre { white-space: pre-wrap; font-size: 0.85em; background: #f4f4f4; padding: 0.5em; }
+code { font-family: monospace; }
+table { border-collapse: collapse; margin: 1em 0; }
+th, td { border: 1px solid #999; padding: 0.2em 0.5em; }
+blockquote { margin-left: 1em; padding-left: 0.8em; border-left: 3px solid #ccc; }
+img { max-width: 100%; }
+.cover { text-align: center; margin: 0; padding: 0; }
+.cover img { max-height: 100%; }
+";
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+fn xhtml_page(title: &str, language: &str, body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{lang}\" lang=\"{lang}\">\n\
+         <head>\n<meta charset=\"UTF-8\"/>\n<title>{title}</title>\n<link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\"/>\n</head>\n\
+         <body>\n{body}\n</body>\n</html>\n",
+        lang = escape_xml(language),
+        title = escape_xml(title),
+        body = body
+    )
+}
+
+/// `dcterms:modified` wants UTC in `CCYY-MM-DDThh:mm:ssZ` form
+fn utc_timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60
+    )
+}
+
+/// Navigation document listing the TOC entries
+fn nav_document(metadata: &BookMetadata, entries: &[(usize, String, String)]) -> String {
+    let body = format!(
+        "<nav epub:type=\"toc\" id=\"toc\">\n<h1>Table of Contents</h1>{}\n</nav>",
+        nested_list("ol", entries)
+    );
+    xhtml_page(&metadata.title, &metadata.language, &body)
+}
+
+fn package_document(metadata: &BookMetadata, chapter_files: &[String]) -> String {
+    let mut meta = format!(
+        "    <dc:identifier id=\"book-id\">urn:uuid:{}</dc:identifier>\n    <dc:title>{}</dc:title>\n",
+        uuid::Uuid::new_v4(),
+        escape_xml(&metadata.title)
+    );
+    if let Some(author) = &metadata.author {
+        meta.push_str(&format!("    <dc:creator>{}</dc:creator>\n", escape_xml(author)));
+    }
+    meta.push_str(&format!("    <dc:language>{}</dc:language>\n", escape_xml(&metadata.language)));
+    meta.push_str(&format!("    <meta property=\"dcterms:modified\">{}</meta>\n", utc_timestamp()));
+
+    let mut manifest = String::from(
+        "    <item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n    \
+         <item id=\"style\" href=\"style.css\" media-type=\"text/css\"/>\n",
+    );
+    let mut spine = String::new();
+    if metadata.cover.is_some() {
+        // The `meta name="cover"` hint is for EPUB 2 readers that ignore `properties`
+        meta.push_str("    <meta name=\"cover\" content=\"cover-image\"/>\n");
+        manifest.push_str(
+            "    <item id=\"cover-image\" href=\"cover.png\" media-type=\"image/png\" properties=\"cover-image\"/>\n    \
+             <item id=\"cover\" href=\"cover.xhtml\" media-type=\"application/xhtml+xml\"/>\n",
+        );
+        spine.push_str("    <itemref idref=\"cover\"/>\n");
+    }
+    spine.push_str("    <itemref idref=\"nav\"/>\n");
+    for file in chapter_files {
+        let id = file.trim_end_matches(".xhtml");
+        manifest.push_str(&format!(
+            "    <item id=\"{}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>\n",
+            id, file
+        ));
+        spine.push_str(&format!("    <itemref idref=\"{}\"/>\n", id));
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"book-id\" xml:lang=\"{}\">\n  \
+         <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n{}  </metadata>\n  \
+         <manifest>\n{}  </manifest>\n  <spine>\n{}  </spine>\n</package>\n",
+        escape_xml(&metadata.language),
+        meta,
+        manifest,
+        spine
+    )
+}
+
+/// Package the chapters as an EPUB 3 book. Heading IDs come from `slugs` (see
+/// `markdown_to_xhtml`); the nav document links each TOC entry to the chapter file holding
+/// its target, or to the chapter holding its page when the target is missing.
+pub fn write_epub(
+    path: &Path,
+    metadata: &BookMetadata,
+    chapters: &[Chapter],
+    slugs: &mut impl Iterator<Item = String>,
+    toc: &[TocEntry],
+) -> Result<()> {
+    let mut bodies = Vec::new();
+    let mut owner: HashMap<String, String> = HashMap::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let file = format!("chapter_{:03}.xhtml", i + 1);
+        let (body, ids) = markdown_to_xhtml(&chapter.markdown, slugs);
+        for id in ids {
+            owner.entry(id).or_insert_with(|| file.clone());
+        }
+        bodies.push((file, body));
+    }
+    let href = |id: &str| owner.get(id).map(|file| format!("{}#{}", file, id));
+
+    // In-book links (`#page_12`, `#some-heading`) have to name the file the target ended up in
+    let local_link = Regex::new(r##"href="#([^"]+)""##)?;
+    let bodies: Vec<(String, String)> = bodies
+        .into_iter()
+        .map(|(file, body)| {
+            let body = local_link.replace_all(&body, |cap: &regex::Captures| match owner.get(&cap[1]) {
+                Some(target) if *target != file => format!("href=\"{}#{}\"", target, &cap[1]),
+                _ => cap[0].to_string(),
+            });
+            let body = body.into_owned();
+            (file, body)
+        })
+        .collect();
+
+    let mut entries: Vec<(usize, String, String)> = toc
+        .iter()
+        .filter_map(|e| {
+            let target = href(&e.target).or_else(|| href(&format!("page_{}", e.page)))?;
+            Some((e.level, e.title.clone(), target))
+        })
+        .collect();
+    if entries.is_empty() {
+        // The nav document needs at least one entry; list the chapters instead
+        entries = chapters
+            .iter()
+            .zip(&bodies)
+            .map(|(chapter, (file, _))| (1, chapter.title.clone(), file.clone()))
+            .collect();
+    }
+
+    let file = std::fs::File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+    let mut zip = ZipWriter::new(file);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // The mimetype must be the first entry, uncompressed, for readers to recognize the file
+    zip.start_file("mimetype", SimpleFileOptions::default().compression_method(CompressionMethod::Stored))?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(CONTAINER_XML.as_bytes())?;
+
+    let chapter_files: Vec<String> = bodies.iter().map(|(file, _)| file.clone()).collect();
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    zip.write_all(package_document(metadata, &chapter_files).as_bytes())?;
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    zip.write_all(nav_document(metadata, &entries).as_bytes())?;
+    zip.start_file("OEBPS/style.css", deflated)?;
+    zip.write_all(STYLESHEET.as_bytes())?;
+
+    if let Some(cover) = &metadata.cover {
+        let image = std::fs::read(cover).with_context(|| format!("Failed to read cover image {:?}", cover))?;
+        // PNG is already compressed
+        zip.start_file("OEBPS/cover.png", SimpleFileOptions::default().compression_method(CompressionMethod::Stored))?;
+        zip.write_all(&image)?;
+        let body = format!(
+            "<section epub:type=\"cover\" class=\"cover\">\n<img src=\"cover.png\" alt=\"{}\"/>\n</section>",
+            escape_xml(&metadata.title)
+        );
+        zip.start_file("OEBPS/cover.xhtml", deflated)?;
+        zip.write_all(xhtml_page(&metadata.title, &metadata.language, &body).as_bytes())?;
+    }
+
+    for ((file, body), chapter) in bodies.iter().zip(chapters) {
+        zip.start_file(format!("OEBPS/{}", file), deflated)?;
+        zip.write_all(xhtml_page(&chapter.title, &metadata.language, body).as_bytes())?;
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::tests::assert_well_formed;
+
+    fn metadata(author: Option<&str>, cover: bool) -> BookMetadata {
+        BookMetadata {
+            title: "Fish & Chips <Vol. 1>".to_string(),
+            author: author.map(str::to_string),
+            language: "en".to_string(),
+            cover: cover.then(|| "page_001.png".into()),
+        }
+    }
+
+    #[test]
+    fn nav_nests_entries_by_level() {
+        let entries = vec![
+            (1, "One & Two".to_string(), "chapter_001.xhtml#one".to_string()),
+            (2, "Sub".to_string(), "chapter_001.xhtml#sub".to_string()),
+            (3, "Deeper".to_string(), "chapter_001.xhtml#deeper".to_string()),
+            (1, "Three".to_string(), "chapter_002.xhtml#three".to_string()),
+        ];
+        let nav = nav_document(&metadata(None, false), &entries);
+        assert_well_formed(&nav);
+        assert!(nav.contains("<title>Fish &amp; Chips &lt;Vol. 1&gt;</title>"));
+        assert!(nav.contains("<nav epub:type=\"toc\" id=\"toc\">"));
+        let list = nested_list("ol", &entries);
+        assert_eq!(
+            list,
+            "\n<ol>\n<li><a href=\"chapter_001.xhtml#one\">One &amp; Two</a>\
+             \n<ol>\n<li><a href=\"chapter_001.xhtml#sub\">Sub</a>\
+             \n<ol>\n<li><a href=\"chapter_001.xhtml#deeper\">Deeper</a></li>\
+             \n</ol></li>\n</ol></li>\
+             \n<li><a href=\"chapter_002.xhtml#three\">Three</a></li>\n</ol>"
+        );
+    }
+
+    #[test]
+    fn empty_nav_is_well_formed() {
+        assert_well_formed(&nav_document(&metadata(None, false), &[]));
+    }
+
+    #[test]
+    fn package_lists_every_chapter_in_the_spine() {
+        let chapters = vec!["chapter_001.xhtml".to_string(), "chapter_002.xhtml".to_string()];
+        let opf = package_document(&metadata(Some("Smith & Jones"), false), &chapters);
+        assert_well_formed(&opf);
+        assert!(opf.contains("<dc:title>Fish &amp; Chips &lt;Vol. 1&gt;</dc:title>"));
+        assert!(opf.contains("<dc:creator>Smith &amp; Jones</dc:creator>"));
+        assert!(opf.contains("<dc:language>en</dc:language>"));
+        assert!(opf.contains("<item id=\"chapter_002\" href=\"chapter_002.xhtml\" media-type=\"application/xhtml+xml\"/>"));
+        assert!(opf.contains(
+            "<spine>\n    <itemref idref=\"nav\"/>\n    <itemref idref=\"chapter_001\"/>\n    \
+             <itemref idref=\"chapter_002\"/>\n  </spine>"
+        ));
+        assert!(!opf.contains("cover"));
+        let modified = Regex::new(r#"<meta property="dcterms:modified">\d{4}-\d\d-\d\dT\d\d:\d\d:\d\dZ</meta>"#).unwrap();
+        assert!(modified.is_match(&opf));
+    }
+
+    #[test]
+    fn package_puts_the_cover_first() {
+        let opf = package_document(&metadata(None, true), &["chapter_001.xhtml".to_string()]);
+        assert_well_formed(&opf);
+        assert!(!opf.contains("dc:creator"));
+        assert!(opf.contains("<meta name=\"cover\" content=\"cover-image\"/>"));
+        assert!(opf.contains("properties=\"cover-image\"/>"));
+        assert!(opf.contains("<spine>\n    <itemref idref=\"cover\"/>\n    <itemref idref=\"nav\"/>"));
+    }
+}
@@ -0,0 +1,393 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::confidence;
+use crate::input::{self, list_folder_images, text_layer_usable, InputKind};
+use crate::provider::ProviderKind;
+use crate::{PageSelection, TextLayerMode, TranscribeOptions};
+
+const OPENROUTER_MODELS_URL: &str = "https://openrouter.ai/api/v1/models";
+
+/// Rough characters-per-token ratio for English prose and Markdown
+const CHARS_PER_TOKEN: usize = 4;
+
+/// A --self-check reply is just the rating
+const SELF_CHECK_REPLY_TOKENS: u64 = 5;
+
+/// Settings for `estimate` and `pipeline --dry-run`
+#[derive(clap::Args, Debug, Clone)]
+pub struct EstimateOptions {
+    /// Model pricing file in OpenRouter /models format (defaults to the cached copy)
+    #[arg(long)]
+    pub pricing: Option<PathBuf>,
+
+    /// Download current OpenRouter pricing into the cache before estimating
+    #[arg(long)]
+    pub refresh_pricing: bool,
+
+    /// Expected output tokens for pages without a text layer to measure
+    #[arg(long, default_value_t = 700)]
+    pub output_tokens_per_page: u64,
+
+    /// Average seconds per request, used for the duration estimate
+    #[arg(long, default_value_t = 20.0)]
+    pub seconds_per_page: f64,
+}
+
+/// Per-token prices in USD, plus flat per-image and per-request charges
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+    pub image: f64,
+    pub request: f64,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+    #[serde(default)]
+    pricing: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Prices are strings in OpenRouter's listing ("0.000001"), but accept plain numbers too
+fn price_field(pricing: &serde_json::Map<String, serde_json::Value>, key: &str) -> f64 {
+    match pricing.get(key) {
+        Some(serde_json::Value::String(s)) => s.parse().unwrap_or(0.0),
+        Some(v) => v.as_f64().unwrap_or(0.0),
+        None => 0.0,
+    }
+}
+
+fn pricing_cache_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))?;
+    Some(base.join("scribe").join("openrouter_models.json"))
+}
+
+/// Download OpenRouter's public model list into the local pricing cache
+pub async fn refresh_pricing_cache() -> Result<PathBuf> {
+    let path = pricing_cache_path().context("Cannot determine cache directory (HOME not set)")?;
+    println!("Fetching model pricing from {}", OPENROUTER_MODELS_URL);
+    let body = reqwest::Client::new()
+        .get(OPENROUTER_MODELS_URL)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    // Validate before overwriting a good cache
+    serde_json::from_str::<ModelList>(&body).context("Unexpected model list format")?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, body)?;
+    println!("Cached pricing at {:?}", path);
+    Ok(path)
+}
+
+/// Look up a model's price in the given pricing file, or the cached copy
+pub fn load_price(pricing_file: Option<&Path>, model: &str) -> Result<Option<ModelPrice>> {
+    let path = match pricing_file {
+        Some(p) => p.to_path_buf(),
+        None => match pricing_cache_path().filter(|p| p.exists()) {
+            Some(p) => p,
+            None => return Ok(None),
+        },
+    };
+    let list: ModelList = serde_json::from_str(&std::fs::read_to_string(&path)?)
+        .with_context(|| format!("Invalid pricing file {:?}", path))?;
+    Ok(list.data.into_iter().find(|m| m.id == model).map(|m| ModelPrice {
+        prompt: price_field(&m.pricing, "prompt"),
+        completion: price_field(&m.pricing, "completion"),
+        image: price_field(&m.pricing, "image"),
+        request: price_field(&m.pricing, "request"),
+    }))
+}
+
+/// Approximate input tokens for one image, following each vendor's published formula
+pub fn image_tokens(provider: ProviderKind, model: &str, width: u32, height: u32) -> u64 {
+    let model = model.to_lowercase();
+    let (w, h) = (width.max(1) as f64, height.max(1) as f64);
+
+    if provider == ProviderKind::Anthropic || model.contains("claude") {
+        // Downscaled to a 1568px long edge, then roughly one token per 750 pixels
+        let scale = (1568.0 / w.max(h)).min(1.0);
+        ((w * scale) * (h * scale) / 750.0).ceil() as u64
+    } else if provider == ProviderKind::Gemini || model.contains("gemini") {
+        // 258 tokens per 768x768 tile
+        ((w / 768.0).ceil() * (h / 768.0).ceil()) as u64 * 258
+    } else {
+        // OpenAI high detail: fit in 2048x2048, shortest side to 768, 170 tokens per 512px tile
+        let fit = (2048.0 / w.max(h)).min(1.0);
+        let (w, h) = (w * fit, h * fit);
+        let shrink = (768.0 / w.min(h)).min(1.0);
+        let tiles = ((w * shrink) / 512.0).ceil() * ((h * shrink) / 512.0).ceil();
+        85 + 170 * tiles as u64
+    }
+}
+
+/// What a transcription run over one book would do
+#[derive(Debug, Default, Clone)]
+pub struct BookEstimate {
+    pub selected_pages: usize,
+    pub to_extract: usize,
+    pub to_transcribe: usize,
+    /// API requests for the pages to transcribe (several per page with --consensus, and one
+    /// more with --self-check)
+    pub requests: usize,
+    pub from_text_layer: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl BookEstimate {
+    pub fn add(&mut self, other: &BookEstimate) {
+        self.selected_pages += other.selected_pages;
+        self.to_extract += other.to_extract;
+        self.to_transcribe += other.to_transcribe;
+        self.requests += other.requests;
+        self.from_text_layer += other.from_text_layer;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+
+    pub fn cost(&self, price: &ModelPrice) -> f64 {
+        self.prompt_tokens as f64 * price.prompt
+            + self.completion_tokens as f64 * price.completion
+            + self.requests as f64 * (price.image + price.request)
+    }
+}
+
+/// Count the work `extract_pdf` and `transcribe_images` would do for one book, without rendering
+/// pages or calling any API. Existing images are measured directly; pages not yet extracted are
+/// sized from the PDF page bounds at the given DPI, or from the source scan.
+pub fn estimate_book(
+    source: &Path,
+    output_base: &Path,
+    dpi: u16,
+    selection: &PageSelection,
+    options: &TranscribeOptions,
+    model: &str,
+    estimate: &EstimateOptions,
+) -> Result<BookEstimate> {
+    let images_dir = output_base.join("images");
+    let markdown_dir = output_base.join("markdown");
+
+    // Image folders and TIFFs are measured from the source files; documents from their page bounds
+    let kind = InputKind::detect(source)?;
+    let (doc, folder_images) = match kind {
+        InputKind::Document => {
+            let doc = mupdf::Document::open(source.to_str().context("Invalid path")?)
+                .context("Failed to open document")?;
+            (Some(doc), Vec::new())
+        }
+        InputKind::ImageFolder => (None, list_folder_images(source)?),
+        InputKind::Tiff | InputKind::Djvu => (None, Vec::new()),
+    };
+    let total_pages = match (&doc, kind) {
+        (Some(doc), _) => doc.page_count().context("Failed to get page count")? as usize,
+        (None, InputKind::Tiff) => input::tiff_page_count(source)?,
+        (None, InputKind::Djvu) => input::djvu_page_count(source)?,
+        (None, _) => folder_images.len(),
+    };
+    let selected = selection.apply((1..=total_pages).map(|n| (n, ())).collect());
+
+    let prompt_template = options.prompt_template()?;
+    let prompt_tokens = (prompt_template.len() / CHARS_PER_TOKEN) as u64;
+    let self_check_tokens = (confidence::self_check_prompt("").len() / CHARS_PER_TOKEN) as u64;
+    let scale = dpi as f32 / 72.0;
+
+    let mut result = BookEstimate { selected_pages: selected.len(), ..Default::default() };
+    for (page_num, _) in selected {
+        let stem = format!("page_{:04}", page_num);
+        let image_path = images_dir.join(format!("{}.png", stem));
+        let text_path = images_dir.join(format!("{}.txt", stem));
+
+        if selection.force || !image_path.exists() {
+            result.to_extract += 1;
+        }
+        if !selection.force && markdown_dir.join(format!("{}.md", stem)).exists() {
+            continue;
+        }
+
+        let text_layer = std::fs::read_to_string(&text_path).ok();
+        if matches!(options.text_layer, TextLayerMode::Skip | TextLayerMode::Auto)
+            && text_layer.as_deref().is_some_and(text_layer_usable)
+        {
+            result.from_text_layer += 1;
+            continue;
+        }
+
+        let (width, height) = match (&doc, image::image_dimensions(&image_path)) {
+            (_, Ok(dims)) if !selection.force => dims,
+            (Some(doc), _) => {
+                let bounds = doc.load_page(page_num as i32 - 1)?.bounds()?;
+                (((bounds.x1 - bounds.x0) * scale) as u32, ((bounds.y1 - bounds.y0) * scale) as u32)
+            }
+            (None, _) => match kind {
+                InputKind::Tiff => input::tiff_page_dimensions(source, page_num)?,
+                InputKind::Djvu => input::djvu_page_dimensions(source, page_num, dpi)?,
+                _ => image::image_dimensions(&folder_images[page_num - 1])?,
+            },
+        };
+
+        // A text layer, when present, is the best guess at how much the model will write
+        let layer_tokens = text_layer
+            .as_deref()
+            .map(|t| (t.len() / CHARS_PER_TOKEN) as u64)
+            .filter(|t| *t > 0);
+        let hint_tokens = match options.text_layer {
+            TextLayerMode::Hint | TextLayerMode::Auto => layer_tokens.unwrap_or(0),
+            _ => 0,
+        };
+
+        let output_tokens = layer_tokens
+            .map(|t| t + t / 10) // Markdown markup on top of the raw text
+            .unwrap_or(estimate.output_tokens_per_page);
+        let requests = options.consensus as u64;
+        result.to_transcribe += 1;
+        result.requests += requests as usize;
+        result.prompt_tokens += requests * (prompt_tokens + hint_tokens + image_tokens(options.provider, model, width, height));
+        result.completion_tokens += requests * output_tokens;
+        if options.self_check {
+            // The page image again, with the transcription in the prompt
+            let check_model = options.self_check_model.as_deref().unwrap_or(model);
+            result.requests += 1;
+            result.prompt_tokens +=
+                self_check_tokens + output_tokens + image_tokens(options.provider, check_model, width, height);
+            result.completion_tokens += SELF_CHECK_REPLY_TOKENS;
+        }
+    }
+
+    Ok(result)
+}
+
+pub fn format_duration(seconds: f64) -> String {
+    let total = seconds.round() as u64;
+    let (h, m, s) = (total / 3600, (total % 3600) / 60, total % 60);
+    if h > 0 {
+        format!("{}h {:02}m", h, m)
+    } else if m > 0 {
+        format!("{}m {:02}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+/// Print the combined estimate for a run
+pub fn print_summary(
+    total: &BookEstimate,
+    model: &str,
+    price: Option<&ModelPrice>,
+    concurrency: usize,
+    estimate: &EstimateOptions,
+) {
+    println!("\n=== Estimate (no API calls made) ===");
+    println!(
+        "Pages: {} selected, {} to extract, {} to transcribe, {} from text layer",
+        total.selected_pages, total.to_extract, total.to_transcribe, total.from_text_layer
+    );
+    println!(
+        "Tokens: ~{} prompt + ~{} completion",
+        total.prompt_tokens, total.completion_tokens
+    );
+    match price {
+        Some(price) => println!(
+            "Estimated cost: ${:.2} ({}: ${:.2}/M prompt, ${:.2}/M completion)",
+            total.cost(price),
+            model,
+            price.prompt * 1e6,
+            price.completion * 1e6
+        ),
+        None => println!(
+            "Estimated cost: unknown (no pricing for '{}'; pass --pricing or --refresh-pricing)",
+            model
+        ),
+    }
+    let batches = total.requests.div_ceil(concurrency.max(1));
+    println!(
+        "Estimated duration: ~{} at concurrency {} ({:.0}s per request)",
+        format_duration(batches as f64 * estimate.seconds_per_page),
+        concurrency,
+        estimate.seconds_per_page
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anthropic_images_cost_a_token_per_750_pixels() {
+        assert_eq!(image_tokens(ProviderKind::Anthropic, "claude-sonnet-4", 1000, 1000), 1334);
+        // Downscaled to a 1568px long edge first
+        assert_eq!(image_tokens(ProviderKind::Anthropic, "claude-sonnet-4", 3136, 2000), 2091);
+    }
+
+    #[test]
+    fn gemini_images_are_counted_in_768px_tiles() {
+        assert_eq!(image_tokens(ProviderKind::Gemini, "gemini-2.5-flash", 768, 768), 258);
+        assert_eq!(image_tokens(ProviderKind::Gemini, "gemini-2.5-flash", 1000, 1500), 4 * 258);
+    }
+
+    #[test]
+    fn openai_images_are_counted_in_512px_tiles() {
+        // The worked examples from OpenAI's vision guide
+        assert_eq!(image_tokens(ProviderKind::OpenAi, "gpt-4o", 1024, 1024), 765);
+        assert_eq!(image_tokens(ProviderKind::OpenAi, "gpt-4o", 2048, 4096), 1105);
+        assert_eq!(image_tokens(ProviderKind::Ollama, "llava", 512, 512), 255);
+    }
+
+    #[test]
+    fn routed_models_use_their_vendors_formula() {
+        assert_eq!(image_tokens(ProviderKind::OpenRouter, "anthropic/claude-sonnet-4", 1000, 1000), 1334);
+        assert_eq!(image_tokens(ProviderKind::OpenRouter, "google/gemini-2.5-flash", 768, 768), 258);
+        assert_eq!(image_tokens(ProviderKind::OpenRouter, "openai/gpt-4o", 1024, 1024), 765);
+    }
+
+    #[test]
+    fn loads_prices_from_a_pricing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("models.json");
+        std::fs::write(
+            &path,
+            r#"{"data": [
+                {"id": "other/model", "pricing": {"prompt": "1"}},
+                {"id": "google/gemini-2.5-flash", "pricing": {
+                    "prompt": "0.0000003", "completion": 0.0000025, "image": "0.001238", "request": "bad"
+                }},
+                {"id": "free/model"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let price = load_price(Some(&path), "google/gemini-2.5-flash").unwrap().unwrap();
+        assert_eq!((price.prompt, price.completion, price.image, price.request), (0.0000003, 0.0000025, 0.001238, 0.0));
+        let free = load_price(Some(&path), "free/model").unwrap().unwrap();
+        assert_eq!(free.prompt + free.completion + free.image + free.request, 0.0);
+        assert!(load_price(Some(&path), "missing/model").unwrap().is_none());
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(load_price(Some(&path), "google/gemini-2.5-flash").is_err());
+    }
+
+    #[test]
+    fn costs_tokens_and_requests() {
+        let estimate =
+            BookEstimate { requests: 10, prompt_tokens: 1_000_000, completion_tokens: 200_000, ..Default::default() };
+        let price = ModelPrice { prompt: 0.000001, completion: 0.000004, image: 0.001, request: 0.0005 };
+        // 1.00 prompt + 0.80 completion + 10 * 0.0015 per request
+        assert!((estimate.cost(&price) - 1.815).abs() < 1e-9);
+        assert_eq!(estimate.cost(&ModelPrice::default()), 0.0);
+    }
+}
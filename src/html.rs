@@ -0,0 +1,269 @@
+use anyhow::{Context, Result};
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Tag, TagEnd};
+use regex::{Captures, Regex};
+use std::ops::Range;
+use std::path::Path;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+use crate::combine::{is_fence, TocEntry};
+use crate::render::{escape_xml, nested_list, parse_markdown, BookMetadata};
+
+const CODE_CLASSES: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+const CODE_THEME: &str = "InspiredGitHub";
+
+const STYLESHEET: &str = "\
+:root { --sidebar: 18rem; --margin: 4.5rem; }
+body { margin: 0; font: 17px/1.6 Georgia, 'Times New Roman', serif; color: #222; background: #fff; }
+nav.toc { position: fixed; top: 0; bottom: 0; left: 0; width: var(--sidebar); overflow-y: auto;
+  box-sizing: border-box; padding: 1rem; background: #f7f7f7; border-right: 1px solid #ddd;
+  font: 14px/1.4 system-ui, sans-serif; }
+nav.toc h2 { font-size: 1rem; margin: 0 0 0.5rem; }
+nav.toc ul { list-style: none; margin: 0; padding-left: 0.9rem; }
+nav.toc > ul { padding-left: 0; }
+nav.toc li { margin: 0.2rem 0; }
+nav.toc a { color: #333; text-decoration: none; }
+nav.toc a:hover { text-decoration: underline; }
+main { position: relative; max-width: 46rem; margin-left: calc(var(--sidebar) + var(--margin));
+  padding: 1rem 2rem 4rem 0; }
+h1, h2, h3, h4, h5, h6 { font-family: system-ui, sans-serif; line-height: 1.25; }
+a { color: #0b5cad; }
+a.page { position: absolute; left: calc(-1 * var(--margin)); width: 3.5rem; text-align: right;
+  font: 12px system-ui, sans-serif; color: #999; text-decoration: none; }
+a.page:hover, a.page:target { color: #0b5cad; }
+pre { overflow-x: auto; padding: 0.8rem; background: #f6f8fa; border-radius: 4px; font-size: 0.85em; }
+code { font-family: ui-monospace, Menlo, Consolas, monospace; }
+table { border-collapse: collapse; margin: 1rem 0; }
+th, td { border: 1px solid #ccc; padding: 0.25rem 0.6rem; }
+blockquote { margin-left: 0; padding-left: 1rem; border-left: 3px solid #ddd; color: #555; }
+img { max-width: 100%; }
+math[display=block] { margin: 1rem 0; overflow-x: auto; }
+.math-error { color: #b00; }
+@media (max-width: 60rem) {
+  nav.toc { position: static; width: auto; border-right: 0; border-bottom: 1px solid #ddd; }
+  main { margin-left: var(--margin); padding-right: 1rem; }
+}
+";
+
+/// Byte ranges of the inline code spans in `text`: a run of backticks up to the next run of the
+/// same length. A run with no match is literal text.
+fn code_spans(text: &str) -> Vec<Range<usize>> {
+    let backticks = Regex::new("`+").expect("valid regex");
+    let runs: Vec<(usize, usize)> = backticks.find_iter(text).map(|m| (m.start(), m.len())).collect();
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < runs.len() {
+        let (start, len) = runs[i];
+        match runs[i + 1..].iter().position(|&(_, l)| l == len) {
+            Some(j) => {
+                spans.push(start..runs[i + 1 + j].0 + len);
+                i += j + 2;
+            }
+            None => i += 1,
+        }
+    }
+    spans
+}
+
+/// Models write math as `\( \)` and `\[ \]` as often as `$ $` and `$$ $$`; rewrite the former
+/// to the dollar forms the Markdown parser understands. Display math only counts when the
+/// delimiters stand on their own lines, since `\[1\]` is usually an escaped citation.
+fn normalize_math_delimiters(markdown: &str) -> String {
+    let display = Regex::new(r"(?sm)^([ \t]*)\\\[(.+?)\\\][ \t]*$").expect("valid regex");
+    let inline = Regex::new(r"\\\((.+?)\\\)").expect("valid regex");
+
+    let mut out = String::with_capacity(markdown.len());
+    let mut prose = String::new();
+    let flush = |prose: &mut String, out: &mut String| {
+        let text = display.replace_all(prose, |c: &Captures| format!("{}$${}$$", &c[1], &c[2]));
+        // Inline code keeps its backslashes
+        let mut last = 0;
+        for span in code_spans(&text) {
+            out.push_str(&inline.replace_all(&text[last..span.start], |c: &Captures| format!("${}$", &c[1])));
+            out.push_str(&text[span.clone()]);
+            last = span.end;
+        }
+        out.push_str(&inline.replace_all(&text[last..], |c: &Captures| format!("${}$", &c[1])));
+        prose.clear();
+    };
+    let mut in_fence = false;
+    for line in markdown.split_inclusive('\n') {
+        if is_fence(line) {
+            if !in_fence {
+                flush(&mut prose, &mut out);
+            }
+            in_fence = !in_fence;
+            out.push_str(line);
+        } else if in_fence {
+            out.push_str(line);
+        } else {
+            prose.push_str(line);
+        }
+    }
+    flush(&mut prose, &mut out);
+    out
+}
+
+fn render_math(tex: &str, display: bool) -> String {
+    // MathML renders natively in current browsers, so no KaTeX fonts or CSS are needed
+    let opts = katex::Opts::builder()
+        .display_mode(display)
+        .output_type(katex::OutputType::Mathml)
+        .build();
+    match opts.map_err(anyhow::Error::from).and_then(|opts| Ok(katex::render_with_opts(tex, &opts)?)) {
+        Ok(mathml) => mathml,
+        Err(_) => format!("<code class=\"math-error\">{}</code>", escape_xml(tex)),
+    }
+}
+
+fn highlight_code(code: &str, lang: &str, syntaxes: &SyntaxSet) -> String {
+    let lang = lang.split_whitespace().next().unwrap_or("");
+    let Some(syntax) = (!lang.is_empty()).then(|| syntaxes.find_syntax_by_token(lang)).flatten() else {
+        return format!("<pre><code>{}</code></pre>\n", escape_xml(code));
+    };
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, syntaxes, CODE_CLASSES);
+    for line in LinesWithEndings::from(code) {
+        if generator.parse_html_for_line_which_includes_newline(line).is_err() {
+            return format!("<pre><code>{}</code></pre>\n", escape_xml(code));
+        }
+    }
+    format!(
+        "<pre class=\"highlight\"><code class=\"language-{}\">{}</code></pre>\n",
+        escape_xml(lang),
+        generator.finalize()
+    )
+}
+
+/// Render the combined Markdown as one self-contained HTML page: embedded stylesheet, sidebar
+/// TOC, highlighted code, MathML for LaTeX math, and `page_N` anchors as margin page links.
+/// Heading IDs come from `slugs` as in `render::parse_markdown`.
+pub fn write_html(
+    path: &Path,
+    metadata: &BookMetadata,
+    markdown: &str,
+    slugs: &mut impl Iterator<Item = String>,
+    toc: &[TocEntry],
+) -> Result<()> {
+    let page_anchor = Regex::new(r"<a id='page_(\d+)'></a>")?;
+    let markdown = normalize_math_delimiters(markdown);
+    let markdown = page_anchor.replace_all(&markdown, "<a class=\"page\" id=\"page_$1\" href=\"#page_$1\">p. $1</a>");
+
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_MATH;
+    let (events, _) = parse_markdown(&markdown, options, slugs);
+
+    let syntaxes = SyntaxSet::load_defaults_newlines();
+    let mut rendered = Vec::with_capacity(events.len());
+    let mut code: Option<(String, String)> = None;
+    for event in events {
+        match (event, &mut code) {
+            (Event::Start(Tag::CodeBlock(kind)), _) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(lang) => lang.to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((lang, String::new()));
+            }
+            (Event::End(TagEnd::CodeBlock), Some((lang, text))) => {
+                rendered.push(Event::Html(CowStr::from(highlight_code(text, lang, &syntaxes))));
+                code = None;
+            }
+            (Event::Text(t), Some((_, text))) => text.push_str(&t),
+            (Event::InlineMath(tex), _) => rendered.push(Event::InlineHtml(CowStr::from(render_math(&tex, false)))),
+            (Event::DisplayMath(tex), _) => rendered.push(Event::InlineHtml(CowStr::from(render_math(&tex, true)))),
+            (event, _) => rendered.push(event),
+        }
+    }
+    let mut body = String::new();
+    html::push_html(&mut body, rendered.into_iter());
+
+    let entries: Vec<(usize, String, String)> =
+        toc.iter().map(|e| (e.level, e.title.clone(), format!("#{}", e.target))).collect();
+    let theme = ThemeSet::load_defaults()
+        .themes
+        .remove(CODE_THEME)
+        .context("Missing built-in code theme")?;
+    let code_css = css_for_theme_with_class_style(&theme, CODE_CLASSES)?;
+    let (author_meta, byline) = match &metadata.author {
+        Some(author) => (
+            format!("<meta name=\"author\" content=\"{}\">\n", escape_xml(author)),
+            format!("<p class=\"author\">{}</p>\n", escape_xml(author)),
+        ),
+        None => (String::new(), String::new()),
+    };
+
+    let page = format!(
+        "<!DOCTYPE html>\n<html lang=\"{lang}\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{title}</title>\n{author_meta}\
+         <style>\n{css}{code_css}</style>\n</head>\n<body>\n\
+         <nav class=\"toc\">\n<h2>Table of Contents</h2>{toc}\n</nav>\n\
+         <main>\n<h1 class=\"title\">{title}</h1>\n{byline}{body}</main>\n</body>\n</html>\n",
+        lang = escape_xml(&metadata.language),
+        title = escape_xml(&metadata.title),
+        css = STYLESHEET,
+        code_css = code_css,
+        toc = nested_list("ul", &entries),
+        author_meta = author_meta,
+        byline = byline,
+        body = body
+    );
+    std::fs::write(path, page).with_context(|| format!("Failed to write {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(markdown: &str) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("book.html");
+        let metadata = BookMetadata { title: "Book".to_string(), author: None, language: "en".to_string(), cover: None };
+        write_html(&path, &metadata, markdown, &mut std::iter::empty(), &[]).unwrap();
+        let page = std::fs::read_to_string(&path).unwrap();
+        let start = page.find("<h1 class=\"title\">Book</h1>\n").unwrap() + "<h1 class=\"title\">Book</h1>\n".len();
+        page[start..page.rfind("</main>").unwrap()].to_string()
+    }
+
+    #[test]
+    fn rewrites_bracket_math_delimiters() {
+        assert_eq!(normalize_math_delimiters(r"Area \(\pi r^2\) and \(x\)."), r"Area $\pi r^2$ and $x$.");
+        assert_eq!(normalize_math_delimiters("Before\n\\[\nE = mc^2\n\\]\nAfter"), "Before\n$$\nE = mc^2\n$$\nAfter");
+        assert_eq!(normalize_math_delimiters("  \\[a + b\\]  \n"), "  $$a + b$$\n");
+    }
+
+    #[test]
+    fn leaves_citations_code_and_escapes_alone() {
+        // Mid-line brackets are an escaped citation, not display math
+        assert_eq!(normalize_math_delimiters(r"See \[1\] and \[2\]."), r"See \[1\] and \[2\].");
+        assert_eq!(
+            normalize_math_delimiters(r"Type `\(x\)` or ``a ` \(y\)`` for \(z\)."),
+            r"Type `\(x\)` or ``a ` \(y\)`` for $z$."
+        );
+        assert_eq!(normalize_math_delimiters("```\n\\(x\\)\n\\[\ny\n\\]\n```\n"), "```\n\\(x\\)\n\\[\ny\n\\]\n```\n");
+        assert_eq!(normalize_math_delimiters(r"It costs \$5, or \$6."), r"It costs \$5, or \$6.");
+    }
+
+    #[test]
+    fn renders_math_but_not_dollars_in_code_or_escaped() {
+        let body = render("Inline \\(x^2\\), `$a$ and \\(b\\)`, and \\$5 or \\$6.\n\n\\[\ny = 1\n\\]\n");
+        assert_eq!(body.matches("<math").count(), 2);
+        assert!(body.contains("<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"block\">"));
+        assert!(body.contains("<code>$a$ and \\(b\\)</code>"));
+        assert!(body.contains("and $5 or $6."));
+    }
+
+    #[test]
+    fn highlights_known_languages_only() {
+        let body = render("```rust\nfn main() {}\n```\n\n```nosuchlang\na < b\n```\n\n```\nplain & simple\n```\n");
+        assert!(body.contains("<pre class=\"highlight\"><code class=\"language-rust\"><span class=\"hl-source hl-rust\">"));
+        assert!(body.contains("<pre><code>a &lt; b\n</code></pre>"));
+        assert!(body.contains("<pre><code>plain &amp; simple\n</code></pre>"));
+        assert!(!body.contains("language-nosuchlang"));
+    }
+}
@@ -0,0 +1,505 @@
+use anyhow::{Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use mupdf::{TextBlockType, TextPageOptions};
+use rayon::prelude::*;
+use regex::Regex;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::combine::{FontHints, FontLine};
+use crate::PageSelection;
+
+/// Document formats mupdf can open. `.zip` archives of page images are opened
+/// through mupdf's comic-book handler, which sniffs the content rather than the extension.
+pub const DOCUMENT_EXTENSIONS: &[&str] = &["pdf", "xps", "oxps", "epub", "cbz", "zip", "fb2", "mobi"];
+
+/// Scanned page images accepted in image folders
+pub const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "tif", "tiff", "webp", "bmp", "gif"];
+
+/// Multi-page TIFF scans, split with the `tiff` decoder
+pub const TIFF_EXTENSIONS: &[&str] = &["tif", "tiff"];
+
+/// DjVu scans, rendered through DjVuLibre's command-line tools
+pub const DJVU_EXTENSIONS: &[&str] = &["djvu", "djv"];
+
+/// How a pipeline input is turned into `page_NNNN.png` files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    /// Anything mupdf renders: PDF, XPS, EPUB, CBZ/zip, FB2, MOBI
+    Document,
+    /// A directory of page images, one file per page
+    ImageFolder,
+    /// A (usually multi-page) TIFF scan
+    Tiff,
+    /// A DjVu scan
+    Djvu,
+}
+
+impl InputKind {
+    pub fn detect(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Ok(InputKind::ImageFolder);
+        }
+        if has_extension(path, DOCUMENT_EXTENSIONS) {
+            return Ok(InputKind::Document);
+        }
+        if has_extension(path, TIFF_EXTENSIONS) {
+            return Ok(InputKind::Tiff);
+        }
+        if has_extension(path, DJVU_EXTENSIONS) {
+            return Ok(InputKind::Djvu);
+        }
+        Err(anyhow::anyhow!(
+            "Unsupported input {:?} (expected one of: {}, {}, {}, or a folder of page images)",
+            path,
+            DOCUMENT_EXTENSIONS.join(", "),
+            TIFF_EXTENSIONS.join(", "),
+            DJVU_EXTENSIONS.join(", ")
+        ))
+    }
+}
+
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| extensions.iter().any(|x| e.eq_ignore_ascii_case(x)))
+}
+
+/// Compare file names so that "scan2" sorts before "scan10"
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, _) => return Ordering::Less,
+            (_, None) => return Ordering::Greater,
+            (Some(ca), Some(cb)) if ca.is_ascii_digit() && cb.is_ascii_digit() => {
+                let da = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+                let db = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+                let na = a[..da].trim_start_matches('0');
+                let nb = b[..db].trim_start_matches('0');
+                let ord = na.len().cmp(&nb.len()).then_with(|| na.cmp(nb));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[da..];
+                b = &b[db..];
+            }
+            (Some(ca), Some(cb)) => {
+                let ord = ca.to_ascii_lowercase().cmp(&cb.to_ascii_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[ca.len_utf8()..];
+                b = &b[cb.len_utf8()..];
+            }
+        }
+    }
+}
+
+/// Page images in a folder, in natural file name order
+pub fn list_folder_images(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut images = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {:?}", dir))? {
+        let path = entry?.path();
+        let hidden = path.file_name().and_then(|n| n.to_str()).is_none_or(|n| n.starts_with('.'));
+        if path.is_file() && !hidden && has_extension(&path, IMAGE_EXTENSIONS) {
+            images.push(path);
+        }
+    }
+    images.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    Ok(images)
+}
+
+/// Documents to process: the input itself, or every supported document and
+/// image folder inside the input directory
+pub fn find_input_documents(input: &Path) -> Result<Vec<PathBuf>> {
+    if !input.is_dir() {
+        return Ok(vec![input.to_path_buf()]);
+    }
+
+    let mut docs = Vec::new();
+    for entry in std::fs::read_dir(input)? {
+        let path = entry?.path();
+        // Single-page TIFFs are loose page scans, not books of their own
+        let is_document = path.is_file()
+            && (has_extension(&path, DOCUMENT_EXTENSIONS)
+                || has_extension(&path, DJVU_EXTENSIONS)
+                || (has_extension(&path, TIFF_EXTENSIONS) && tiff_page_count(&path).unwrap_or(0) > 1));
+        if is_document || (path.is_dir() && !list_folder_images(&path)?.is_empty()) {
+            docs.push(path);
+        }
+    }
+    docs.sort();
+
+    if docs.is_empty() {
+        // A directory of scans with no documents in it is a single book
+        if !list_folder_images(input)?.is_empty() {
+            println!("Treating {:?} as a folder of page images", input);
+            return Ok(vec![input.to_path_buf()]);
+        }
+        println!("No supported documents found in directory: {:?}", input);
+    } else {
+        println!("Found {} documents in directory: {:?}", docs.len(), input);
+    }
+    Ok(docs)
+}
+
+/// Convert a folder of scanned page images into `page_NNNN.png`, numbered by natural file order
+pub fn extract_image_folder(input: &Path, output_dir: &Path, selection: &PageSelection) -> Result<()> {
+    if !output_dir.exists() {
+        std::fs::create_dir_all(output_dir).context("Failed to create output dir")?;
+    }
+
+    let images = list_folder_images(input)?;
+    let total_pages = images.len();
+    let pages = selection.apply(images.into_iter().enumerate().map(|(i, p)| (i + 1, p)).collect());
+
+    println!("Converting {} images (of {}) from {:?} in parallel...", pages.len(), total_pages, input);
+
+    let pb = ProgressBar::new(pages.len() as u64);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?
+        .progress_chars("#>-"));
+
+    pages.into_par_iter().for_each(|(page_num, source)| {
+        let output_path = output_dir.join(format!("page_{:04}.png", page_num));
+        if selection.force || !output_path.exists() {
+            let process = || -> Result<()> {
+                let img = image::open(&source).with_context(|| format!("Failed to decode {:?}", source))?;
+                img.save_with_format(&output_path, image::ImageFormat::Png)?;
+                Ok(())
+            };
+            if let Err(e) = process() {
+                eprintln!("Error processing page {}: {}", page_num, e);
+            }
+        }
+        pb.inc(1);
+    });
+
+    pb.finish_with_message("Extraction complete");
+    Ok(())
+}
+
+// --- Multi-page TIFF ---
+
+fn open_tiff(path: &Path) -> Result<tiff::decoder::Decoder<std::io::BufReader<std::fs::File>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    tiff::decoder::Decoder::new(std::io::BufReader::new(file))
+        .map(|d| d.with_limits(tiff::decoder::Limits::unlimited()))
+        .with_context(|| format!("Failed to read TIFF {:?}", path))
+}
+
+/// Number of images (pages) in a TIFF file
+pub fn tiff_page_count(path: &Path) -> Result<usize> {
+    let mut decoder = open_tiff(path)?;
+    let mut count = 1;
+    while decoder.more_images() {
+        decoder.next_image()?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Pixel size of one TIFF page (1-based)
+pub fn tiff_page_dimensions(path: &Path, page_num: usize) -> Result<(u32, u32)> {
+    let mut decoder = open_tiff(path)?;
+    decoder.seek_to_image(page_num - 1)?;
+    Ok(decoder.dimensions()?)
+}
+
+/// Convert a full-range YCbCr pixel to RGB with the JPEG (ITU-R BT.601) equations
+fn ycbcr_to_rgb(p: &[u8]) -> [u8; 3] {
+    let (y, cb, cr) = (p[0] as f32, p[1] as f32 - 128.0, p[2] as f32 - 128.0);
+    [y + 1.402 * cr, y - 0.344_136 * cb - 0.714_136 * cr, y + 1.772 * cb].map(|c| c.round().clamp(0.0, 255.0) as u8)
+}
+
+/// Decode one TIFF page (1-based) into an image the `image` crate can save
+fn decode_tiff_page(path: &Path, page_num: usize) -> Result<image::DynamicImage> {
+    use image::DynamicImage as D;
+    use tiff::decoder::DecodingResult as R;
+    use tiff::ColorType as C;
+
+    let mut decoder = open_tiff(path)?;
+    decoder.seek_to_image(page_num - 1)?;
+    let (width, height) = decoder.dimensions()?;
+    let color = decoder.colortype()?;
+    let data = decoder.read_image()?;
+
+    let image = match (color, data) {
+        // Bilevel fax scans: rows are bit-packed and padded to a whole byte
+        (C::Gray(1), R::U8(bits)) => {
+            let stride = (width as usize).div_ceil(8);
+            let pixels = bits
+                .chunks(stride)
+                .flat_map(|row| (0..width as usize).map(move |x| if row[x / 8] & (0x80 >> (x % 8)) != 0 { 255 } else { 0 }))
+                .collect();
+            image::GrayImage::from_raw(width, height, pixels).map(D::ImageLuma8)
+        }
+        (C::Gray(8), R::U8(v)) => image::GrayImage::from_raw(width, height, v).map(D::ImageLuma8),
+        (C::Gray(16), R::U16(v)) => image::ImageBuffer::from_raw(width, height, v).map(D::ImageLuma16),
+        (C::GrayA(8), R::U8(v)) => image::GrayAlphaImage::from_raw(width, height, v).map(D::ImageLumaA8),
+        (C::RGB(8), R::U8(v)) => image::RgbImage::from_raw(width, height, v).map(D::ImageRgb8),
+        (C::YCbCr(8), R::U8(v)) => {
+            // The decoder upsamples JPEG-compressed pages but leaves uncompressed subsampled chroma packed
+            if v.len() != width as usize * height as usize * 3 {
+                anyhow::bail!("Unsupported TIFF page: uncompressed YCbCr with subsampled chroma");
+            }
+            let rgb = v.chunks_exact(3).flat_map(ycbcr_to_rgb).collect();
+            image::RgbImage::from_raw(width, height, rgb).map(D::ImageRgb8)
+        }
+        (C::RGB(16), R::U16(v)) => image::ImageBuffer::from_raw(width, height, v).map(D::ImageRgb16),
+        (C::RGBA(8), R::U8(v)) => image::RgbaImage::from_raw(width, height, v).map(D::ImageRgba8),
+        (C::RGBA(16), R::U16(v)) => image::ImageBuffer::from_raw(width, height, v).map(D::ImageRgba16),
+        (C::CMYK(8), R::U8(v)) => {
+            let rgb = v
+                .chunks_exact(4)
+                .flat_map(|p| {
+                    let k = 255 - p[3] as u32;
+                    [0, 1, 2].map(|i| ((255 - p[i] as u32) * k / 255) as u8)
+                })
+                .collect();
+            image::RgbImage::from_raw(width, height, rgb).map(D::ImageRgb8)
+        }
+        (color, _) => return Err(anyhow::anyhow!("Unsupported TIFF color type {:?}", color)),
+    };
+    image.context("TIFF page data does not match its dimensions")
+}
+
+/// Split a multi-page TIFF into `page_NNNN.png`, one per TIFF page at its native resolution
+pub fn extract_tiff(input: &Path, output_dir: &Path, selection: &PageSelection) -> Result<()> {
+    if !output_dir.exists() {
+        std::fs::create_dir_all(output_dir).context("Failed to create output dir")?;
+    }
+
+    let total_pages = tiff_page_count(input)?;
+    let pages = selection.apply((1..=total_pages).map(|n| (n, ())).collect());
+
+    println!("Extracting {} pages (of {}) from {:?} in parallel...", pages.len(), total_pages, input);
+
+    let pb = ProgressBar::new(pages.len() as u64);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?
+        .progress_chars("#>-"));
+
+    // Each page seeks its own decoder, so pages decode independently
+    pages.into_par_iter().for_each(|(page_num, _)| {
+        let output_path = output_dir.join(format!("page_{:04}.png", page_num));
+        if selection.force || !output_path.exists() {
+            let process = || -> Result<()> {
+                let img = decode_tiff_page(input, page_num)?;
+                img.save_with_format(&output_path, image::ImageFormat::Png)?;
+                Ok(())
+            };
+            if let Err(e) = process() {
+                eprintln!("Error processing page {}: {}", page_num, e);
+            }
+        }
+        pb.inc(1);
+    });
+
+    pb.finish_with_message("Extraction complete");
+    Ok(())
+}
+
+// --- DjVu ---
+
+/// Run a DjVuLibre tool and return its stdout
+fn djvulibre(tool: &str, args: &[&str], input: &Path) -> Result<Vec<u8>> {
+    let output = Command::new(tool)
+        .args(args)
+        .arg(input)
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow::anyhow!(
+                "DjVu input needs DjVuLibre's `{}` on PATH (e.g. `apt install djvulibre-bin` or `brew install djvulibre`)",
+                tool
+            ),
+            _ => anyhow::anyhow!("Failed to run {}: {}", tool, e),
+        })?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} failed on {:?}: {}",
+            tool,
+            input,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+pub fn djvu_page_count(path: &Path) -> Result<usize> {
+    let out = djvulibre("djvused", &["-e", "n"], path)?;
+    String::from_utf8_lossy(&out)
+        .trim()
+        .parse()
+        .with_context(|| format!("Unexpected page count from djvused for {:?}", path))
+}
+
+/// Resolution DjVuLibre assumes when a page's INFO chunk gives none it can use
+const DJVU_DEFAULT_DPI: u32 = 300;
+
+/// Stored width, height and resolution from a `djvused` dump of one page, whose INFO chunk
+/// reads like `INFO [10]  DjVu 2550x3300, v24, 600 dpi, gamma=2.2`
+fn parse_djvu_info(dump: &str) -> Option<(u32, u32, u32)> {
+    let info = Regex::new(r"DjVu (\d+)x(\d+),[^\n]*?(\d+) dpi").expect("valid regex");
+    let cap = info.captures(dump)?;
+    let dpi = cap[3].parse().ok().filter(|d| (25..=6000).contains(d)).unwrap_or(DJVU_DEFAULT_DPI);
+    Some((cap[1].parse().ok()?, cap[2].parse().ok()?, dpi))
+}
+
+/// Approximate rendered size of one DjVu page (1-based) at the given DPI, scaled from the
+/// stored size by the resolution the page was scanned at
+pub fn djvu_page_dimensions(path: &Path, page_num: usize, dpi: u16) -> Result<(u32, u32)> {
+    let script = format!("select {}; dump", page_num);
+    let out = String::from_utf8_lossy(&djvulibre("djvused", &["-e", &script], path)?).into_owned();
+    let (width, height, stored_dpi) = parse_djvu_info(&out)
+        .with_context(|| format!("No page INFO chunk in djvused output for page {} of {:?}", page_num, path))?;
+    let scale = dpi as f64 / stored_dpi as f64;
+    Ok(((width as f64 * scale) as u32, (height as f64 * scale) as u32))
+}
+
+/// Render a DjVu document into `page_NNNN.png` at the given DPI, with its hidden text layer
+/// (if any) in `page_NNNN.txt` like the PDF path
+pub fn extract_djvu(input: &Path, output_dir: &Path, dpi: u16, selection: &PageSelection) -> Result<()> {
+    if !output_dir.exists() {
+        std::fs::create_dir_all(output_dir).context("Failed to create output dir")?;
+    }
+
+    let total_pages = djvu_page_count(input)?;
+    let pages = selection.apply((1..=total_pages).map(|n| (n, ())).collect());
+
+    println!("Extracting {} pages (of {}) from {:?} in parallel...", pages.len(), total_pages, input);
+
+    let pb = ProgressBar::new(pages.len() as u64);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?
+        .progress_chars("#>-"));
+
+    pages.into_par_iter().for_each(|(page_num, _)| {
+        let output_path = output_dir.join(format!("page_{:04}.png", page_num));
+        let text_path = output_dir.join(format!("page_{:04}.txt", page_num));
+        let process = || -> Result<()> {
+            if selection.force || !output_path.exists() {
+                let page_arg = format!("-page={}", page_num);
+                let scale_arg = format!("-scale={}", dpi);
+                let pnm = djvulibre("ddjvu", &["-format=pnm", &page_arg, &scale_arg], input)?;
+                let img = image::load_from_memory_with_format(&pnm, image::ImageFormat::Pnm)
+                    .context("Failed to decode ddjvu output")?;
+                img.save_with_format(&output_path, image::ImageFormat::Png)?;
+            }
+            if selection.force || !text_path.exists() {
+                // Scans without a hidden text layer get an empty file, as PDFs do
+                let text = djvulibre("djvutxt", &[&format!("--page={}", page_num)], input)?;
+                std::fs::write(&text_path, String::from_utf8_lossy(&text).as_bytes())?;
+            }
+            Ok(())
+        };
+        if let Err(e) = process() {
+            eprintln!("Error processing page {}: {}", page_num, e);
+        }
+        pb.inc(1);
+    });
+
+    pb.finish_with_message("Extraction complete");
+    Ok(())
+}
+
+// --- Text Layer ---
+
+/// Pages with less text than this are left to the model (title pages, figures)
+const MIN_TEXT_LAYER_CHARS: usize = 200;
+
+/// Plain text of a page from mupdf's structured text, one line per text line and
+/// a blank line between blocks, along with the lines set larger than the body text
+pub fn page_text(page: &mupdf::Page) -> Result<(String, FontHints)> {
+    let text_page = page.to_text_page(TextPageOptions::PRESERVE_LIGATURES | TextPageOptions::PRESERVE_WHITESPACE)?;
+    let mut blocks = Vec::new();
+    let mut sized_lines = Vec::new();
+    // Character counts per font size, in half points
+    let mut size_counts: std::collections::HashMap<i32, usize> = std::collections::HashMap::new();
+    for block in text_page.blocks() {
+        if block.r#type() != TextBlockType::Text {
+            continue;
+        }
+        let mut lines = Vec::new();
+        for line in block.lines() {
+            let chars: Vec<_> = line.chars().collect();
+            let text = chars.iter().filter_map(|c| c.char()).collect::<String>().trim_end().to_string();
+            if text.is_empty() {
+                continue;
+            }
+            for c in &chars {
+                *size_counts.entry((c.size() * 2.0).round() as i32).or_default() += 1;
+            }
+            let size = chars.iter().map(|c| c.size()).sum::<f32>() / chars.len() as f32;
+            sized_lines.push(FontLine { text: text.trim().to_string(), size });
+            lines.push(text);
+        }
+        if !lines.is_empty() {
+            blocks.push(lines.join("\n"));
+        }
+    }
+
+    let body_size = size_counts.into_iter().max_by_key(|(_, n)| *n).map_or(0.0, |(s, _)| s as f32 / 2.0);
+    let lines = sized_lines.into_iter().filter(|l| l.size >= body_size * 1.1).collect();
+    Ok((blocks.join("\n\n"), FontHints { body_size, lines }))
+}
+
+/// Heuristic check that a text layer is real, readable text rather than
+/// garbage from a broken font encoding or a poor OCR pass
+pub fn text_layer_usable(text: &str) -> bool {
+    let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if chars.len() < MIN_TEXT_LAYER_CHARS {
+        return false;
+    }
+
+    // Unmapped glyphs show up as U+FFFD or control characters
+    let broken = chars.iter().filter(|c| **c == '\u{FFFD}' || c.is_control()).count();
+    if broken * 100 > chars.len() {
+        return false;
+    }
+
+    let alnum = chars.iter().filter(|c| c.is_alphanumeric()).count();
+    if (alnum as f32) < chars.len() as f32 * 0.7 {
+        return false;
+    }
+
+    // Most tokens should look like words, not "l1I|" soup
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let wordlike = words.iter()
+        .filter(|w| {
+            let letters = w.chars().filter(|c| c.is_alphabetic()).count();
+            letters > 0 && letters * 2 >= w.chars().count() && w.chars().count() <= 25
+        })
+        .count();
+    wordlike as f32 >= words.len() as f32 * 0.75
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_ycbcr_to_rgb() {
+        assert_eq!(ycbcr_to_rgb(&[128, 128, 128]), [128, 128, 128]);
+        assert_eq!(ycbcr_to_rgb(&[0, 128, 128]), [0, 0, 0]);
+        assert_eq!(ycbcr_to_rgb(&[255, 128, 128]), [255, 255, 255]);
+        assert_eq!(ycbcr_to_rgb(&[76, 85, 255]), [254, 0, 0]);
+        assert_eq!(ycbcr_to_rgb(&[29, 255, 107]), [0, 0, 254]);
+    }
+
+    #[test]
+    fn reads_djvu_page_resolution() {
+        let dump = "  FORM:DJVU [81326] \n    INFO [10]         DjVu 5100x6600, v24, 600 dpi, gamma=2.2\n    Sjbz [50213]      JB2 bilevel data\n";
+        assert_eq!(parse_djvu_info(dump), Some((5100, 6600, 600)));
+    }
+
+    #[test]
+    fn falls_back_to_300_dpi_for_unusable_resolutions() {
+        let dump = "    INFO [10]         DjVu 2550x3300, v24, 0 dpi, gamma=2.2\n";
+        assert_eq!(parse_djvu_info(dump), Some((2550, 3300, 300)));
+        assert_eq!(parse_djvu_info("FORM:DJVM [100]\n"), None);
+    }
+}
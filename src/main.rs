@@ -1,17 +1,42 @@
+mod combine;
+mod confidence;
+mod consensus;
+mod epub;
+mod estimate;
+mod html;
+mod input;
+mod mdbook;
+mod overlay;
+mod provider;
+mod render;
+mod structured;
+mod validate;
+
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose, Engine as _};
-use clap::{Parser, Subcommand};
+use combine::{
+    CombineOptions, DocumentInfo, OutlineEntry, OutputFormat, Slugger, TocEntry, METADATA_FILE,
+    OUTLINE_FILE,
+};
+use confidence::Confidence;
+use consensus::{CandidateInfo, ConsensusReport};
+use estimate::EstimateOptions;
+use input::{page_text, text_layer_usable, InputKind};
+use clap::{Parser, Subcommand, ValueEnum};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use mupdf::{Colorspace, Matrix};
-use reqwest::Client;
+use mupdf::{Colorspace, Matrix, MetadataName};
+use provider::{ApiError, PageRequest, Provider, ProviderKind, Usage};
+use render::BookMetadata;
+use structured::PageBlocks;
+use validate::{Problem, ValidationOptions};
 use serde::{Deserialize, Serialize};
-use std::env;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use tempfile::NamedTempFile;
 use tokio::fs;
-use tokio::sync::Semaphore;
+use tokio::sync::{watch, Semaphore};
 use rayon::prelude::*;
 use walkdir::WalkDir;
 use regex::Regex;
@@ -25,9 +50,9 @@ struct Args {
 
 #[derive(Subcommand, Debug, Clone)]
 enum Commands {
-    /// Extract pages from a PDF to Images
+    /// Extract pages from a document or image folder to Images
     Extract {
-        /// Input PDF file
+        /// Input document (PDF, XPS, EPUB, CBZ, FB2, MOBI), zip of page images, or folder of page images
         #[arg(short, long)]
         input: PathBuf,
 
@@ -39,11 +64,9 @@ enum Commands {
         #[arg(long, default_value_t = 300)]
         dpi: u16,
 
-        /// Limit number of pages to extract
-        #[arg(long)]
-        limit: Option<usize>,
+        #[command(flatten)]
+        pages: PageSelection,
     },
-    // ... Transcribe stays same ...
     Transcribe {
         /// Input directory containing images
         #[arg(short, long)]
@@ -57,18 +80,21 @@ enum Commands {
         #[arg(short, long, default_value_t = 50)]
         concurrency: usize,
 
-        /// OpenRouter Model ID (e.g., google/gemini-flash-1.5, anthropic/claude-3.5-sonnet)
+        /// Model ID for the selected provider (e.g., google/gemini-flash-1.5 on OpenRouter), or a
+        /// comma-separated list of models to fall back through when a page fails on the one before.
         /// Falls back to OPENROUTER_MODEL env var if not specified
         #[arg(long, env = "OPENROUTER_MODEL")]
-        model: Option<String>,
+        model: Option<ModelChain>,
         
-        /// Limit number of images (for testing)
-        #[arg(long)]
-        limit: Option<usize>,
+        #[command(flatten)]
+        pages: PageSelection,
+
+        #[command(flatten)]
+        options: TranscribeOptions,
     },
     /// Run both pipeline steps: Extract then Transcribe
     Pipeline {
-        /// Input PDF file
+        /// Input document, zip or image folder, or a directory of them
         #[arg(short, long)]
         input: PathBuf,
 
@@ -84,14 +110,58 @@ enum Commands {
         #[arg(short, long, default_value_t = 50)]
         concurrency: usize,
 
-        /// OpenRouter Model ID (e.g., google/gemini-flash-1.5, anthropic/claude-3.5-sonnet)
+        /// Model ID for the selected provider (e.g., google/gemini-flash-1.5 on OpenRouter), or a
+        /// comma-separated list of models to fall back through when a page fails on the one before.
         /// Falls back to OPENROUTER_MODEL env var if not specified
         #[arg(long, env = "OPENROUTER_MODEL")]
-        model: Option<String>,
+        model: Option<ModelChain>,
 
-        /// Limit number of pages to process
+        #[command(flatten)]
+        pages: PageSelection,
+
+        #[command(flatten)]
+        options: TranscribeOptions,
+
+        #[command(flatten)]
+        combine: CombineOptions,
+
+        /// Only print a cost and time estimate (same as the `estimate` command)
         #[arg(long)]
-        limit: Option<usize>,
+        dry_run: bool,
+
+        #[command(flatten)]
+        estimate: EstimateOptions,
+    },
+    /// Estimate pages, tokens, cost and duration of a pipeline run without calling any API
+    Estimate {
+        /// Input document, zip or image folder, or a directory of them
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Base output directory, checked for pages that are already done
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// DPI for rasterization
+        #[arg(long, default_value_t = 300)]
+        dpi: u16,
+
+        /// Number of concurrent requests
+        #[arg(short, long, default_value_t = 50)]
+        concurrency: usize,
+
+        /// Model ID used to look up pricing (the first one, if given a list)
+        #[arg(long, env = "OPENROUTER_MODEL")]
+        model: Option<ModelChain>,
+
+        #[command(flatten)]
+        pages: PageSelection,
+
+        #[command(flatten)]
+        options: TranscribeOptions,
+
+        #[command(flatten)]
+        estimate: EstimateOptions,
     },
     /// Combine markdown files into a single book with TOC
     Combine {
@@ -99,66 +169,521 @@ enum Commands {
         #[arg(short, long)]
         input: PathBuf,
 
-        /// Output file path (default: input_dir/../{book_name}.md)
+        /// Output path (default: input_dir/../{book_name}.md, .epub or .html, or a {book_name} directory for mdbook)
         #[arg(short, long)]
         output: Option<PathBuf>,
+
+        #[command(flatten)]
+        options: CombineOptions,
+    },
+    /// Add the transcription to the source PDF as an invisible, searchable text layer
+    Overlay {
+        /// Source PDF
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Directory containing the page_NNNN.md files (default: out/{book_name}/markdown)
+        #[arg(short, long)]
+        markdown: Option<PathBuf>,
+
+        /// Output PDF (default: markdown_dir/../{book_name}.searchable.pdf)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Also add text to pages that already have a usable text layer
+        #[arg(long)]
+        force: bool,
+
+        /// TrueType/OpenType font to embed for the text layer, for scripts the built-in
+        /// Helvetica doesn't cover (e.g. a Noto Sans CJK font for Chinese or Japanese)
+        #[arg(long)]
+        font: Option<PathBuf>,
+    },
+    /// List the transcribed pages with the lowest confidence scores, for proofreading
+    ReviewQueue {
+        /// Directory containing the page_NNNN.md files and their sidecars
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Number of pages to list
+        #[arg(short, long, default_value_t = 20)]
+        limit: usize,
+
+        /// Only list pages scoring below this (0 to 1)
+        #[arg(long)]
+        below: Option<f64>,
+    },
+}
+
+/// Which pages to process, shared by `extract`, `transcribe` and `pipeline`
+#[derive(clap::Args, Debug, Clone, Default)]
+struct PageSelection {
+    /// Limit number of pages to process (for testing; applied after --pages)
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Pages to process by page number, e.g. "120-180" or "1,5,9-12" ("200-" runs to the end)
+    #[arg(long)]
+    pages: Option<PageRanges>,
+
+    /// Regenerate the selected pages even if their output already exists
+    #[arg(long)]
+    force: bool,
+}
+
+impl PageSelection {
+    /// Filter (page number, item) pairs by --pages, then apply --limit
+    fn apply<T>(&self, items: Vec<(usize, T)>) -> Vec<(usize, T)> {
+        let mut selected: Vec<_> = items
+            .into_iter()
+            .filter(|(num, _)| self.pages.as_ref().is_none_or(|r| r.contains(*num)))
+            .collect();
+        if let Some(l) = self.limit {
+            selected.truncate(l);
+        }
+        selected
     }
 }
 
-// --- OpenRouter API Structs ---
+/// A page range expression such as "1,5,9-12" or "120-"
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PageRanges(Vec<(usize, Option<usize>)>);
 
-#[derive(Serialize)]
-struct ChatCompletionRequest {
-    model: String,
-    messages: Vec<Message>,
+impl PageRanges {
+    fn contains(&self, page: usize) -> bool {
+        self.0.iter().any(|&(start, end)| page >= start && end.is_none_or(|e| page <= e))
+    }
+}
+
+impl std::str::FromStr for PageRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse_num = |n: &str| -> std::result::Result<usize, String> {
+            match n.trim().parse::<usize>() {
+                Ok(0) => Err("page numbers start at 1".to_string()),
+                Ok(v) => Ok(v),
+                Err(_) => Err(format!("invalid page number '{}'", n.trim())),
+            }
+        };
+
+        let mut ranges = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let range = match part.split_once('-') {
+                Some((start, end)) => {
+                    let start = parse_num(start)?;
+                    let end = if end.trim().is_empty() { None } else { Some(parse_num(end)?) };
+                    if end.is_some_and(|e| e < start) {
+                        return Err(format!("range '{}' ends before it starts", part));
+                    }
+                    (start, end)
+                }
+                None => {
+                    let page = parse_num(part)?;
+                    (page, Some(page))
+                }
+            };
+            ranges.push(range);
+        }
+
+        if ranges.is_empty() {
+            return Err("empty page range".to_string());
+        }
+        Ok(PageRanges(ranges))
+    }
+}
+
+/// Models to send pages to, in order, e.g. "google/gemini-flash,anthropic/claude-sonnet".
+/// Every page goes to the first; the rest are only tried when the ones before fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ModelChain(Vec<String>);
+
+impl ModelChain {
+    fn primary(&self) -> &str {
+        &self.0[0]
+    }
+}
+
+impl std::ops::Deref for ModelChain {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for ModelChain {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let models: Vec<String> = s.split(',').map(|m| m.trim().to_string()).collect();
+        if models.iter().any(String::is_empty) {
+            return Err("empty model name in list".to_string());
+        }
+        Ok(ModelChain(models))
+    }
+}
+
+/// Transcription settings shared by `transcribe` and `pipeline`
+#[derive(clap::Args, Debug, Clone)]
+struct TranscribeOptions {
+    /// Built-in prompt template
+    #[arg(long, value_enum, default_value_t = PromptTemplate::Book)]
+    template: PromptTemplate,
+
+    /// Custom prompt text (overrides --template).
+    /// Supports {book_title}, {page_number} and {total_pages} placeholders
+    #[arg(long, conflicts_with = "prompt_file")]
+    prompt: Option<String>,
+
+    /// Read the prompt from a file (overrides --template)
+    #[arg(long)]
+    prompt_file: Option<PathBuf>,
+
+    /// LLM backend to send pages to
+    #[arg(long, value_enum, env = "SCRIBE_PROVIDER", default_value_t = ProviderKind::OpenRouter)]
+    provider: ProviderKind,
+
+    /// Override the provider's API base URL (e.g. http://localhost:8080/v1 for a local server)
+    #[arg(long, env = "SCRIBE_BASE_URL")]
+    base_url: Option<String>,
+
+    /// Title substituted for {book_title} (defaults to the book directory name)
+    #[arg(long)]
+    book_title: Option<String>,
+
+    /// How to use the PDF text layer (page_NNNN.txt) written during extraction
+    #[arg(long, value_enum, default_value_t = TextLayerMode::Off)]
+    text_layer: TextLayerMode,
+
+    /// Maximum attempts per page for retryable errors (timeouts, 429, 5xx)
+    #[arg(long, default_value_t = 5)]
+    max_attempts: u32,
+
+    /// Base delay in milliseconds for exponential backoff between attempts
+    #[arg(long, default_value_t = 1000)]
+    backoff_ms: u64,
+
+    /// Stop scheduling new pages once the run's spend (USD) would exceed this. Every request
+    /// counts, including rejected responses and pages that fail. Pages run one at a time until
+    /// the first one has been transcribed to measure the per-page cost; after that a page's cost
+    /// is only known once it finishes, so the run can overshoot by at most the cost of the
+    /// pages already in flight (--concurrency requests' worth)
+    #[arg(long)]
+    max_cost: Option<f64>,
+
+    /// Stop scheduling new pages once the run's token usage would exceed this. Like --max-cost,
+    /// it can overshoot by at most the tokens of the pages in flight
+    #[arg(long)]
+    max_tokens: Option<u64>,
+
+    /// Seconds to wait for in-flight pages after Ctrl-C before aborting them
+    #[arg(long, default_value_t = 120)]
+    drain_timeout: u64,
+
+    /// Also ask for a structured JSON block list per page, saved as page_NNNN.json
+    #[arg(long)]
+    structured: bool,
+
+    #[command(flatten)]
+    validation: ValidationOptions,
+
+    /// Transcribe each page this many times (cycling through the --model list) and merge the
+    /// results by majority vote, saving the candidates and a disagreement report
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "structured")]
+    consensus: u32,
+
+    /// Request token log probabilities to score pages by (OpenAI-compatible providers;
+    /// Gemini reports them without being asked)
+    #[arg(long)]
+    logprobs: bool,
+
+    /// After transcribing a page, ask a model to rate its accuracy against the image
+    #[arg(long)]
+    self_check: bool,
+
+    /// Model for --self-check (defaults to the model that transcribed the page)
+    #[arg(long, requires = "self_check")]
+    self_check_model: Option<String>,
+}
+
+// --- Prompt Templates ---
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum PromptTemplate {
+    /// General-purpose book
+    Book,
+    /// Academic paper (equations, citations, footnotes)
+    Paper,
+    /// Technical manual (code listings, tables, callouts)
+    Manual,
+    /// Legal document (verbatim wording and clause numbering)
+    Legal,
+}
+
+impl PromptTemplate {
+    fn text(self) -> &'static str {
+        match self {
+            PromptTemplate::Book => "Transcribe page {page_number} of {total_pages} from the book \"{book_title}\". \
+                Output strictly formatted Markdown. Use headers, lists, and code blocks where appropriate. \
+                IMPORTANT: Transcribe ALL legible text, including page numbers, headers, footers, and captions. \
+                Do NOT wrap the entire output in a markdown block.",
+            PromptTemplate::Paper => "Transcribe page {page_number} of {total_pages} from the academic paper \"{book_title}\". \
+                Output strictly formatted Markdown. Keep section headings, render equations as LaTeX between $...$ (inline) or $$...$$ (display), \
+                keep citation markers such as [12] exactly as printed, use Markdown tables for tables and include figure captions and footnotes. \
+                IMPORTANT: Transcribe ALL legible text, including page numbers, headers and footers. \
+                Do NOT wrap the entire output in a markdown block.",
+            PromptTemplate::Manual => "Transcribe page {page_number} of {total_pages} from the technical manual \"{book_title}\". \
+                Output strictly formatted Markdown. Put code listings, commands and register layouts in fenced code blocks, \
+                use Markdown tables for tables and blockquotes for notes, warnings and tips. Preserve identifiers and symbols exactly. \
+                IMPORTANT: Transcribe ALL legible text, including page numbers, headers, footers, and captions. \
+                Do NOT wrap the entire output in a markdown block.",
+            PromptTemplate::Legal => "Transcribe page {page_number} of {total_pages} from the legal document \"{book_title}\". \
+                Output strictly formatted Markdown. Reproduce the wording verbatim: do not summarize, correct spelling or modernize language. \
+                Keep section, clause and paragraph numbering exactly as printed, including defined terms, signature blocks and marginal notes. \
+                IMPORTANT: Transcribe ALL legible text, including page numbers, headers, footers, and stamps. \
+                Do NOT wrap the entire output in a markdown block.",
+        }
+    }
+}
+
+/// Placeholders a prompt can use, filled in per page by `render_prompt`
+const PROMPT_PLACEHOLDERS: &[&str] = &["book_title", "page_number", "total_pages"];
+
+impl TranscribeOptions {
+    /// Raw prompt template, before placeholder substitution. A `{name}` that isn't one of the
+    /// placeholders is an error rather than being sent to the model as is.
+    fn prompt_template(&self) -> Result<String> {
+        let template = match &self.prompt_file {
+            Some(path) => {
+                std::fs::read_to_string(path).with_context(|| format!("Failed to read prompt file {:?}", path))?
+            }
+            None => self.prompt.clone().unwrap_or_else(|| self.template.text().to_string()),
+        };
+        let placeholder = Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").expect("valid regex");
+        if let Some(unknown) = placeholder.captures_iter(&template).find(|c| !PROMPT_PLACEHOLDERS.contains(&&c[1])) {
+            anyhow::bail!(
+                "Unknown placeholder {} in the prompt (use {{book_title}}, {{page_number}} or {{total_pages}})",
+                &unknown[0]
+            );
+        }
+        Ok(template)
+    }
+}
+
+/// Fill in the placeholders in one pass, so a book title containing `{page_number}` stays as is
+fn render_prompt(template: &str, book_title: &str, page_number: usize, total_pages: usize) -> String {
+    let placeholder = Regex::new(r"\{(book_title|page_number|total_pages)\}").expect("valid regex");
+    placeholder
+        .replace_all(template, |c: &regex::Captures| match &c[1] {
+            "book_title" => book_title.to_string(),
+            "page_number" => page_number.to_string(),
+            _ => total_pages.to_string(),
+        })
+        .into_owned()
+}
+
+/// Parse the page number out of a `page_NNNN` file stem
+fn page_number_from_stem(stem: &str) -> Option<usize> {
+    stem.strip_prefix("page_")?.parse().ok()
+}
+
+// --- Budget ---
+
+/// Exit status when a run stops because it hit --max-cost or --max-tokens
+const EXIT_BUDGET_EXCEEDED: i32 = 3;
+
+/// Tracks spend against --max-cost / --max-tokens while pages are in flight
+struct Budget {
+    max_cost: Option<f64>,
+    max_tokens: Option<u64>,
+    /// Usage of every request so far, and the number of pages finished. Failed pages and pages
+    /// still in flight add usage without a page, so the average per page errs high.
+    spent: std::sync::Mutex<(Usage, usize)>,
+}
+
+impl Budget {
+    fn new(options: &TranscribeOptions) -> Self {
+        Budget {
+            max_cost: options.max_cost,
+            max_tokens: options.max_tokens,
+            spent: std::sync::Mutex::new((Usage::default(), 0)),
+        }
+    }
+
+    /// Add the usage of a request, as soon as it is known
+    fn spend(&self, usage: Usage) {
+        self.spent.lock().unwrap().0 += usage;
+    }
+
+    /// Count a transcribed page, whose requests have all been spent
+    fn page_done(&self) {
+        self.spent.lock().unwrap().1 += 1;
+    }
+
+    /// Whether a limit is set but no page has been transcribed yet to measure the per-page cost by
+    fn unsampled(&self) -> bool {
+        (self.max_cost.is_some() || self.max_tokens.is_some()) && self.spent.lock().unwrap().1 == 0
+    }
+
+    /// Why no more pages should be started, if `committed` more pages (the running ones and
+    /// the one about to start) at the average cost so far would take the run past a limit
+    fn exhausted(&self, committed: usize) -> Option<String> {
+        let (usage, pages) = *self.spent.lock().unwrap();
+        let projected = |spent: f64| {
+            let avg = if pages > 0 { spent / pages as f64 } else { 0.0 };
+            spent + avg * committed as f64
+        };
+
+        if let Some(max) = self.max_cost {
+            let cost = usage.cost.unwrap_or(0.0);
+            if projected(cost) > max || cost >= max {
+                return Some(format!("--max-cost ${:.2} reached (spent ${:.4})", max, cost));
+            }
+        }
+        if let Some(max) = self.max_tokens {
+            let tokens = usage.total_tokens();
+            if projected(tokens as f64) > max as f64 || tokens >= max {
+                return Some(format!("--max-tokens {} reached (used {})", max, tokens));
+            }
+        }
+        None
+    }
 }
 
-#[derive(Serialize)]
-struct Message {
-    role: String,
-    content: Vec<ContentPart>,
+/// Returned by `transcribe_images` when it stopped early because of the budget
+#[derive(Debug)]
+struct BudgetExceeded {
+    reason: String,
+    spent: Usage,
+    not_started: usize,
 }
 
-#[derive(Serialize)]
-#[serde(tag = "type")]
-enum ContentPart {
-    #[serde(rename = "text")]
-    Text { text: String },
-    #[serde(rename = "image_url")]
-    ImageUrl { image_url: ImageUrlData },
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Budget exceeded: {}. Stopped with {} page(s) not started; spent {}",
+            self.reason, self.not_started, self.spent
+        )
+    }
 }
 
-#[derive(Serialize)]
-struct ImageUrlData {
-    url: String,
+impl std::error::Error for BudgetExceeded {}
+
+// --- Interrupt Handling ---
+
+/// Exit status after Ctrl-C, following the shell convention of 128 + SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Number of Ctrl-C presses since the current transcription started
+static INTERRUPTS: OnceLock<watch::Sender<u32>> = OnceLock::new();
+
+/// True while `transcribe_images` is running and handles Ctrl-C itself
+static GRACEFUL_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+fn interrupt_sender() -> &'static watch::Sender<u32> {
+    INTERRUPTS.get_or_init(|| watch::channel(0).0)
+}
+
+/// Listen for Ctrl-C for the lifetime of the process. Outside of transcription an
+/// interrupt exits immediately, as it would without a handler.
+fn install_interrupt_handler() {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if !GRACEFUL_INTERRUPTS.load(Ordering::SeqCst) {
+                eprintln!("\nInterrupted");
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+            interrupt_sender().send_modify(|n| *n += 1);
+        }
+    });
 }
 
-#[derive(Deserialize, Debug)]
-struct ChatCompletionResponse {
-    choices: Option<Vec<Choice>>,
-    error: Option<OpenRouterError>,
+/// While alive, Ctrl-C is counted for the transcription loop instead of exiting:
+/// the first press stops scheduling, the second aborts in-flight requests
+struct GracefulInterrupts;
+
+impl GracefulInterrupts {
+    fn enter() -> (Self, watch::Receiver<u32>) {
+        interrupt_sender().send_replace(0);
+        GRACEFUL_INTERRUPTS.store(true, Ordering::SeqCst);
+        (GracefulInterrupts, interrupt_sender().subscribe())
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct Choice {
-    message: Option<ResponseMessage>,
+impl Drop for GracefulInterrupts {
+    fn drop(&mut self) {
+        GRACEFUL_INTERRUPTS.store(false, Ordering::SeqCst);
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct ResponseMessage {
-    content: Option<String>,
+/// Returned by `transcribe_images` when the run was stopped with Ctrl-C
+#[derive(Debug)]
+struct Interrupted {
+    spent: Usage,
+    aborted: usize,
+    not_started: usize,
 }
 
-#[derive(Deserialize, Debug)]
-struct OpenRouterError {
-    message: String,
-    #[serde(rename = "type")]
-    error_type: Option<String>,
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Interrupted: {} page(s) aborted in flight, {} not started; spent {}",
+            self.aborted, self.not_started, self.spent
+        )
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+// --- Retry Handling ---
+
+/// Longest we will ever sleep between two attempts, whatever the backoff or Retry-After says
+const MAX_BACKOFF: Duration = Duration::from_secs(120);
+
+/// Exponential backoff with jitter: a random delay in [d/2, d] where d = base * 2^(attempt-1)
+fn backoff_delay(attempt: u32, base: Duration) -> Duration {
+    let exp = base.saturating_mul(1u32 << attempt.saturating_sub(1).min(16)).min(MAX_BACKOFF);
+    let half = exp / 2;
+    half + half.mul_f64(fastrand::f64())
+}
+
+/// Run `request` until it succeeds, hits a fatal error, or runs out of attempts.
+/// Returns the final result together with the number of attempts made.
+async fn with_retries<T, F, Fut>(
+    options: &TranscribeOptions,
+    pb: &ProgressBar,
+    label: &str,
+    mut request: F,
+) -> (std::result::Result<T, ApiError>, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::result::Result<T, ApiError>>,
+{
+    let max_attempts = options.max_attempts.max(1);
+    let base = Duration::from_millis(options.backoff_ms);
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Err(ApiError::Retryable { message, retry_after }) if attempt < max_attempts => {
+                let delay = retry_after
+                    .map(|d| d.min(MAX_BACKOFF))
+                    .unwrap_or_else(|| backoff_delay(attempt, base));
+                pb.set_message(format!("Retry {} ({}/{}) in {:.1}s", label, attempt + 1, max_attempts, delay.as_secs_f32()));
+                pb.println(format!("{}: attempt {} failed, retrying: {}", label, attempt, message));
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return (result, attempt),
+        }
+    }
 }
 
 // --- Phases ---
 
-fn combine_book(input_dir: &Path, output_file: &Path) -> Result<()> {
+fn combine_book(input_dir: &Path, output_file: &Path, options: &CombineOptions) -> Result<()> {
     println!("Combining markdown files from {:?} into {:?}", input_dir, output_file);
     
     let mut files = Vec::new();
@@ -217,85 +742,220 @@ fn combine_book(input_dir: &Path, output_file: &Path) -> Result<()> {
         }
     }
     
-    let mut combined_content = String::new();
-    let mut toc_lines = Vec::new();
-    let mut seen_slugs = std::collections::HashMap::new();
+    // The title and TOC headings come first in the book, so they claim their IDs first
+    let book_name = match options.format {
+        OutputFormat::Mdbook => output_file.file_name(),
+        _ => output_file.file_stem(),
+    };
+    let book_name = book_name.unwrap_or_default().to_string_lossy();
+    let book_title = book_name.replace('_', " ");
+    let mut slugger = Slugger::new(options.anchors);
+    slugger.slug(&book_title);
+    slugger.slug("Table of Contents");
 
     // Regex to match image links containing 'img/' or just general image links for cleanup
     // Python script used: r'!\[.*?\]\([^\)]*?img/[^\)]*\)'
     let img_regex = Regex::new(r"!\[.*?\]\([^\)]*?img/[^\)]*\)")?;
 
-    // Header regex for TOC
-    let header_regex = Regex::new(r"^(#+)\s+(.+)$")?;
-
+    // Read synchronously and strip images
+    let mut pages = Vec::new();
     for (page_num, path) in files {
-        // Read synchronously
         let content = std::fs::read_to_string(&path)?;
-        
-        // Strip images
-        let clean_content = img_regex.replace_all(&content, "");
-        let clean_content = clean_content.trim();
-        
-        combined_content.push_str(&format!("\n<a id='page_{}'></a>\n", page_num));
-        
-        // Scan headers for TOC, processing line by line
-        for line in clean_content.lines() {
-             if let Some(cap) = header_regex.captures(line) {
-                 let level = cap[1].len();
-                 let title = cap[2].trim();
-                 
-                 // Slug generation
-                 let slug_base = title.to_lowercase()
-                    .replace(' ', "-")
-                    .chars()
-                    .filter(|c| c.is_alphanumeric() || *c == '-')
-                    .collect::<String>();
-                 
-                 let slug = if let Some(count) = seen_slugs.get_mut(&slug_base) {
-                     *count += 1;
-                     format!("{}-{}", slug_base, *count)
-                 } else {
-                     seen_slugs.insert(slug_base.clone(), 0);
-                     slug_base
-                 };
-                 
-                 let indent = "  ".repeat(level.saturating_sub(1));
-                 toc_lines.push(format!("{}- [{}]({}) *(Page {})*", indent, title, slug, page_num));
-             }
-        }
-        
-        combined_content.push_str(&clean_content);
-        combined_content.push_str("\n\n---\n\n");
+        pages.push((page_num, img_regex.replace_all(&content, "").trim().to_string()));
     }
+
+    // Cleanup only touches the combined book; page files stay as transcribed
+    if !options.keep_running_heads {
+        let removed = combine::strip_running_heads(&mut pages);
+        println!("Removed {} running header/footer and page number lines", removed);
+    }
+
+    // Extraction manifests (outline, font sizes) live next to the page images
+    let images_dir = input_dir.parent().map(|p| p.join("images"));
+    let outline = match &images_dir {
+        Some(dir) => combine::load_outline(dir)?,
+        None => None,
+    };
+
+    if options.normalize_headings {
+        let fonts = images_dir.as_deref().map(|dir| combine::load_font_hints(dir, &pages)).unwrap_or_default();
+        let stats = combine::normalize_headings(&mut pages, outline.as_deref(), &fonts);
+        println!(
+            "Normalized headings: {} changed, {} running heads demoted (levels from outline: {}, font size: {}, wording: {})",
+            stats.changed, stats.demoted, stats.from_outline, stats.from_fonts, stats.from_patterns
+        );
+    }
+
+    let mut headings = combine::collect_headings(&pages, &mut slugger);
+
+    // A document outline, when extraction found one, is the better table of contents
+    let outline = outline.filter(|_| !options.toc_from_headings);
+    let toc = match &outline {
+        Some(outline) => {
+            let matches = combine::match_outline(outline, &headings);
+            println!(
+                "Using document outline for the TOC ({} entries, {} matched to headings)",
+                outline.len(),
+                matches.iter().flatten().count()
+            );
+            if options.outline_levels {
+                let changed = combine::apply_outline_levels(&mut pages, &mut headings, outline, &matches);
+                println!("Adjusted {} heading levels to match the outline", changed);
+            }
+            combine::outline_toc(outline, &headings, &matches, &pages, options.toc_depth)
+        }
+        None => combine::heading_toc(&headings, options.toc_depth),
+    };
+
+    let combined_content = if options.reflow {
+        let (reflowed, joined) = combine::reflow_pages(&pages);
+        println!("Reflowed {} page breaks", joined);
+        reflowed
+    } else {
+        combine::join_pages(&pages).0
+    };
     
-    let book_name = output_file.file_stem().unwrap_or_default().to_string_lossy();
-    let mut final_doc = format!("# {}\n\n## Table of Contents\n\n", book_name.replace('_', " "));
-    final_doc.push_str(&toc_lines.join("\n"));
-    final_doc.push_str("\n\n---\n\n");
-    final_doc.push_str(&combined_content);
-    
-    std::fs::write(output_file, final_doc)?;
+    match options.format {
+        OutputFormat::Markdown => {
+            let toc_lines: Vec<String> = toc.iter().map(TocEntry::to_markdown).collect();
+            let mut final_doc = format!("# {}\n\n## Table of Contents\n\n", book_title);
+            final_doc.push_str(&toc_lines.join("\n"));
+            final_doc.push_str("\n\n---\n\n");
+            final_doc.push_str(&combined_content);
+
+            std::fs::write(output_file, final_doc)?;
+        }
+        OutputFormat::Epub | OutputFormat::Html | OutputFormat::Mdbook => {
+            // Flags win over the PDF info dictionary, which wins over the file name
+            let info = match &images_dir {
+                Some(dir) => DocumentInfo::load(dir)?,
+                None => DocumentInfo::default(),
+            };
+            let epub = options.format == OutputFormat::Epub;
+            let cover = match (&images_dir, options.cover && epub) {
+                (Some(dir), true) => first_page_image(dir)?,
+                _ => None,
+            };
+            if options.cover && epub && cover.is_none() {
+                println!("Warning: no page images found for the cover");
+            }
+            let metadata = BookMetadata {
+                title: options.title.clone().or(info.title).unwrap_or_else(|| book_title.clone()),
+                author: options.author.clone().or(info.author),
+                language: options.language.clone(),
+                cover,
+            };
+            let mut slugs = headings.iter().map(|h| h.slug.clone());
+            match options.format {
+                OutputFormat::Epub => {
+                    let chapters = render::split_chapters(&combined_content, &metadata.title);
+                    epub::write_epub(output_file, &metadata, &chapters, &mut slugs, &toc)?;
+                    println!("Packaged {} chapters as EPUB", chapters.len());
+                }
+                OutputFormat::Mdbook => {
+                    let chapters = render::split_chapters(&combined_content, &metadata.title);
+                    mdbook::write_mdbook(output_file, &metadata, &chapters, &mut slugs)?;
+                    println!("Wrote {} chapter files and SUMMARY.md for mdBook", chapters.len());
+                }
+                _ => html::write_html(output_file, &metadata, &combined_content, &mut slugs, &toc)?,
+            }
+        }
+    }
     println!("Created combined file: {:?}", output_file);
-    
+
     Ok(())
 }
 
-fn extract_pdf(input: &Path, output_dir: &Path, dpi: u16, limit: Option<usize>) -> Result<()> {
+/// Lowest-numbered `page_NNNN.png` in the images directory
+fn first_page_image(images_dir: &Path) -> Result<Option<PathBuf>> {
+    let mut first: Option<(usize, PathBuf)> = None;
+    for entry in WalkDir::new(images_dir).max_depth(1) {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str() else { continue };
+        let Some(num) = name.strip_prefix("page_").and_then(|n| n.strip_suffix(".png")) else { continue };
+        if let Ok(num) = num.parse::<usize>() {
+            if first.as_ref().is_none_or(|(n, _)| num < *n) {
+                first = Some((num, entry.path().to_path_buf()));
+            }
+        }
+    }
+    Ok(first.map(|(_, path)| path))
+}
+
+// --- Text Layer ---
+
+/// How the PDF's embedded text layer (`page_NNNN.txt`) is used during transcription
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum TextLayerMode {
+    /// Ignore the text layer
+    Off,
+    /// Send the text layer to the model as a hint alongside the image
+    Hint,
+    /// Use the text layer directly for pages that pass the quality check
+    Skip,
+    /// Skip good pages, hint the rest
+    Auto,
+}
+
+fn text_layer_hint(text: &str) -> String {
+    format!(
+        "\n\nThe document's embedded text layer for this page is given below. \
+        It may contain recognition errors, lose formatting or be out of reading order, \
+        so use it only as a reference and always trust the image:\n<text_layer>\n{}\n</text_layer>",
+        text
+    )
+}
+
+/// Extract any supported input into `page_NNNN.png` files
+fn extract_input(input: &Path, output_dir: &Path, dpi: u16, selection: &PageSelection) -> Result<()> {
+    match InputKind::detect(input)? {
+        InputKind::Document => extract_pdf(input, output_dir, dpi, selection),
+        InputKind::ImageFolder => input::extract_image_folder(input, output_dir, selection),
+        InputKind::Tiff => input::extract_tiff(input, output_dir, selection),
+        InputKind::Djvu => input::extract_djvu(input, output_dir, dpi, selection),
+    }
+}
+
+fn extract_pdf(input: &Path, output_dir: &Path, dpi: u16, selection: &PageSelection) -> Result<()> {
     if !output_dir.exists() {
         std::fs::create_dir_all(output_dir).context("Failed to create output dir")?;
     }
 
     // Open once to get count
-    println!("Loading PDF with MuPDF to check page count...");
+    println!("Loading document with MuPDF to check page count...");
     let doc_check = mupdf::Document::open(input.to_str().context("Invalid path")?)
-        .context("Failed to open PDF")?;
+        .context("Failed to open document")?;
     let total_pages = doc_check.page_count().context("Failed to get page count")? as usize;
+
+    // The outline (bookmarks) becomes the table of contents when combining
+    match doc_check.outlines() {
+        Ok(items) => {
+            let mut outline = Vec::new();
+            flatten_outline(&items, 1, &mut outline);
+            if !outline.is_empty() {
+                std::fs::write(output_dir.join(OUTLINE_FILE), serde_json::to_string_pretty(&outline)?)?;
+                println!("Saved document outline ({} entries)", outline.len());
+            }
+        }
+        Err(e) => eprintln!("Warning: could not read document outline: {}", e),
+    }
+
+    // Title and author from the info dictionary feed e-book metadata when combining
+    let info_field = |name| doc_check.metadata(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+    let info = DocumentInfo { title: info_field(MetadataName::Title), author: info_field(MetadataName::Author) };
+    if info.title.is_some() || info.author.is_some() {
+        std::fs::write(output_dir.join(METADATA_FILE), serde_json::to_string_pretty(&info)?)?;
+    }
     
-    let num_pages = limit.map(|l| l.min(total_pages)).unwrap_or(total_pages);
+    let page_nums: Vec<usize> = selection
+        .apply((1..=total_pages).map(|n| (n, ())).collect())
+        .into_iter()
+        .map(|(n, _)| n)
+        .collect();
     
-    println!("Extracting {} pages (of {}) from {:?} in parallel...", num_pages, total_pages, input);
+    println!("Extracting {} pages (of {}) from {:?} in parallel...", page_nums.len(), total_pages, input);
 
-    let pb = ProgressBar::new(num_pages as u64);
+    let pb = ProgressBar::new(page_nums.len() as u64);
     pb.set_style(ProgressStyle::default_bar()
         .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")?
         .progress_chars("#>-"));
@@ -308,11 +968,13 @@ fn extract_pdf(input: &Path, output_dir: &Path, dpi: u16, limit: Option<usize>)
     // Given file I/O overhead of opening is small vs rendering, we open per page or use thread local?
     // Let's just open inside the closure. It's robust.
     
-    (0..num_pages).into_par_iter().for_each(|page_num| {
-        let filename = format!("page_{:04}.png", page_num + 1);
+    page_nums.into_par_iter().for_each(|page_num| {
+        let filename = format!("page_{:04}.png", page_num);
         let output_path = output_dir.join(&filename);
+        let text_path = output_path.with_extension("txt");
+        let force = selection.force;
 
-        if output_path.exists() {
+        if !force && output_path.exists() && text_path.exists() {
              pb.inc(1);
              return;
         }
@@ -324,15 +986,25 @@ fn extract_pdf(input: &Path, output_dir: &Path, dpi: u16, limit: Option<usize>)
         // User probably wants to know if it failed.
         let process = || -> Result<()> {
             let doc = mupdf::Document::open(input.to_str().unwrap())?;
-            let page = doc.load_page(page_num as i32)?;
-            let matrix = Matrix::new_scale(scale, scale);
-            let pixmap = page.to_pixmap(&matrix, &Colorspace::device_rgb(), false, true)?;
-            pixmap.save_as(&output_path.to_string_lossy(), mupdf::ImageFormat::PNG)?;
+            let page = doc.load_page(page_num as i32 - 1)?;
+            if force || !output_path.exists() {
+                let matrix = Matrix::new_scale(scale, scale);
+                let pixmap = page.to_pixmap(&matrix, &Colorspace::device_rgb(), false, true)?;
+                pixmap.save_as(&output_path.to_string_lossy(), mupdf::ImageFormat::PNG)?;
+            }
+            if force || !text_path.exists() {
+                // Written even when empty so we know the page has been checked
+                let (text, fonts) = page_text(&page)?;
+                std::fs::write(&text_path, text)?;
+                if !fonts.lines.is_empty() {
+                    std::fs::write(output_path.with_extension("fonts.json"), serde_json::to_string(&fonts)?)?;
+                }
+            }
             Ok(())
         };
 
         if let Err(e) = process() {
-            eprintln!("Error processing page {}: {}", page_num + 1, e);
+            eprintln!("Error processing page {}: {}", page_num, e);
         }
         
         pb.inc(1);
@@ -342,20 +1014,41 @@ fn extract_pdf(input: &Path, output_dir: &Path, dpi: u16, limit: Option<usize>)
     Ok(())
 }
 
+/// Flatten mupdf's outline tree into reading order, dropping entries that point outside the document
+fn flatten_outline(items: &[mupdf::Outline], level: usize, out: &mut Vec<OutlineEntry>) {
+    for item in items {
+        if let Some(page) = item.page {
+            out.push(OutlineEntry { title: item.title.trim().to_string(), level, page: page as usize + 1 });
+        }
+        flatten_outline(&item.down, level + 1, out);
+    }
+}
+
 async fn transcribe_images(
     input_dir: PathBuf,
     output_dir: PathBuf,
     concurrency: usize,
-    model: String,
-    api_key: String,
-    limit: Option<usize>,
-) -> Result<()> {
+    models: ModelChain,
+    provider: Arc<dyn Provider>,
+    selection: PageSelection,
+    options: TranscribeOptions,
+) -> Result<Usage> {
     if !output_dir.exists() {
         fs::create_dir_all(&output_dir).await?;
     }
 
-    let client = Client::new();
     let semaphore = Arc::new(Semaphore::new(concurrency));
+    // --concurrency counts requests, and a consensus page makes several at once
+    let permits_per_page = (options.consensus as usize).clamp(1, concurrency.max(1)) as u32;
+    let budget = Arc::new(Budget::new(&options));
+    // Cost is only known for providers that report it; refuse to run blind
+    let reports_cost = matches!(options.provider, ProviderKind::OpenRouter | ProviderKind::Ollama);
+    if options.max_cost.is_some() && !reports_cost {
+        return Err(anyhow::anyhow!(
+            "--max-cost needs a provider that reports cost (openrouter, ollama); use --max-tokens with {}",
+            options.provider.name()
+        ));
+    }
 
     let mut paths = Vec::new();
     for entry in WalkDir::new(&input_dir).sort_by_file_name() {
@@ -364,15 +1057,20 @@ async fn transcribe_images(
         if path.is_file() {
             if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
                 if name.ends_with(".png") && !name.starts_with("._") {
-                    paths.push(path.to_path_buf());
+                    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+                    let page_number = page_number_from_stem(stem).unwrap_or(paths.len() + 1);
+                    paths.push((page_number, path.to_path_buf()));
                 }
             }
         }
     }
 
-    if let Some(l) = limit {
-        paths.truncate(l);
-    }
+    // Total is taken before --pages/--limit so {total_pages} reflects the whole book
+    let total_pages = paths.len();
+    let prompt_template = Arc::new(options.prompt_template()?);
+    let book_title = Arc::new(options.book_title.clone().unwrap_or_else(|| default_book_title(&input_dir)));
+
+    let paths = selection.apply(paths);
 
     println!("Found {} images to transcribe", paths.len());
     let m = MultiProgress::new();
@@ -382,91 +1080,141 @@ async fn transcribe_images(
         .progress_chars("#>-"));
 
     let mut tasks = Vec::new();
-
-    for path in paths {
-        let client = client.clone();
-        let api_key = api_key.clone();
+    let page_count = paths.len();
+    let mut budget_stop = None;
+    let (_graceful, mut interrupt_rx) = GracefulInterrupts::enter();
+
+    for (page_number, path) in paths {
+        let provider = provider.clone();
+        let prompt_template = prompt_template.clone();
+        let book_title = book_title.clone();
+        let options = options.clone();
         let output_dir = output_dir.clone();
-        let model = model.clone();
-        let permit = semaphore.clone().acquire_owned().await?;
+        let models = models.clone();
+
+        let file_stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("unknown").to_string();
+        let final_output = output_dir.join(format!("{}.md", file_stem));
+        // In structured mode a page is only done once it has its JSON as well
+        let structured_output = output_dir.join(format!("{}.json", file_stem));
+        let done = final_output.exists() && (!options.structured || structured_output.exists());
+        let skip = done && !selection.force;
+        // Text layer written next to the image by extract_pdf, if any. Besides --text-layer
+        // it is what validation measures the response against.
+        let text_layer = if skip { None } else { fs::read_to_string(path.with_extension("txt")).await.ok() };
+        let from_text_layer = matches!(options.text_layer, TextLayerMode::Skip | TextLayerMode::Auto)
+            && text_layer.as_deref().is_some_and(text_layer_usable);
+
+        // Until a page has been transcribed there is no per-page cost to project the budget
+        // from, so pages that make requests take every permit and run one at a time
+        let sampling = !skip && !from_text_layer && budget.unsampled();
+        let permits = if sampling { concurrency.max(1) as u32 } else { permits_per_page };
+        let permit = tokio::select! {
+            permit = semaphore.clone().acquire_many_owned(permits) => permit?,
+            _ = interrupt_rx.wait_for(|n| *n >= 1) => break,
+        };
+        if *interrupt_rx.borrow() >= 1 {
+            break;
+        }
         let pb = pb.clone();
+        let budget = budget.clone();
+
+        // Waiting for the permit lets in-flight pages report their usage first. The projection
+        // counts the pages still running plus this one, which is about to start spending too.
+        let running = (concurrency - semaphore.available_permits() - permits as usize) / permits_per_page as usize;
+        if let Some(reason) = budget.exhausted(running + 1) {
+            pb.println(format!("{}; waiting for in-flight pages to finish", reason));
+            budget_stop = Some(reason);
+            break;
+        }
 
         tasks.push(tokio::spawn(async move {
             let _permit = permit;
-            let file_stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("unknown");
-            let output_filename = format!("{}.md", file_stem);
-            let final_output = output_dir.join(&output_filename);
-
-            if final_output.exists() {
+            let file_stem = file_stem.as_str();
+            if skip {
                 pb.inc(1);
-                return Ok(());
+                return Ok(PageOutcome { page: file_stem.to_string(), attempts: 0, reasks: 0, model: None, from_text_layer: false, usage: Usage::default() });
             }
 
             pb.set_message(format!("Proc: {}", file_stem));
 
             // Atomic write prep
             let mut tmp_file = NamedTempFile::new_in(&output_dir)?;
+
+            if let Some(layer) = text_layer.as_deref().filter(|_| from_text_layer) {
+                tmp_file.write_all(layer.as_bytes())?;
+                tmp_file.persist(&final_output)?;
+                if options.structured {
+                    PageBlocks::from_plain_text(layer).write(&output_dir, file_stem)?;
+                }
+                let meta = PageMeta {
+                    page: page_number,
+                    provider: None,
+                    model: None,
+                    escalated_from: Vec::new(),
+                    attempts: 0,
+                    reasks: 0,
+                    usage: Usage::default(),
+                    confidence: None,
+                };
+                meta.write(&output_dir, file_stem)?;
+                pb.inc(1);
+                return Ok(PageOutcome { page: file_stem.to_string(), attempts: 0, reasks: 0, model: None, from_text_layer: true, usage: Usage::default() });
+            }
             
             // Process
             let image_data = fs::read(&path).await?;
             let b64_data = general_purpose::STANDARD.encode(&image_data);
 
-            // OpenRouter uses OpenAI-compatible format with data URLs for images
-            let request_body = ChatCompletionRequest {
-                model: model.clone(),
-                messages: vec![Message {
-                    role: "user".to_string(),
-                    content: vec![
-                        ContentPart::Text {
-                            text: "Transcribe this page from Inside Macintosh. Output strictly formatted Markdown. Use headers, lists, and code blocks where appropriate. IMPORTANT: Transcribe ALL legible text, including page numbers, headers, footers, and captions. Do NOT wrap the entire output in a markdown block.".to_string(),
-                        },
-                        ContentPart::ImageUrl {
-                            image_url: ImageUrlData {
-                                url: format!("data:image/png;base64,{}", b64_data),
-                            },
-                        },
-                    ],
-                }],
+            let mut prompt = render_prompt(&prompt_template, &book_title, page_number, total_pages);
+            if matches!(options.text_layer, TextLayerMode::Hint | TextLayerMode::Auto) {
+                if let Some(layer) = text_layer.as_deref().filter(|l| !l.trim().is_empty()) {
+                    prompt.push_str(&text_layer_hint(layer));
+                }
+            }
+            if options.structured {
+                prompt.push_str(&structured::structured_instructions());
+            }
+
+            let page_request = PageRequest {
+                model: models.primary().to_string(),
+                prompt,
+                image_base64: b64_data,
+                mime_type: "image/png".to_string(),
+                response_schema: options.structured.then(structured::page_schema),
+                logprobs: options.logprobs,
             };
+            let check_request = options.self_check.then(|| page_request.clone());
 
-            let url = "https://openrouter.ai/api/v1/chat/completions";
-            
-            let resp = client
-                .post(url)
-                .header("Authorization", format!("Bearer {}", api_key))
-                .header("Content-Type", "application/json")
-                .json(&request_body)
-                .timeout(Duration::from_secs(120))
-                .send()
-                .await?;
-
-            if !resp.status().is_success() {
-                let txt = resp.text().await?;
-                return Err(anyhow::anyhow!("API Error: {}", txt));
-            }
-
-            let result: ChatCompletionResponse = resp.json().await?;
-            if let Some(err) = result.error {
-                let type_str = err.error_type.as_deref().unwrap_or("unknown");
-                return Err(anyhow::anyhow!("API Error ({}): {}", type_str, err.message));
-            }
-
-            let mut text = result.choices
-                .and_then(|c| c.into_iter().next())
-                .and_then(|c| c.message)
-                .and_then(|m| m.content)
-                .ok_or_else(|| anyhow::anyhow!("No content in response"))?;
-
-            // Clean up code blocks if the model wrapped the output
-            if text.trim_start().starts_with("```") {
-                // Find first newline
-                if let Some(newline_pos) = text.find('\n') {
-                    text = text[newline_pos + 1..].to_string();
-                }
-                // Strip trailing fence
-                if let Some(last_fence) = text.rfind("```") {
-                    text = text[..last_fence].trim_end().to_string();
+            let expected = text_layer.as_deref().filter(|l| text_layer_usable(l));
+            let response = if options.consensus > 1 {
+                transcribe_consensus(&*provider, page_request, &models, expected, &options, &budget, &pb, &output_dir, file_stem)
+                    .await?
+            } else {
+                request_page(&*provider, page_request, &models, expected, &options, &budget, &pb, file_stem).await?
+            };
+            let PageResponse { text, blocks, model, escalated_from, attempts, reasks, mut usage, logprob, agreement } =
+                response;
+
+            let self_check = match check_request {
+                Some(request) => {
+                    let (rating, check_usage) =
+                        run_self_check(&*provider, request, &text, &model, &options, &budget, &pb, file_stem).await;
+                    usage += check_usage;
+                    rating
                 }
+                None => None,
+            };
+            let confidence = Confidence {
+                text_layer: expected.map(|layer| confidence::text_agreement(&text, layer)),
+                consensus: agreement,
+                token_probability: logprob.map(f64::exp),
+                self_check,
+                ..Default::default()
+            }
+            .scored();
+
+            if let Some(blocks) = &blocks {
+                blocks.write(&output_dir, file_stem)?;
             }
 
             // Write to temp
@@ -476,24 +1224,93 @@ async fn transcribe_images(
             // Atomic rename
             tmp_file.persist(&final_output)?;
 
+            let meta = PageMeta {
+                page: page_number,
+                provider: Some(options.provider.name().to_string()),
+                model: Some(model.clone()),
+                escalated_from,
+                attempts,
+                reasks,
+                usage,
+                confidence,
+            };
+            meta.write(&output_dir, file_stem)?;
+            budget.page_done();
+
             pb.inc(1);
             pb.set_message("Done");
-            Ok::<(), anyhow::Error>(())
+            Ok::<PageOutcome, anyhow::Error>(PageOutcome {
+                page: file_stem.to_string(),
+                attempts,
+                reasks,
+                model: Some(model),
+                from_text_layer: false,
+                usage,
+            })
         }));
     }
 
-    let results = futures::future::join_all(tasks).await;
+    let not_started = page_count - tasks.len();
+    if *interrupt_rx.borrow() >= 1 {
+        pb.println("Interrupted: no new pages will be started, waiting for in-flight pages (Ctrl-C again to abort)");
+    }
+
+    // Wait for in-flight pages. After a first Ctrl-C they get --drain-timeout seconds,
+    // a second Ctrl-C aborts them straight away.
+    let abort_handles: Vec<_> = tasks.iter().map(|t| t.abort_handle()).collect();
+    let all_tasks = futures::future::join_all(tasks);
+    tokio::pin!(all_tasks);
+    let drain_timeout = Duration::from_secs(options.drain_timeout);
+    let mut abort_rx = interrupt_rx.clone();
+    let results = tokio::select! {
+        results = &mut all_tasks => results,
+        _ = async {
+            let _ = abort_rx.wait_for(|n| *n >= 1).await;
+            tokio::select! {
+                _ = abort_rx.wait_for(|n| *n >= 2) => pb.println("Second interrupt: aborting in-flight pages"),
+                _ = tokio::time::sleep(drain_timeout) => pb.println("Drain timeout reached: aborting in-flight pages"),
+            }
+        } => {
+            for handle in &abort_handles {
+                handle.abort();
+            }
+            all_tasks.await
+        }
+    };
     pb.finish_with_message("Transcription complete");
     
     // Check for errors
     let mut error_count = 0;
+    let mut transcribed = 0;
+    let mut skipped = 0;
+    let mut from_text_layer = 0;
+    let mut retried = Vec::new();
+    let mut by_model: std::collections::BTreeMap<String, usize> = std::collections::BTreeMap::new();
+    let mut run_usage = Usage::default();
+    let mut aborted = 0;
     for result in results {
         match result {
-            Ok(Ok(())) => {}
+            Ok(Ok(outcome)) => {
+                if outcome.from_text_layer {
+                    from_text_layer += 1;
+                } else if outcome.attempts == 0 {
+                    skipped += 1;
+                } else {
+                    transcribed += 1;
+                    run_usage += outcome.usage;
+                    if let Some(model) = &outcome.model {
+                        *by_model.entry(model.clone()).or_default() += 1;
+                    }
+                    if outcome.attempts > 1 || outcome.reasks > 0 {
+                        retried.push(outcome);
+                    }
+                }
+            }
             Ok(Err(e)) => {
                 eprintln!("Task error: {}", e);
                 error_count += 1;
             }
+            Err(e) if e.is_cancelled() => aborted += 1,
             Err(e) => {
                 eprintln!("Join error: {}", e);
                 error_count += 1;
@@ -501,22 +1318,464 @@ async fn transcribe_images(
         }
     }
     
+    println!(
+        "Summary: {} transcribed, {} from text layer, {} already done, {} failed",
+        transcribed, from_text_layer, skipped, error_count
+    );
+    if !retried.is_empty() {
+        println!("Pages that needed retries:");
+        for outcome in &retried {
+            println!("  {}: {} attempts, {} re-ask(s)", outcome.page, outcome.attempts, outcome.reasks);
+        }
+    }
+    if models.len() > 1 && !by_model.is_empty() {
+        println!("Pages by model:");
+        for model in models.iter().filter(|m| by_model.contains_key(*m)) {
+            println!("  {}: {}", model, by_model[model]);
+        }
+    }
+    println!("Usage this run: {}", run_usage);
+    println!("Usage for book (all runs): {}", book_usage(&output_dir)?);
+
     if error_count > 0 {
         eprintln!("{} tasks failed", error_count);
     }
+
+    if *interrupt_rx.borrow() >= 1 {
+        return Err(Interrupted { spent: run_usage, aborted, not_started }.into());
+    }
+    if let Some(reason) = budget_stop {
+        return Err(BudgetExceeded { reason, spent: run_usage, not_started }.into());
+    }
     
+    Ok(run_usage)
+}
+
+/// A page response that passed validation, and what it took to get it
+struct PageResponse {
+    text: String,
+    blocks: Option<PageBlocks>,
+    /// Model that produced the response
+    model: String,
+    /// Models earlier in the chain that failed on the page
+    escalated_from: Vec<String>,
+    attempts: u32,
+    reasks: u32,
+    usage: Usage,
+    /// Mean token log probability, when the provider reported it
+    logprob: Option<f64>,
+    /// Share of lines the --consensus candidates agreed on
+    agreement: Option<f64>,
+}
+
+/// Request a page until a response passes validation, starting with the first model in `chain`.
+/// A rejected response is re-asked with instructions aimed at the problem, up to --reasks times
+/// per model. Errors, content filter blocks and responses that keep failing move the page on to
+/// the next model. Every response's usage is spent from the budget as it arrives, since
+/// rejected responses are paid for too.
+#[allow(clippy::too_many_arguments)]
+async fn request_page(
+    provider: &dyn Provider,
+    mut page_request: PageRequest,
+    chain: &[String],
+    expected: Option<&str>,
+    options: &TranscribeOptions,
+    budget: &Budget,
+    pb: &ProgressBar,
+    label: &str,
+) -> Result<PageResponse> {
+    let base_prompt = page_request.prompt.clone();
+    page_request.model = chain[0].clone();
+    let mut attempts = 0;
+    let mut reasks = 0;
+    let mut model_reasks = 0;
+    let mut model_index = 0;
+    let mut escalated_from = Vec::new();
+    let mut usage = Usage::default();
+    loop {
+        let (result, tries) = with_retries(options, pb, label, || provider.transcribe(&page_request)).await;
+        attempts += tries;
+        let (failure, problem) = match result {
+            Ok(transcription) => {
+                budget.spend(transcription.usage);
+                usage += transcription.usage;
+                let mut text = transcription.text;
+                let mut blocks = None;
+                let mut problem = None;
+
+                if options.structured {
+                    // The Markdown file is rendered from the blocks so the two never disagree
+                    match PageBlocks::parse(&text) {
+                        Ok(parsed) => {
+                            text = parsed.to_markdown();
+                            blocks = Some(parsed);
+                        }
+                        Err(e) => problem = Some(Problem::InvalidStructure(format!("{:#}", e))),
+                    }
+                } else if text.trim_start().starts_with("```") {
+                    // Clean up code blocks if the model wrapped the output
+                    // Find first newline
+                    if let Some(newline_pos) = text.find('\n') {
+                        text = text[newline_pos + 1..].to_string();
+                    }
+                    // Strip trailing fence
+                    if let Some(last_fence) = text.rfind("```") {
+                        text = text[..last_fence].trim_end().to_string();
+                    }
+                }
+
+                let problem = problem
+                    .or_else(|| validate::check(&text, transcription.finish_reason, expected, &options.validation));
+                match problem {
+                    None => {
+                        return Ok(PageResponse {
+                            text,
+                            blocks,
+                            model: page_request.model,
+                            escalated_from,
+                            attempts,
+                            reasks,
+                            usage,
+                            logprob: transcription.logprob,
+                            agreement: None,
+                        });
+                    }
+                    Some(problem) => (problem.to_string(), Some(problem)),
+                }
+            }
+            Err(e) => (format!("request failed after {} attempt(s): {}", tries, e), None),
+        };
+
+        // A content filter will most likely block the same model again
+        let reask = problem.as_ref().is_some_and(|p| !matches!(p, Problem::Filtered))
+            && model_reasks < options.validation.reasks;
+        if reask {
+            model_reasks += 1;
+            reasks += 1;
+            pb.println(format!(
+                "{}: {}, re-asking {} ({}/{})",
+                label, failure, page_request.model, model_reasks, options.validation.reasks
+            ));
+        } else if model_index + 1 < chain.len() {
+            model_index += 1;
+            model_reasks = 0;
+            pb.println(format!("{}: {} on {}, trying {}", label, failure, page_request.model, chain[model_index]));
+            escalated_from.push(std::mem::replace(&mut page_request.model, chain[model_index].clone()));
+        } else {
+            return Err(anyhow::anyhow!(
+                "{} failed on {} ({} attempt(s), {} re-ask(s)): {}",
+                label, page_request.model, attempts, reasks, failure
+            ));
+        }
+        page_request.prompt = match &problem {
+            Some(problem) => format!("{}{}", base_prompt, problem.stricter_instructions()),
+            None => base_prompt.clone(),
+        };
+    }
+}
+
+/// Transcribe a page --consensus times and merge the candidates by majority vote. Candidate i
+/// starts at model i of the chain (wrapping around) and falls back through the models after it,
+/// so a list of models gives one candidate per model and a single model gives repeated samples.
+/// Raw candidates are saved to `candidates/` and the disagreements to `page_NNNN.consensus.json`.
+#[allow(clippy::too_many_arguments)]
+async fn transcribe_consensus(
+    provider: &dyn Provider,
+    page_request: PageRequest,
+    chain: &[String],
+    expected: Option<&str>,
+    options: &TranscribeOptions,
+    budget: &Budget,
+    pb: &ProgressBar,
+    output_dir: &Path,
+    file_stem: &str,
+) -> Result<PageResponse> {
+    let count = options.consensus as usize;
+    let labels: Vec<String> = (1..=count).map(|i| format!("{}#{}", file_stem, i)).collect();
+    let requests = labels.iter().enumerate().map(|(i, label)| {
+        let chain = &chain[i % chain.len()..];
+        request_page(provider, page_request.clone(), chain, expected, options, budget, pb, label)
+    });
+
+    let mut candidates = Vec::new();
+    for result in futures::future::join_all(requests).await {
+        match result {
+            Ok(response) => candidates.push(response),
+            Err(e) => pb.println(format!("{:#}", e)),
+        }
+    }
+    if candidates.len() < 2 {
+        anyhow::bail!(
+            "{}: consensus needs at least 2 transcriptions, {} of {} succeeded",
+            file_stem, candidates.len(), count
+        );
+    }
+
+    fs::create_dir_all(output_dir.join("candidates")).await?;
+    let mut infos = Vec::new();
+    for (i, candidate) in candidates.iter().enumerate() {
+        let file = format!("candidates/{}.{}.md", file_stem, i + 1);
+        fs::write(output_dir.join(&file), &candidate.text).await?;
+        infos.push(CandidateInfo { model: candidate.model.clone(), file });
+    }
+
+    let texts: Vec<&str> = candidates.iter().map(|c| c.text.as_str()).collect();
+    let merged = consensus::merge(&texts);
+    let reference = merged.reference;
+    let report = ConsensusReport {
+        candidates: infos,
+        reference,
+        agreement: merged.agreement,
+        disagreements: merged.disagreements,
+    };
+    report.write(output_dir, file_stem)?;
+
+    let logprobs: Vec<f64> = candidates.iter().filter_map(|c| c.logprob).collect();
+    let mut response = PageResponse {
+        text: merged.text,
+        blocks: None,
+        model: candidates[reference].model.clone(),
+        escalated_from: Vec::new(),
+        attempts: 0,
+        reasks: 0,
+        usage: Usage::default(),
+        logprob: (!logprobs.is_empty()).then(|| logprobs.iter().sum::<f64>() / logprobs.len() as f64),
+        agreement: Some(merged.agreement),
+    };
+    for candidate in candidates {
+        response.escalated_from.extend(candidate.escalated_from);
+        response.attempts += candidate.attempts;
+        response.reasks += candidate.reasks;
+        response.usage += candidate.usage;
+    }
+    Ok(response)
+}
+
+/// Ask a model to rate a finished page against its image (--self-check). Returns the rating,
+/// or None when the request failed or the reply held no rating, with the usage it cost, which
+/// is spent from the budget like any other request.
+#[allow(clippy::too_many_arguments)]
+async fn run_self_check(
+    provider: &dyn Provider,
+    mut request: PageRequest,
+    text: &str,
+    model: &str,
+    options: &TranscribeOptions,
+    budget: &Budget,
+    pb: &ProgressBar,
+    label: &str,
+) -> (Option<f64>, Usage) {
+    request.model = options.self_check_model.clone().unwrap_or_else(|| model.to_string());
+    request.prompt = confidence::self_check_prompt(text);
+    request.response_schema = None;
+    request.logprobs = false;
+    let (result, _) = with_retries(options, pb, label, || provider.transcribe(&request)).await;
+    match result {
+        Ok(reply) => {
+            budget.spend(reply.usage);
+            let rating = confidence::parse_self_check(&reply.text);
+            if rating.is_none() {
+                pb.println(format!("{}: no rating in self-check reply: {}", label, reply.text.trim()));
+            }
+            (rating, reply.usage)
+        }
+        Err(e) => {
+            pb.println(format!("{}: self-check failed: {}", label, e));
+            (None, Usage::default())
+        }
+    }
+}
+
+/// Result of a single page task, used for the end-of-run summary
+#[derive(Debug)]
+struct PageOutcome {
+    page: String,
+    /// Number of API requests made (0 when the page was already transcribed)
+    attempts: u32,
+    /// Times the page was re-requested because a response failed validation
+    reasks: u32,
+    /// Model that produced the page, when one was called
+    model: Option<String>,
+    /// Output was taken from the PDF text layer without calling the model
+    from_text_layer: bool,
+    usage: Usage,
+}
+
+/// Per-page sidecar (`page_NNNN.meta.json`) recording how the page was produced
+#[derive(Serialize, Deserialize, Debug)]
+struct PageMeta {
+    page: usize,
+    /// Provider and model that produced the page; absent when taken from the text layer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    /// Models in the --model chain that failed on this page before `model` succeeded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    escalated_from: Vec<String>,
+    attempts: u32,
+    /// Responses rejected by validation before the saved one
+    #[serde(default)]
+    reasks: u32,
+    usage: Usage,
+    /// Absent for pages taken from the text layer or transcribed without any signal to score by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    confidence: Option<Confidence>,
+}
+
+impl PageMeta {
+    fn write(&self, output_dir: &Path, file_stem: &str) -> Result<()> {
+        use std::io::Write;
+        let mut tmp_file = NamedTempFile::new_in(output_dir)?;
+        tmp_file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        tmp_file.persist(output_dir.join(format!("{}.meta.json", file_stem)))?;
+        Ok(())
+    }
+}
+
+/// Every page sidecar of a markdown directory, with the page's file stem, in page order
+fn page_metas(markdown_dir: &Path) -> Result<Vec<(String, PageMeta)>> {
+    let mut metas = Vec::new();
+    for entry in WalkDir::new(markdown_dir).max_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let name = entry.file_name().to_str().unwrap_or_default();
+        let stem = name.strip_suffix(".meta.json").filter(|s| s.starts_with("page_"));
+        if let (true, Some(stem)) = (entry.file_type().is_file(), stem) {
+            let meta: PageMeta = serde_json::from_str(&std::fs::read_to_string(entry.path())?)
+                .with_context(|| format!("Invalid sidecar {:?}", entry.path()))?;
+            metas.push((stem.to_string(), meta));
+        }
+    }
+    Ok(metas)
+}
+
+/// Sum the usage recorded in every page sidecar of a markdown directory
+fn book_usage(markdown_dir: &Path) -> Result<Usage> {
+    let mut total = Usage::default();
+    for (_, meta) in page_metas(markdown_dir)? {
+        total += meta.usage;
+    }
+    Ok(total)
+}
+
+/// Print the lowest-scoring pages of a markdown directory, worst first
+fn review_queue(markdown_dir: &Path, limit: usize, below: Option<f64>) -> Result<()> {
+    let metas = page_metas(markdown_dir)?;
+    let unscored = metas.iter().filter(|(_, m)| m.confidence.is_none()).count();
+    let mut scored: Vec<(&str, Confidence)> = metas
+        .iter()
+        .filter_map(|(stem, m)| m.confidence.map(|c| (stem.as_str(), c)))
+        .filter(|(_, c)| below.is_none_or(|b| c.score < b))
+        .collect();
+    scored.sort_by(|a, b| a.1.score.total_cmp(&b.1.score).then(a.0.cmp(&b.0)));
+
+    if scored.is_empty() {
+        println!("No scored pages to review in {:?}", markdown_dir);
+    } else {
+        println!("{:<12} {:>5}  {:<18} File", "Page", "Score", "Weakest signal");
+        for (stem, c) in scored.iter().take(limit) {
+            let file = markdown_dir.join(format!("{}.md", stem));
+            println!("{:<12} {:>5.2}  {:<18} {}", stem, c.score, c.weakest(), file.display());
+        }
+    }
+    println!(
+        "{} of {} page(s) listed; {} page(s) have no score (taken from the text layer, or no scoring signal was available)",
+        scored.len().min(limit),
+        metas.len(),
+        unscored
+    );
     Ok(())
 }
 
+/// Whether a pipeline input is a directory of several books rather than a single one
+fn is_batch(input: &Path, inputs: &[PathBuf]) -> bool {
+    input.is_dir() && inputs != [input.to_path_buf()]
+}
+
+/// Output directory for one book of a pipeline run
+fn book_output_base(batch: bool, output: &Option<PathBuf>, book_name: &str) -> PathBuf {
+    if batch {
+        // If input was a directory, output arg is the parent dir for all books
+        match output {
+            Some(p) => p.join(book_name),
+            None => PathBuf::from("out").join(book_name) // Default structure
+        }
+    } else {
+        // Single file mode: match existing behavior
+        match output {
+            Some(p) => p.clone(),
+            None => PathBuf::from("out").join(book_name)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_estimate(
+    input: &Path,
+    output: &Option<PathBuf>,
+    dpi: u16,
+    concurrency: usize,
+    model: Option<ModelChain>,
+    pages: &PageSelection,
+    options: &TranscribeOptions,
+    estimate: &EstimateOptions,
+) -> Result<()> {
+    if estimate.refresh_pricing {
+        estimate::refresh_pricing_cache().await?;
+    }
+    // Pages only reach later models in the chain when the first fails, so price the first
+    let model = model.map(|m| m.primary().to_string()).unwrap_or_default();
+    let price = if model.is_empty() {
+        None
+    } else {
+        estimate::load_price(estimate.pricing.as_deref(), &model)?
+    };
+
+    let mut total = estimate::BookEstimate::default();
+    let inputs = input::find_input_documents(input)?;
+    let batch = is_batch(input, &inputs);
+    for pdf_path in inputs {
+        let book_name = pdf_path.file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("unknown_book");
+        let output_base = book_output_base(batch, output, book_name);
+        let book = estimate::estimate_book(&pdf_path, &output_base, dpi, pages, options, &model, estimate)
+            .with_context(|| format!("Failed to estimate {}", book_name))?;
+        let cost = price.as_ref().map(|p| format!(", ~${:.2}", book.cost(p))).unwrap_or_default();
+        println!(
+            "{}: {} pages selected, {} to transcribe (~{} prompt + ~{} completion tokens{})",
+            book_name, book.selected_pages, book.to_transcribe, book.prompt_tokens, book.completion_tokens, cost
+        );
+        total.add(&book);
+    }
+
+    estimate::print_summary(&total, &model, price.as_ref(), concurrency, estimate);
+    Ok(())
+}
+
+/// Guess a human-readable title from an images directory (`out/my_book/images` -> "my book")
+fn default_book_title(images_dir: &Path) -> String {
+    let dir = if images_dir.ends_with("images") {
+        images_dir.parent().unwrap_or(images_dir)
+    } else {
+        images_dir
+    };
+    dir.file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("unknown_book")
+        .replace('_', " ")
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Load .env file (ignore if not present)
     let _ = dotenvy::dotenv();
     
     let args = Args::parse();
+    install_interrupt_handler();
     
     match args.command {
-        Commands::Extract { input, output, dpi, limit } => {
+        Commands::Extract { input, output, dpi, pages } => {
             let output = match output {
                 Some(p) => p,
                 None => {
@@ -526,10 +1785,10 @@ async fn main() -> Result<()> {
                     PathBuf::from("out").join(book_name).join("images")
                 }
             };
-            extract_pdf(&input, &output, dpi, limit)?;
+            extract_input(&input, &output, dpi, &pages)?;
         }
-        Commands::Transcribe { input, output, concurrency, model, limit } => {
-            let api_key = env::var("OPENROUTER_API_KEY").context("OPENROUTER_API_KEY must be set")?;
+        Commands::Transcribe { input, output, concurrency, model, pages, options } => {
+            let provider: Arc<dyn Provider> = options.provider.build(options.base_url.clone())?.into();
             let model = model.context("Model must be specified via --model or OPENROUTER_MODEL env var")?;
             
             let output = match output {
@@ -548,9 +1807,19 @@ async fn main() -> Result<()> {
                 }
             };
             
-            transcribe_images(input, output, concurrency, model, api_key, limit).await?;
+            if let Err(e) = transcribe_images(input, output, concurrency, model, provider, pages, options).await {
+                if let Some(exceeded) = e.downcast_ref::<BudgetExceeded>() {
+                    eprintln!("{}", exceeded);
+                    std::process::exit(EXIT_BUDGET_EXCEEDED);
+                }
+                if let Some(interrupted) = e.downcast_ref::<Interrupted>() {
+                    eprintln!("{}", interrupted);
+                    std::process::exit(EXIT_INTERRUPTED);
+                }
+                return Err(e);
+            }
         }
-        Commands::Combine { input, output } => {
+        Commands::Combine { input, output, options } => {
              let output = match output {
                 Some(p) => p,
                 None => {
@@ -558,35 +1827,49 @@ async fn main() -> Result<()> {
                      // Input: out/book/markdown -> parent is out/book -> join book.md
                      let parent = input.parent().unwrap_or(&input);
                      let book_name = parent.file_name().unwrap_or_default();
-                     parent.join(format!("{}.md", book_name.to_string_lossy()))
-                }
-            };
-            combine_book(&input, &output)?;
-        }
-        Commands::Pipeline { input, output, dpi, concurrency, model, limit } => {
-            let inputs: Vec<PathBuf> = if input.is_dir() {
-                let mut pdfs = Vec::new();
-                let mut entries = fs::read_dir(&input).await?;
-                while let Some(entry) = entries.next_entry().await? {
-                    let path = entry.path();
-                    if path.is_file() {
-                        if let Some(ext) = path.extension() {
-                            if ext.eq_ignore_ascii_case("pdf") {
-                                pdfs.push(path);
-                            }
-                        }
-                    }
-                }
-                pdfs.sort();
-                if pdfs.is_empty() {
-                    println!("No PDF files found in directory: {:?}", input);
-                } else {
-                    println!("Found {} PDF files in directory: {:?}", pdfs.len(), input);
+                     parent.join(options.format.output_name(&book_name.to_string_lossy()))
                 }
-                pdfs
-            } else {
-                vec![input.clone()]
             };
+            combine_book(&input, &output, &options)?;
+        }
+        Commands::Overlay { input, markdown, output, force, font } => {
+            let book_name = input.file_stem().unwrap_or_default().to_string_lossy().to_string();
+            let markdown = markdown.unwrap_or_else(|| PathBuf::from("out").join(&book_name).join("markdown"));
+            let output = output.unwrap_or_else(|| {
+                let parent = markdown.parent().unwrap_or(Path::new("."));
+                parent.join(format!("{}.searchable.pdf", book_name))
+            });
+            println!("Adding text layer from {:?} to {:?}", markdown, input);
+            let stats = overlay::overlay_pdf(&input, &markdown, &output, force, font.as_deref())?;
+            println!(
+                "Created searchable PDF: {:?} ({} pages with added text, {} already had a text layer, {} not transcribed)",
+                output, stats.overlaid, stats.had_text, stats.missing
+            );
+            if stats.unmappable_pages > 0 {
+                println!(
+                    "Warning: {} characters on {} pages have no glyph in the text layer font and won't search or copy; \
+                     pass --font with a font that covers them",
+                    stats.unmappable_chars, stats.unmappable_pages
+                );
+            }
+        }
+        Commands::ReviewQueue { input, limit, below } => {
+            review_queue(&input, limit, below)?;
+        }
+        Commands::Estimate { input, output, dpi, concurrency, model, pages, options, estimate } => {
+            run_estimate(&input, &output, dpi, concurrency, model, &pages, &options, &estimate).await?;
+        }
+        Commands::Pipeline { input, output, dpi, concurrency, model, pages, options, combine, dry_run, estimate } => {
+            if dry_run {
+                return run_estimate(&input, &output, dpi, concurrency, model, &pages, &options, &estimate).await;
+            }
+
+            let inputs = input::find_input_documents(&input)?;
+            let batch = is_batch(&input, &inputs);
+
+            let mut run_usage = Usage::default();
+            let mut book_usages = Vec::new();
+            let mut stopped = None;
 
             for (i, pdf_path) in inputs.iter().enumerate() {
                 let book_name = pdf_path.file_stem()
@@ -595,19 +1878,7 @@ async fn main() -> Result<()> {
 
                 println!("\n=== Processing Book {}/{}: {} ===\n", i + 1, inputs.len(), book_name);
 
-                let output_base = if input.is_dir() {
-                    // If input was a directory, output arg is the parent dir for all books
-                    match &output {
-                        Some(p) => p.join(book_name),
-                        None => PathBuf::from("out").join(book_name) // Default structure
-                    }
-                } else {
-                    // Single file mode: match existing behavior
-                    match &output {
-                        Some(p) => p.clone(),
-                        None => PathBuf::from("out").join(book_name)
-                    }
-                };
+                let output_base = book_output_base(batch, &output, book_name);
 
                 let images_dir = output_base.join("images");
                 let markdown_dir = output_base.join("markdown");
@@ -615,23 +1886,45 @@ async fn main() -> Result<()> {
                 println!("--- Phase 1: Extract ---");
                 println!("Output directory: {:?}", output_base);
                 
-                if let Err(e) = extract_pdf(pdf_path, &images_dir, dpi, limit) {
+                if let Err(e) = extract_input(pdf_path, &images_dir, dpi, &pages) {
                     eprintln!("Error extracting {}: {}", book_name, e);
                     continue; // Skip to next book on failure
                 }
 
                 println!("--- Phase 2: Transcribe ---");
-                let api_key = env::var("OPENROUTER_API_KEY").context("OPENROUTER_API_KEY must be set")?;
-                let model_str = model.clone().context("Model must be specified via --model or OPENROUTER_MODEL env var")?;
+                let provider: Arc<dyn Provider> = options.provider.build(options.base_url.clone())?.into();
+                let models = model.clone().context("Model must be specified via --model or OPENROUTER_MODEL env var")?;
                 
-                if let Err(e) = transcribe_images(images_dir, markdown_dir.clone(), concurrency, model_str, api_key, limit).await {
-                    eprintln!("Error transcribing {}: {}", book_name, e);
-                    continue;
+                let mut book_options = options.clone();
+                book_options.book_title.get_or_insert_with(|| book_name.replace('_', " "));
+                // Budgets apply to the whole run, so each book gets what earlier books left over
+                book_options.max_cost = options.max_cost.map(|m| m - run_usage.cost.unwrap_or(0.0));
+                book_options.max_tokens = options.max_tokens.map(|m| m.saturating_sub(run_usage.total_tokens()));
+
+                match transcribe_images(images_dir, markdown_dir.clone(), concurrency, models, provider, pages.clone(), book_options).await {
+                    Ok(usage) => {
+                        run_usage += usage;
+                        book_usages.push((book_name.to_string(), book_usage(&markdown_dir)?));
+                    }
+                    Err(e) => {
+                        if let Some(exceeded) = e.downcast_ref::<BudgetExceeded>() {
+                            run_usage += exceeded.spent;
+                            stopped = Some((exceeded.to_string(), EXIT_BUDGET_EXCEEDED));
+                            break;
+                        }
+                        if let Some(interrupted) = e.downcast_ref::<Interrupted>() {
+                            run_usage += interrupted.spent;
+                            stopped = Some((interrupted.to_string(), EXIT_INTERRUPTED));
+                            break;
+                        }
+                        eprintln!("Error transcribing {}: {}", book_name, e);
+                        continue;
+                    }
                 }
                 
                 println!("--- Phase 3: Combine ---");
                 
-                let combined_file = if input.is_dir() {
+                let combined_file = if batch {
                     let root = match &output {
                         Some(p) => p.clone(),
                         None => PathBuf::from("out")
@@ -640,19 +1933,417 @@ async fn main() -> Result<()> {
                     if !combined_dir.exists() {
                          std::fs::create_dir_all(&combined_dir).context("Failed to create combined output dir")?;
                     }
-                    combined_dir.join(format!("{}.md", book_name))
+                    combined_dir.join(combine.format.output_name(book_name))
                 } else {
-                    output_base.join(format!("{}.md", book_name))
+                    output_base.join(combine.format.output_name(book_name))
                 };
                 
-                 if let Err(e) = combine_book(&markdown_dir, &combined_file) {
+                 if let Err(e) = combine_book(&markdown_dir, &combined_file, &combine) {
                      eprintln!("Warning: Failed to combine files for {}: {}", book_name, e);
                  }
                  
                  println!("\nCompleted pipeline for: {}\n", book_name);
             }
+
+            if !book_usages.is_empty() {
+                println!("=== Usage ===");
+                for (book_name, usage) in &book_usages {
+                    println!("  {}: {}", book_name, usage);
+                }
+                println!("Pipeline run total (new requests only): {}", run_usage);
+            }
+
+            if let Some((message, code)) = stopped {
+                eprintln!("{}", message);
+                eprintln!("Pipeline stopped; run total: {}", run_usage);
+                std::process::exit(code);
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod budget_tests {
+    use super::*;
+
+    fn budget(max_cost: Option<f64>, max_tokens: Option<u64>) -> Budget {
+        Budget { max_cost, max_tokens, spent: std::sync::Mutex::new((Usage::default(), 0)) }
+    }
+
+    fn page(cost: f64, tokens: u64) -> Usage {
+        Usage { prompt_tokens: tokens, completion_tokens: 0, cost: Some(cost) }
+    }
+
+    fn transcribe(budget: &Budget, usage: Usage) {
+        budget.spend(usage);
+        budget.page_done();
+    }
+
+    #[test]
+    fn no_limits_never_exhausts() {
+        let budget = budget(None, None);
+        transcribe(&budget, page(100.0, 1_000_000));
+        assert!(!budget.unsampled());
+        assert_eq!(budget.exhausted(1000), None);
+    }
+
+    #[test]
+    fn unsampled_until_a_page_is_transcribed() {
+        let budget = budget(Some(1.0), None);
+        assert!(budget.unsampled());
+        // Nothing to project from yet; the caller runs pages one at a time meanwhile
+        assert_eq!(budget.exhausted(50), None);
+        // A failed page is paid for but measures nothing
+        budget.spend(page(0.1, 0));
+        assert!(budget.unsampled());
+        budget.page_done();
+        assert!(!budget.unsampled());
+    }
+
+    #[test]
+    fn projects_committed_pages_at_the_average_cost() {
+        let budget = budget(Some(2.0), None);
+        transcribe(&budget, page(0.125, 0));
+        transcribe(&budget, page(0.375, 0));
+        // 0.50 spent, 0.25 per page
+        assert_eq!(budget.exhausted(6), None);
+        assert!(budget.exhausted(7).is_some());
+    }
+
+    #[test]
+    fn failed_pages_raise_the_average() {
+        let budget = budget(Some(2.0), None);
+        transcribe(&budget, page(0.25, 0));
+        // Re-asks and fallbacks of a page that failed in the end
+        budget.spend(page(0.25, 0));
+        // 0.50 spent over one page
+        assert_eq!(budget.exhausted(3), None);
+        assert!(budget.exhausted(4).is_some());
+    }
+
+    #[test]
+    fn exhausted_once_spent_reaches_the_limit() {
+        let budget = budget(Some(0.5), None);
+        budget.spend(page(0.5, 0));
+        assert!(budget.exhausted(0).unwrap().contains("--max-cost"));
+    }
+
+    #[test]
+    fn projects_tokens() {
+        let budget = budget(None, Some(5000));
+        transcribe(&budget, page(0.0, 1000));
+        assert_eq!(budget.exhausted(4), None);
+        assert!(budget.exhausted(5).unwrap().contains("--max-tokens"));
+    }
+}
+
+#[cfg(test)]
+mod page_selection_tests {
+    use super::*;
+
+    fn ranges(s: &str) -> PageRanges {
+        s.parse().unwrap()
+    }
+
+    fn select(pages: &str, limit: Option<usize>, count: usize) -> Vec<usize> {
+        let selection = PageSelection { pages: Some(ranges(pages)), limit, force: false };
+        selection.apply((1..=count).map(|n| (n, ())).collect()).into_iter().map(|(n, _)| n).collect()
+    }
+
+    #[test]
+    fn parses_lists_and_ranges() {
+        assert_eq!(ranges("1,5,9-12"), PageRanges(vec![(1, Some(1)), (5, Some(5)), (9, Some(12))]));
+        assert_eq!(ranges("7-7"), PageRanges(vec![(7, Some(7))]));
+        assert_eq!(select("1,5,9-12", None, 20), vec![1, 5, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn open_range_runs_to_the_end() {
+        assert_eq!(ranges("120-"), PageRanges(vec![(120, None)]));
+        assert_eq!(select("18-", None, 20), vec![18, 19, 20]);
+    }
+
+    #[test]
+    fn ignores_whitespace_and_empty_parts() {
+        assert_eq!(ranges(" 1 , 3 - 4 ,, "), PageRanges(vec![(1, Some(1)), (3, Some(4))]));
+        assert_eq!(ranges("2 -"), PageRanges(vec![(2, None)]));
+    }
+
+    #[test]
+    fn selects_duplicates_and_overlaps_once() {
+        assert_eq!(select("3,3,2-4,4", None, 10), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn limit_applies_after_ranges() {
+        assert_eq!(select("5-", Some(2), 10), vec![5, 6]);
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!("0".parse::<PageRanges>().unwrap_err().contains("start at 1"));
+        assert!("0-3".parse::<PageRanges>().is_err());
+        assert!("12-9".parse::<PageRanges>().unwrap_err().contains("ends before it starts"));
+        assert!("-5".parse::<PageRanges>().is_err());
+        assert!("a-b".parse::<PageRanges>().unwrap_err().contains("invalid page number"));
+        assert!("1-2-3".parse::<PageRanges>().is_err());
+        assert!("".parse::<PageRanges>().is_err());
+        assert!(" , ".parse::<PageRanges>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod model_chain_tests {
+    use super::*;
+    use futures::future::BoxFuture;
+    use provider::{FinishReason, Transcription};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Answers requests with scripted replies, in order, and records the model each one asked for
+    struct ScriptedProvider {
+        replies: Mutex<VecDeque<std::result::Result<Transcription, ApiError>>>,
+        models: Mutex<Vec<String>>,
+    }
+
+    impl ScriptedProvider {
+        fn new(replies: Vec<std::result::Result<Transcription, ApiError>>) -> Self {
+            ScriptedProvider { replies: Mutex::new(replies.into()), models: Mutex::new(Vec::new()) }
+        }
+    }
+
+    impl Provider for ScriptedProvider {
+        fn transcribe<'a>(
+            &'a self,
+            request: &'a PageRequest,
+        ) -> BoxFuture<'a, std::result::Result<Transcription, ApiError>> {
+            self.models.lock().unwrap().push(request.model.clone());
+            let reply = self.replies.lock().unwrap().pop_front().expect("no reply left");
+            Box::pin(async move { reply })
+        }
+    }
+
+    fn reply(finish_reason: FinishReason) -> std::result::Result<Transcription, ApiError> {
+        Ok(Transcription {
+            text: "The page text.".to_string(),
+            usage: Usage::default(),
+            finish_reason,
+            logprob: None,
+        })
+    }
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        options: TranscribeOptions,
+    }
+
+    /// Transcription options as parsed from the given flags
+    pub(super) fn options(args: &[&str]) -> TranscribeOptions {
+        Cli::parse_from(["scribe"].iter().chain(args)).options
+    }
+
+    async fn request(provider: &ScriptedProvider, args: &[&str]) -> Result<PageResponse> {
+        let options = options(&[&["--max-attempts", "1"], args].concat());
+        let chain: ModelChain = "a,b,c".parse().unwrap();
+        let request = PageRequest {
+            model: String::new(),
+            prompt: "Transcribe.".to_string(),
+            image_base64: String::new(),
+            mime_type: "image/png".to_string(),
+            response_schema: None,
+            logprobs: false,
+        };
+        let budget = Budget::new(&options);
+        request_page(provider, request, &chain, None, &options, &budget, &ProgressBar::hidden(), "page_0001").await
+    }
+
+    #[test]
+    fn parses_comma_separated_models() {
+        let chain: ModelChain = "a,b, c".parse().unwrap();
+        assert_eq!(chain, ModelChain(vec!["a".to_string(), "b".to_string(), "c".to_string()]));
+        assert_eq!(chain.primary(), "a");
+        assert_eq!("  google/gemini-flash ".parse(), Ok(ModelChain(vec!["google/gemini-flash".to_string()])));
+    }
+
+    #[test]
+    fn rejects_empty_model_names() {
+        for list in ["", " ", "a,,b", "a, ,b", "a,", ",a"] {
+            assert!(list.parse::<ModelChain>().is_err(), "{:?}", list);
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_through_the_chain() {
+        let provider = ScriptedProvider::new(vec![
+            Err(ApiError::Fatal("unknown model".to_string())),
+            reply(FinishReason::Filtered),
+            reply(FinishReason::Complete),
+        ]);
+        let response = request(&provider, &["--reasks", "0"]).await.unwrap();
+        assert_eq!(response.model, "c");
+        assert_eq!(response.escalated_from, vec!["a", "b"]);
+        assert_eq!((response.attempts, response.reasks), (3, 0));
+        assert_eq!(*provider.models.lock().unwrap(), vec!["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn re_asks_a_model_before_giving_up_on_it() {
+        let provider = ScriptedProvider::new(vec![
+            reply(FinishReason::Truncated),
+            reply(FinishReason::Truncated),
+            reply(FinishReason::Complete),
+        ]);
+        let response = request(&provider, &["--reasks", "1"]).await.unwrap();
+        assert_eq!(response.model, "b");
+        assert_eq!(response.escalated_from, vec!["a"]);
+        assert_eq!(response.reasks, 1);
+        assert_eq!(*provider.models.lock().unwrap(), vec!["a", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn fails_once_every_model_has_failed() {
+        let provider = ScriptedProvider::new(vec![
+            reply(FinishReason::Filtered),
+            reply(FinishReason::Filtered),
+            reply(FinishReason::Filtered),
+        ]);
+        let Err(error) = request(&provider, &[]).await else { panic!("a model succeeded") };
+        assert!(error.to_string().starts_with("page_0001 failed on c (3 attempt(s), 0 re-ask(s))"), "{}", error);
+    }
+}
+
+#[cfg(test)]
+mod retry_tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use std::time::Instant;
+
+    fn retryable(retry_after: Option<Duration>) -> ApiError {
+        ApiError::Retryable { message: "overloaded".to_string(), retry_after }
+    }
+
+    /// Run `with_retries` over scripted results, returning the outcome, the attempts it
+    /// reported and the number of requests actually made
+    async fn run(
+        args: &[&str],
+        mut results: Vec<std::result::Result<u32, ApiError>>,
+    ) -> (std::result::Result<u32, ApiError>, u32, u32) {
+        let options = model_chain_tests::options(args);
+        let calls = AtomicU32::new(0);
+        results.reverse();
+        let results = std::sync::Mutex::new(results);
+        let (result, attempts) = with_retries(&options, &ProgressBar::hidden(), "page_0001", || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            let next = results.lock().unwrap().pop().expect("no result left");
+            async move { next }
+        })
+        .await;
+        (result, attempts, calls.into_inner())
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let base = Duration::from_millis(100);
+        for _ in 0..100 {
+            for attempt in 1..=6 {
+                let full = base * (1 << (attempt - 1));
+                let delay = backoff_delay(attempt, base);
+                assert!(delay >= full / 2 && delay <= full, "attempt {}: {:?}", attempt, delay);
+            }
+            for attempt in [12, 40, u32::MAX] {
+                let delay = backoff_delay(attempt, base);
+                assert!(delay >= MAX_BACKOFF / 2 && delay <= MAX_BACKOFF, "attempt {}: {:?}", attempt, delay);
+            }
+        }
+        assert_eq!(backoff_delay(3, Duration::ZERO), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let (result, attempts, calls) =
+            run(&["--backoff-ms", "1"], vec![Err(retryable(None)), Err(retryable(None)), Ok(7)]).await;
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!((attempts, calls), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let results = (0..3).map(|_| Err(retryable(None))).collect();
+        let (result, attempts, calls) = run(&["--backoff-ms", "1", "--max-attempts", "3"], results).await;
+        assert!(matches!(result, Err(ApiError::Retryable { .. })));
+        assert_eq!((attempts, calls), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn fatal_errors_are_not_retried() {
+        let (result, attempts, calls) =
+            run(&["--backoff-ms", "1"], vec![Err(ApiError::Fatal("bad key".to_string()))]).await;
+        assert!(matches!(result, Err(ApiError::Fatal(_))));
+        assert_eq!((attempts, calls), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn honours_retry_after() {
+        // No backoff of its own, so any wait comes from Retry-After
+        let started = Instant::now();
+        let (result, _, calls) =
+            run(&["--backoff-ms", "0"], vec![Err(retryable(Some(Duration::from_millis(200)))), Ok(1)]).await;
+        assert!(result.is_ok());
+        assert_eq!(calls, 2);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+}
+
+#[cfg(test)]
+mod prompt_tests {
+    use super::*;
+    use model_chain_tests::options;
+
+    #[test]
+    fn fills_in_every_placeholder() {
+        assert_eq!(
+            render_prompt("Page {page_number} of {total_pages} of \"{book_title}\", page {page_number}", "Walden", 7, 300),
+            "Page 7 of 300 of \"Walden\", page 7"
+        );
+        for template in PromptTemplate::value_variants() {
+            let prompt = render_prompt(template.text(), "Walden", 7, 300);
+            assert!(prompt.contains("page 7 of 300") && prompt.contains("\"Walden\""), "{:?}", template);
+            assert!(!prompt.contains('{'), "{:?}", template);
+        }
+    }
+
+    #[test]
+    fn substituted_values_are_not_rescanned() {
+        assert_eq!(
+            render_prompt("{book_title}: {page_number}", "{page_number} {total_pages}", 1, 2),
+            "{page_number} {total_pages}: 1"
+        );
+    }
+
+    #[test]
+    fn picks_the_prompt_source() {
+        assert_eq!(options(&[]).prompt_template().unwrap(), PromptTemplate::Book.text());
+        assert_eq!(options(&["--template", "legal"]).prompt_template().unwrap(), PromptTemplate::Legal.text());
+        assert_eq!(options(&["--prompt", "Page {page_number}"]).prompt_template().unwrap(), "Page {page_number}");
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "From a file, page {page_number}").unwrap();
+        let path = file.path().to_str().unwrap();
+        assert_eq!(options(&["--prompt-file", path]).prompt_template().unwrap(), "From a file, page {page_number}");
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let error = options(&["--prompt", "Transcribe page {page} of {book_title}"]).prompt_template().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unknown placeholder {page} in the prompt (use {book_title}, {page_number} or {total_pages})"
+        );
+        assert!(options(&["--prompt", "Page {Page_Number}"]).prompt_template().is_err());
+        // Braces that aren't placeholders, like a JSON example, are fine
+        assert!(options(&["--prompt", "Reply as {\"text\": \"...\"} for {page_number}"]).prompt_template().is_ok());
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::combine::{link_text, parse_headings, AnchorStyle, Slugger};
+use crate::render::{BookMetadata, Chapter};
+
+fn chapter_file(index: usize) -> String {
+    format!("chapter_{:03}.md", index + 1)
+}
+
+/// TOML basic string; JSON's string escapes are a subset of TOML's
+fn toml_string(text: &str) -> String {
+    serde_json::to_string(text).unwrap_or_else(|_| "\"\"".to_string())
+}
+
+/// Write the chapters as an mdBook project: `book.toml`, `src/SUMMARY.md` and one
+/// `src/chapter_NNN.md` per chapter. Heading slugs come from `slugs` in book order (as
+/// `collect_headings` produced them). Since mdBook numbers duplicate heading IDs per page,
+/// links to headings and `page_N` anchors are rewritten to the chapter file and ID they
+/// end up with. An existing `book.toml` is kept, so local settings survive a rebuild.
+pub fn write_mdbook(
+    dir: &Path,
+    metadata: &BookMetadata,
+    chapters: &[Chapter],
+    slugs: &mut impl Iterator<Item = String>,
+) -> Result<()> {
+    let anchor = Regex::new(r"<a id='(page_\d+)'></a>")?;
+
+    // Where every book-wide anchor ID lives: (chapter file, ID within that page)
+    let mut targets: HashMap<String, (String, String)> = HashMap::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let file = chapter_file(i);
+        for cap in anchor.captures_iter(&chapter.markdown) {
+            targets.insert(cap[1].to_string(), (file.clone(), cap[1].to_string()));
+        }
+        // mdBook gives every heading an ID, underlined ones too, but the book-wide slugs only
+        // cover ATX headings
+        let mut local = Slugger::new(AnchorStyle::Mdbook);
+        for heading in parse_headings(&chapter.markdown) {
+            let id = local.slug(&heading.text);
+            if heading.atx {
+                if let Some(global) = slugs.next() {
+                    targets.entry(global).or_insert((file.clone(), id));
+                }
+            }
+        }
+    }
+
+    let md_link = Regex::new(r"\]\(#([^)\s]+)\)")?;
+    let html_link = Regex::new(r#"href=['"]#([^'"]+)['"]"#)?;
+    // mdBook turns `.md` links into `.html` only in Markdown links, not in raw HTML
+    let link = |file: &str, id: &str, raw_html: bool| match targets.get(id) {
+        Some((target, local)) if target == file => format!("#{}", local),
+        Some((target, local)) if raw_html => format!("{}#{}", target.replace(".md", ".html"), local),
+        Some((target, local)) => format!("{}#{}", target, local),
+        None => format!("#{}", id),
+    };
+
+    let src = dir.join("src");
+    std::fs::create_dir_all(&src).with_context(|| format!("Failed to create {:?}", src))?;
+    // Chapters from an earlier build that no longer exist would linger unlisted
+    for entry in std::fs::read_dir(&src)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if name.starts_with("chapter_") && name.ends_with(".md") {
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    let mut summary = String::from("# Summary\n\n");
+    let mut numbered = false;
+    for (i, chapter) in chapters.iter().enumerate() {
+        let file = chapter_file(i);
+        let content = md_link.replace_all(&chapter.markdown, |c: &Captures| format!("]({})", link(&file, &c[1], false)));
+        let content = html_link.replace_all(&content, |c: &Captures| format!("href=\"{}\"", link(&file, &c[1], true)));
+        std::fs::write(src.join(&file), format!("{}\n", content.trim_end()))?;
+
+        // Front matter is an unnumbered prefix chapter, which mdBook only allows before the list
+        if chapter.front_matter && !numbered {
+            summary.push_str(&format!("[{}]({})\n\n", link_text(&chapter.title), file));
+        } else {
+            numbered = true;
+            summary.push_str(&format!("- [{}]({})\n", link_text(&chapter.title), file));
+        }
+    }
+    std::fs::write(src.join("SUMMARY.md"), summary)?;
+
+    let book_toml = dir.join("book.toml");
+    if book_toml.exists() {
+        println!("Keeping existing {:?}", book_toml);
+    } else {
+        let mut toml = format!("[book]\ntitle = {}\n", toml_string(&metadata.title));
+        if let Some(author) = &metadata.author {
+            toml.push_str(&format!("authors = [{}]\n", toml_string(author)));
+        }
+        toml.push_str(&format!("language = {}\nsrc = \"src\"\n", toml_string(&metadata.language)));
+        std::fs::write(&book_toml, toml)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combine::{collect_headings, join_pages};
+    use crate::render::split_chapters;
+
+    /// Build the mdBook the way `combine` does and return each chapter file and SUMMARY.md
+    fn build(pages: &[(usize, &str)]) -> (Vec<String>, String) {
+        let pages: Vec<(usize, String)> = pages.iter().map(|(n, text)| (*n, text.to_string())).collect();
+        let headings = collect_headings(&pages, &mut Slugger::new(AnchorStyle::Mdbook));
+        let chapters = split_chapters(&join_pages(&pages).0, "Front");
+        let metadata = BookMetadata { title: "Book".to_string(), author: None, language: "en".to_string(), cover: None };
+        let dir = tempfile::tempdir().unwrap();
+        write_mdbook(dir.path(), &metadata, &chapters, &mut headings.iter().map(|h| h.slug.clone())).unwrap();
+        let src = dir.path().join("src");
+        let files = (0..chapters.len()).map(|i| std::fs::read_to_string(src.join(chapter_file(i))).unwrap()).collect();
+        (files, std::fs::read_to_string(src.join("SUMMARY.md")).unwrap())
+    }
+
+    #[test]
+    fn links_follow_headings_and_pages_across_chapters() {
+        let (files, summary) = build(&[
+            (1, "# One\n\nUnderlined\n----------\n\n## Notes\n\nSee [page 2](#page_2) and <a href=\"#notes-1\">its notes</a>."),
+            (2, "# Two [draft]\n\n## Notes\n\nBack to [the first notes](#notes) or [these](#notes-1)."),
+        ]);
+        assert_eq!(files.len(), 2);
+        assert_eq!(summary, "# Summary\n\n- [One](chapter_001.md)\n- [Two \\[draft\\]](chapter_002.md)\n");
+
+        // The underlined heading has an ID in mdBook but no book-wide slug, so "notes" still
+        // resolves to the first chapter's second ATX heading
+        assert!(files[0].contains("See [page 2](chapter_002.md#page_2)"), "{}", files[0]);
+        // Raw HTML isn't rewritten by mdBook, so it links the rendered page
+        assert!(files[0].contains("<a href=\"chapter_002.html#notes\">its notes</a>"), "{}", files[0]);
+        // The second "Notes" is notes-1 book-wide but notes within its own chapter
+        assert!(files[1].contains("[the first notes](chapter_001.md#notes)"), "{}", files[1]);
+        assert!(files[1].contains("[these](#notes)"), "{}", files[1]);
+        assert!(files[1].starts_with("<a id='page_2'></a>\n\n# Two [draft]"), "{}", files[1]);
+    }
+
+    #[test]
+    fn front_matter_is_an_unnumbered_prefix_chapter() {
+        let (files, summary) = build(&[(1, "Title page\n\nUnderlined\n----------"), (2, "# One\n\nText.")]);
+        assert_eq!(files.len(), 2);
+        assert_eq!(summary, "# Summary\n\n[Front](chapter_001.md)\n\n- [One](chapter_002.md)\n");
+    }
+
+    #[test]
+    fn unknown_anchors_are_left_alone() {
+        let (files, _) = build(&[(1, "# One\n\nSee [nowhere](#missing).")]);
+        assert!(files[0].contains("[nowhere](#missing)"));
+    }
+}
@@ -0,0 +1,305 @@
+use anyhow::{Context, Result};
+use mupdf::pdf::{PdfDocument, PdfObject, PdfWriteOptions};
+use mupdf::Font;
+use std::path::Path;
+
+use crate::input::{page_text, text_layer_usable};
+use crate::render::plain_text;
+
+/// Resource name of the text layer font, numbered if the page already has a font by that name
+const FONT_RESOURCE: &str = "ScribeText";
+/// Built-in font for the text layer; it is never drawn, so only its character coverage matters.
+/// It covers Latin, Greek and Cyrillic; other scripts need a font passed with --font.
+const FONT_NAME: &str = "Helvetica";
+/// Lines never get taller than this, so a short page doesn't get page-sized selection boxes
+const MAX_LINE_HEIGHT: f32 = 24.0;
+/// Blank border around the text layer, as a fraction of the page size
+const MARGIN: f32 = 0.06;
+
+/// What `overlay_pdf` did with each page
+#[derive(Debug, Default)]
+pub struct OverlayStats {
+    pub overlaid: usize,
+    pub had_text: usize,
+    pub missing: usize,
+    /// Overlaid pages with characters the font has no glyph for
+    pub unmappable_pages: usize,
+    /// Those characters, which search and copy as blanks
+    pub unmappable_chars: usize,
+}
+
+/// The page's visible area in PDF user space: the crop box, falling back to the media box
+fn page_box(page: &PdfObject) -> Result<(f32, f32, f32, f32)> {
+    let bbox = match page.get_dict_inheritable("CropBox")? {
+        Some(b) => b,
+        None => page.get_dict_inheritable("MediaBox")?.context("Page has no MediaBox")?,
+    };
+    let mut v = [0.0; 4];
+    for (i, slot) in v.iter_mut().enumerate() {
+        *slot = bbox.get_array(i as i32)?.context("Malformed page box")?.as_float()?;
+    }
+    Ok((v[0].min(v[2]), v[1].min(v[3]), v[0].max(v[2]), v[1].max(v[3])))
+}
+
+/// The page's `/Rotate`, normalised to 0, 90, 180 or 270 degrees clockwise
+fn page_rotation(page: &PdfObject) -> Result<i32> {
+    let rotate = match page.get_dict_inheritable("Rotate")? {
+        Some(r) => r.as_int()?,
+        None => 0,
+    };
+    // Viewers ignore a rotation that isn't a multiple of 90
+    Ok(if rotate % 90 == 0 { rotate.rem_euclid(360) } else { 0 })
+}
+
+/// Content stream drawing the lines invisibly (text render mode 3), spread evenly down the
+/// page as it is displayed after `rotate`, and squeezed horizontally where a line would
+/// overflow it. Only page-level placement: the words don't sit over their images. Also returns
+/// the number of characters the font has no glyph for.
+fn text_layer_stream(
+    font: &Font,
+    resource: &str,
+    lines: &[&str],
+    bbox: (f32, f32, f32, f32),
+    rotate: i32,
+) -> Result<(String, usize)> {
+    let (x0, y0, x1, y1) = bbox;
+    // Lay the text out upright on the displayed page, then map that onto the page box
+    let (w, h, matrix) = match rotate {
+        90 => (y1 - y0, x1 - x0, format!("0 1 -1 0 {:.2} {:.2}", x1, y0)),
+        180 => (x1 - x0, y1 - y0, format!("-1 0 0 -1 {:.2} {:.2}", x1, y1)),
+        270 => (y1 - y0, x1 - x0, format!("0 -1 1 0 {:.2} {:.2}", x0, y1)),
+        _ => (x1 - x0, y1 - y0, format!("1 0 0 1 {:.2} {:.2}", x0, y0)),
+    };
+    let (mx, my) = (w * MARGIN, h * MARGIN);
+    let width = w - 2.0 * mx;
+    let line_height = ((h - 2.0 * my) / lines.len() as f32).min(MAX_LINE_HEIGHT);
+    let size = line_height * 0.8;
+
+    let mut stream = format!("Q\nq\n{} cm\nBT\n3 Tr\n/{} {:.2} Tf\n", matrix, resource, size);
+    let mut unmappable = 0;
+    for (i, line) in lines.iter().enumerate() {
+        // The font is added with Identity-H encoding, so strings are 2-byte glyph IDs
+        let mut glyphs = String::new();
+        let mut advance = 0.0;
+        for c in line.chars() {
+            let gid = font.encode_character(c as i32)?;
+            // Glyph 0 is .notdef: the character is lost from the text layer
+            if gid == 0 && !c.is_whitespace() {
+                unmappable += 1;
+            }
+            advance += font.advance_glyph(gid)? * size;
+            glyphs.push_str(&format!("{:04X}", gid));
+        }
+        let scale = if advance > width { 100.0 * width / advance } else { 100.0 };
+        let baseline = h - my - (i as f32 + 1.0) * line_height + (line_height - size) / 2.0;
+        stream.push_str(&format!("{:.2} Tz\n1 0 0 1 {:.2} {:.2} Tm\n<{}> Tj\n", scale, mx, baseline, glyphs));
+    }
+    stream.push_str("ET\nQ\n");
+    Ok((stream, unmappable))
+}
+
+/// A direct copy of `dict`'s entries; the values are shared, not copied
+fn copy_dict(doc: &PdfDocument, dict: Option<&PdfObject>) -> Result<PdfObject> {
+    let mut copy = doc.new_dict()?;
+    if let Some(dict) = dict {
+        for i in 0..dict.dict_len()? as i32 {
+            if let (Some(key), Some(value)) = (dict.get_dict_key(i)?, dict.get_dict_val(i)?) {
+                copy.dict_put(key, value)?;
+            }
+        }
+    }
+    Ok(copy)
+}
+
+/// Add the text layer font to the page's resources and return its resource name. Resources
+/// are often shared: inherited from the page tree or referenced by several pages. So the page
+/// gets its own copy of its resource and font dictionaries before the font goes in, under a
+/// name none of its fonts use.
+fn add_font_resource(doc: &PdfDocument, page: &mut PdfObject, font: &PdfObject) -> Result<String> {
+    let inherited = page.get_dict_inheritable("Resources")?;
+    let mut resources = copy_dict(doc, inherited.as_ref())?;
+    let mut fonts = copy_dict(doc, resources.get_dict("Font")?.as_ref())?;
+    let mut name = FONT_RESOURCE.to_string();
+    let mut n = 1;
+    while fonts.get_dict(name.as_str())?.is_some() {
+        name = format!("{}{}", FONT_RESOURCE, n);
+        n += 1;
+    }
+    fonts.dict_put(name.as_str(), font.try_clone()?)?;
+    resources.dict_put("Font", fonts)?;
+    page.dict_put("Resources", resources)?;
+    Ok(name)
+}
+
+/// Draw the text layer over the page's existing content. The old content is wrapped in
+/// `q`/`Q` so whatever graphics state it leaves behind doesn't move or clip the text.
+fn add_text_layer(doc: &mut PdfDocument, page: &mut PdfObject, content: &str) -> Result<()> {
+    let mut save = doc.add_object(&doc.new_dict()?)?;
+    save.write_stream_string("q\n")?;
+    let mut layer = doc.add_object(&doc.new_dict()?)?;
+    layer.write_stream_string(content)?;
+
+    let mut contents = doc.new_array()?;
+    contents.array_push(save)?;
+    if let Some(existing) = page.get_dict("Contents")? {
+        if existing.is_array()? {
+            for i in 0..existing.len()? {
+                if let Some(item) = existing.get_array(i as i32)? {
+                    contents.array_push(item)?;
+                }
+            }
+        } else {
+            contents.array_push(existing)?;
+        }
+    }
+    contents.array_push(layer)?;
+    page.dict_put("Contents", contents)?;
+    Ok(())
+}
+
+/// Write a copy of `input` where every transcribed page carries its Markdown as invisible,
+/// searchable text. Pages that already have a usable text layer are left alone unless `force`.
+/// `font_file` is a TrueType or OpenType font to embed instead of the built-in one.
+pub fn overlay_pdf(
+    input: &Path,
+    markdown_dir: &Path,
+    output: &Path,
+    force: bool,
+    font_file: Option<&Path>,
+) -> Result<OverlayStats> {
+    let mut doc = PdfDocument::open(input.to_str().context("Invalid path")?)
+        .with_context(|| format!("Failed to open {:?} as a PDF", input))?;
+    let total_pages = doc.page_count().context("Failed to get page count")?;
+    let font = match font_file {
+        Some(path) => {
+            let data = std::fs::read(path).with_context(|| format!("Failed to read font {:?}", path))?;
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            Font::from_bytes(&name, &data).with_context(|| format!("Failed to load font {:?}", path))?
+        }
+        None => Font::new(FONT_NAME)?,
+    };
+    let font_ref = doc.add_font(&font)?;
+
+    let mut stats = OverlayStats::default();
+    for page_num in 1..=total_pages as usize {
+        let md_path = markdown_dir.join(format!("page_{:04}.md", page_num));
+        let Ok(markdown) = std::fs::read_to_string(&md_path) else {
+            stats.missing += 1;
+            continue;
+        };
+        if !force {
+            let page = doc.load_page(page_num as i32 - 1)?;
+            if page_text(&page).is_ok_and(|(text, _)| text_layer_usable(&text)) {
+                stats.had_text += 1;
+                continue;
+            }
+        }
+
+        let text = plain_text(&markdown);
+        let lines: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+        if lines.is_empty() {
+            continue;
+        }
+        let mut page = doc.find_page(page_num as i32 - 1)?;
+        let resource = add_font_resource(&doc, &mut page, &font_ref)
+            .with_context(|| format!("Failed to add the text font to page {}", page_num))?;
+        let (content, unmappable) =
+            text_layer_stream(&font, &resource, &lines, page_box(&page)?, page_rotation(&page)?)?;
+        if unmappable > 0 {
+            stats.unmappable_pages += 1;
+            stats.unmappable_chars += unmappable;
+        }
+        add_text_layer(&mut doc, &mut page, &content)
+            .with_context(|| format!("Failed to add text to page {}", page_num))?;
+        stats.overlaid += 1;
+    }
+
+    let mut options = PdfWriteOptions::default();
+    options.set_garbage(true).set_compress(true);
+    doc.save_with_options(output.to_str().context("Invalid path")?, options)
+        .with_context(|| format!("Failed to write {:?}", output))?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARKDOWN: &str = "# Chapter One\n\nThe first line of text.\nAnd the *second* one.\n";
+
+    fn write_pages(dir: &Path, pages: &[usize]) {
+        for page in pages {
+            std::fs::write(dir.join(format!("page_{:04}.md", page)), MARKDOWN).unwrap();
+        }
+    }
+
+    fn read_text(path: &Path, page: i32) -> String {
+        let doc = mupdf::Document::open(path.to_str().unwrap()).unwrap();
+        page_text(&doc.load_page(page).unwrap()).unwrap().0
+    }
+
+    #[test]
+    fn text_layer_reads_back_on_plain_and_rotated_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.pdf"), dir.path().join("out.pdf"));
+        let mut doc = PdfDocument::new();
+        doc.new_page((400.0, 600.0)).unwrap();
+        doc.new_page((400.0, 600.0)).unwrap().set_rotation(90).unwrap();
+        doc.save(input.to_str().unwrap()).unwrap();
+        write_pages(dir.path(), &[1, 2]);
+
+        let stats = overlay_pdf(&input, dir.path(), &output, false, None).unwrap();
+        assert_eq!(stats.overlaid, 2);
+        assert_eq!(stats.unmappable_chars, 0);
+        for page in 0..2 {
+            let text = read_text(&output, page);
+            let lines: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+            assert_eq!(lines, vec!["Chapter One", "The first line of text.", "And the second one."], "page {}", page + 1);
+        }
+    }
+
+    #[test]
+    fn inherited_resources_are_copied_to_the_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.pdf"), dir.path().join("out.pdf"));
+        let mut doc = PdfDocument::new();
+        doc.new_page((400.0, 600.0)).unwrap();
+        doc.new_page((400.0, 600.0)).unwrap();
+        // Page 1 inherits resources from the page tree, which already name a font ScribeText
+        let mut existing = doc.new_dict().unwrap();
+        existing.dict_put("Type", PdfObject::new_name("Font").unwrap()).unwrap();
+        let mut fonts = doc.new_dict().unwrap();
+        fonts.dict_put(FONT_RESOURCE, existing).unwrap();
+        let mut shared = doc.new_dict().unwrap();
+        shared.dict_put("Font", fonts).unwrap();
+        let shared = doc.add_object(&shared).unwrap();
+        doc.catalog().unwrap().get_dict("Pages").unwrap().unwrap().dict_put("Resources", shared).unwrap();
+        doc.find_page(0).unwrap().dict_delete("Resources").unwrap();
+        doc.save(input.to_str().unwrap()).unwrap();
+        write_pages(dir.path(), &[1]);
+
+        let stats = overlay_pdf(&input, dir.path(), &output, false, None).unwrap();
+        assert_eq!((stats.overlaid, stats.missing), (1, 1));
+        let doc = PdfDocument::open(output.to_str().unwrap()).unwrap();
+        let tree_fonts = doc.catalog().unwrap().get_dict("Pages").unwrap().unwrap()
+            .get_dict("Resources").unwrap().unwrap().get_dict("Font").unwrap().unwrap();
+        assert_eq!(tree_fonts.dict_len().unwrap(), 1);
+        let page_fonts = doc.find_page(0).unwrap()
+            .get_dict("Resources").unwrap().unwrap().get_dict("Font").unwrap().unwrap();
+        assert!(page_fonts.get_dict(FONT_RESOURCE).unwrap().is_some());
+        assert!(page_fonts.get_dict("ScribeText1").unwrap().is_some());
+        assert!(read_text(&output, 0).contains("The first line of text."));
+        assert!(read_text(&output, 1).is_empty());
+    }
+
+    #[test]
+    fn rotation_defaults_to_upright() {
+        let doc = PdfDocument::new();
+        let mut page = doc.new_dict().unwrap();
+        assert_eq!(page_rotation(&page).unwrap(), 0);
+        for (rotate, expected) in [(90, 90), (-90, 270), (450, 90), (45, 0)] {
+            page.dict_put("Rotate", doc.new_int(rotate).unwrap()).unwrap();
+            assert_eq!(page_rotation(&page).unwrap(), expected);
+        }
+    }
+}
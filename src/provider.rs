@@ -0,0 +1,861 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use futures::future::BoxFuture;
+use reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::time::Duration;
+
+/// Per-request timeout; vision models can be slow on dense pages
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Upper bound on output tokens for APIs that require one (Anthropic)
+const MAX_OUTPUT_TOKENS: u32 = 8192;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderKind {
+    /// OpenRouter (OpenAI-compatible, default)
+    #[value(name = "openrouter")]
+    OpenRouter,
+    /// Any OpenAI-compatible chat completions endpoint (OpenAI, vLLM, llama.cpp server, ...)
+    #[value(name = "openai")]
+    OpenAi,
+    /// Native Anthropic Messages API
+    Anthropic,
+    /// Native Google Gemini generateContent API
+    Gemini,
+    /// Local Ollama server
+    Ollama,
+}
+
+impl ProviderKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ProviderKind::OpenRouter => "openrouter",
+            ProviderKind::OpenAi => "openai",
+            ProviderKind::Anthropic => "anthropic",
+            ProviderKind::Gemini => "gemini",
+            ProviderKind::Ollama => "ollama",
+        }
+    }
+
+    fn default_base_url(self) -> &'static str {
+        match self {
+            ProviderKind::OpenRouter => "https://openrouter.ai/api/v1",
+            ProviderKind::OpenAi => "https://api.openai.com/v1",
+            ProviderKind::Anthropic => "https://api.anthropic.com/v1",
+            ProviderKind::Gemini => "https://generativelanguage.googleapis.com/v1beta",
+            ProviderKind::Ollama => "http://localhost:11434",
+        }
+    }
+
+    /// Environment variable holding the API key, if the provider uses one
+    fn api_key_env(self) -> Option<&'static str> {
+        match self {
+            ProviderKind::OpenRouter => Some("OPENROUTER_API_KEY"),
+            ProviderKind::OpenAi => Some("OPENAI_API_KEY"),
+            ProviderKind::Anthropic => Some("ANTHROPIC_API_KEY"),
+            ProviderKind::Gemini => Some("GEMINI_API_KEY"),
+            ProviderKind::Ollama => None,
+        }
+    }
+
+    /// Build a provider, reading its API key from the environment.
+    /// A custom `base_url` makes the key optional for OpenAI-compatible servers (on-prem deployments).
+    pub fn build(self, base_url: Option<String>) -> Result<Box<dyn Provider>> {
+        let custom_url = base_url.is_some();
+        let base_url = base_url
+            .unwrap_or_else(|| self.default_base_url().to_string())
+            .trim_end_matches('/')
+            .to_string();
+        let api_key = match self.api_key_env() {
+            Some(var) => match env::var(var) {
+                Ok(key) => Some(key),
+                Err(_) if self == ProviderKind::OpenAi && custom_url => None,
+                Err(_) => return Err(anyhow::anyhow!("{} must be set", var)),
+            },
+            None => None,
+        };
+        let client = Client::new();
+
+        Ok(match self {
+            ProviderKind::OpenRouter | ProviderKind::OpenAi => Box::new(OpenAiCompatible {
+                client,
+                base_url,
+                api_key,
+                // Only OpenRouter understands the usage accounting extension
+                report_cost: self == ProviderKind::OpenRouter,
+            }),
+            ProviderKind::Anthropic => Box::new(Anthropic {
+                client,
+                base_url,
+                api_key: api_key.context("ANTHROPIC_API_KEY must be set")?,
+            }),
+            ProviderKind::Gemini => Box::new(Gemini {
+                client,
+                base_url,
+                api_key: api_key.context("GEMINI_API_KEY must be set")?,
+            }),
+            ProviderKind::Ollama => Box::new(Ollama { client, base_url }),
+        })
+    }
+}
+
+/// Everything a provider needs to transcribe one page
+#[derive(Debug, Clone)]
+pub struct PageRequest {
+    pub model: String,
+    pub prompt: String,
+    pub image_base64: String,
+    pub mime_type: String,
+    /// JSON schema the response must follow, for providers that can enforce one.
+    /// The prompt has to describe the format too, since not all of them can.
+    pub response_schema: Option<serde_json::Value>,
+    /// Ask for token log probabilities (OpenAI-compatible APIs only)
+    pub logprobs: bool,
+}
+
+/// Provider-independent result of a page transcription
+#[derive(Debug, Clone)]
+pub struct Transcription {
+    pub text: String,
+    pub usage: Usage,
+    pub finish_reason: FinishReason,
+    /// Mean log probability per output token, when the provider reports it
+    pub logprob: Option<f64>,
+}
+
+/// Why the model stopped generating, normalized across providers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// Natural end of the answer
+    Complete,
+    /// Hit the output token limit, so the page is cut off
+    Truncated,
+    /// Stopped or emptied by the provider's safety filter
+    Filtered,
+    /// Not reported, or a reason we don't know
+    Unknown,
+}
+
+impl FinishReason {
+    /// Map the reason strings used by the supported APIs:
+    /// OpenAI `finish_reason`, Anthropic `stop_reason`, Gemini `finishReason`, Ollama `done_reason`
+    fn parse(reason: Option<&str>) -> Self {
+        match reason.map(str::to_ascii_lowercase).as_deref() {
+            Some("stop" | "end_turn" | "stop_sequence") => FinishReason::Complete,
+            Some("length" | "max_tokens") => FinishReason::Truncated,
+            Some(
+                "content_filter" | "refusal" | "safety" | "recitation" | "blocklist" | "prohibited_content" | "spii",
+            ) => FinishReason::Filtered,
+            _ => FinishReason::Unknown,
+        }
+    }
+}
+
+/// Token counts and cost for one or more requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Cost in USD as reported by the provider (only OpenRouter reports it)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost = match (self.cost, other.cost) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0.0) + b.unwrap_or(0.0)),
+        };
+    }
+}
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} prompt + {} completion tokens", self.prompt_tokens, self.completion_tokens)?;
+        match self.cost {
+            Some(cost) => write!(f, ", cost ${:.4}", cost),
+            None => write!(f, ", cost unknown"),
+        }
+    }
+}
+
+/// A vision-capable LLM backend
+pub trait Provider: Send + Sync {
+    fn transcribe<'a>(&'a self, request: &'a PageRequest) -> BoxFuture<'a, Result<Transcription, ApiError>>;
+}
+
+// --- Errors ---
+
+/// A failed API request, classified so the retry loop knows whether to try again
+#[derive(Debug)]
+pub enum ApiError {
+    /// Timeouts, 429, 5xx and provider overload
+    Retryable { message: String, retry_after: Option<Duration> },
+    /// Bad credentials, invalid model, malformed request
+    Fatal(String),
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Retryable { message, .. } => write!(f, "{}", message),
+            ApiError::Fatal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ApiError {
+    pub fn retryable(message: impl Into<String>) -> Self {
+        ApiError::Retryable { message: message.into(), retry_after: None }
+    }
+
+    /// Classify a non-success HTTP response
+    fn from_status(status: reqwest::StatusCode, retry_after: Option<Duration>, body: &str) -> Self {
+        let message = format!("API Error ({}): {}", status, body.trim());
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || status == reqwest::StatusCode::REQUEST_TIMEOUT
+            || status.is_server_error()
+        {
+            ApiError::Retryable { message, retry_after }
+        } else {
+            ApiError::Fatal(message)
+        }
+    }
+
+    /// Classify an error object returned inside a 200 response body
+    fn from_body(err: &OpenRouterError) -> Self {
+        let type_str = err.error_type.as_deref().unwrap_or("unknown");
+        let message = format!("API Error ({}): {}", type_str, err.message);
+        let code = err.code.as_ref().and_then(|c| c.as_u64());
+        let overloaded = type_str.contains("overloaded") || err.message.to_lowercase().contains("overloaded");
+        if overloaded || matches!(code, Some(408 | 429 | 500..=599)) {
+            ApiError::retryable(message)
+        } else {
+            ApiError::Fatal(message)
+        }
+    }
+
+    /// Network-level failures are worth another try. A body that doesn't decode means the
+    /// endpoint speaks a different API (wrong --base-url or version) and will keep doing so.
+    fn from_reqwest(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            ApiError::Fatal(format!("Unexpected response format: {}", err))
+        } else {
+            ApiError::retryable(format!("Request failed: {}", err))
+        }
+    }
+}
+
+/// Parse a `Retry-After` header given in seconds
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Error for a response without any text. A filtered or refused page will be refused again
+/// and a page that hit the token limit before any text will hit it again, so only an
+/// unexplained empty response is worth retrying.
+fn empty_response(finish_reason: FinishReason) -> ApiError {
+    match finish_reason {
+        FinishReason::Filtered => ApiError::Fatal("Blocked by provider's content filter".to_string()),
+        FinishReason::Truncated => ApiError::Fatal("Output token limit reached before any text".to_string()),
+        FinishReason::Complete | FinishReason::Unknown => ApiError::retryable("No content in response"),
+    }
+}
+
+/// Send a JSON request and decode the JSON response, classifying any failure
+async fn send_json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, ApiError> {
+    let resp = request
+        .header("Content-Type", "application/json")
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await
+        .map_err(ApiError::from_reqwest)?;
+
+    let status = resp.status();
+    if !status.is_success() {
+        let retry_after = parse_retry_after(resp.headers());
+        let txt = resp.text().await.unwrap_or_default();
+        return Err(ApiError::from_status(status, retry_after, &txt));
+    }
+
+    resp.json().await.map_err(ApiError::from_reqwest)
+}
+
+// --- OpenAI-compatible (OpenRouter, OpenAI, vLLM, llama.cpp) ---
+
+#[derive(Serialize)]
+struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<UsageRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+}
+
+/// Structured output request: `{"type": "json_schema", "json_schema": {...}}`
+#[derive(Serialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    format_type: String,
+    json_schema: JsonSchemaFormat,
+}
+
+#[derive(Serialize)]
+struct JsonSchemaFormat {
+    name: String,
+    strict: bool,
+    schema: serde_json::Value,
+}
+
+/// OpenRouter extension asking for token counts and cost in the response
+#[derive(Serialize)]
+struct UsageRequest {
+    include: bool,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: String,
+    content: Vec<ContentPart>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum ContentPart {
+    #[serde(rename = "text")]
+    Text { text: String },
+    #[serde(rename = "image_url")]
+    ImageUrl { image_url: ImageUrlData },
+}
+
+#[derive(Serialize)]
+struct ImageUrlData {
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatCompletionResponse {
+    choices: Option<Vec<Choice>>,
+    error: Option<OpenRouterError>,
+    usage: Option<ChatUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatUsage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+    cost: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Choice {
+    message: Option<ResponseMessage>,
+    finish_reason: Option<String>,
+    logprobs: Option<ChoiceLogprobs>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChoiceLogprobs {
+    content: Option<Vec<TokenLogprob>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenLogprob {
+    logprob: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct ResponseMessage {
+    content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OpenRouterError {
+    message: String,
+    #[serde(rename = "type")]
+    error_type: Option<String>,
+    code: Option<serde_json::Value>,
+}
+
+struct OpenAiCompatible {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+    report_cost: bool,
+}
+
+impl Provider for OpenAiCompatible {
+    fn transcribe<'a>(&'a self, request: &'a PageRequest) -> BoxFuture<'a, Result<Transcription, ApiError>> {
+        Box::pin(async move {
+            // Images are sent inline as data URLs
+            let body = ChatCompletionRequest {
+                model: request.model.clone(),
+                messages: vec![Message {
+                    role: "user".to_string(),
+                    content: vec![
+                        ContentPart::Text { text: request.prompt.clone() },
+                        ContentPart::ImageUrl {
+                            image_url: ImageUrlData {
+                                url: format!("data:{};base64,{}", request.mime_type, request.image_base64),
+                            },
+                        },
+                    ],
+                }],
+                usage: self.report_cost.then_some(UsageRequest { include: true }),
+                response_format: request.response_schema.clone().map(|schema| ResponseFormat {
+                    format_type: "json_schema".to_string(),
+                    json_schema: JsonSchemaFormat { name: "page".to_string(), strict: true, schema },
+                }),
+                logprobs: request.logprobs.then_some(true),
+            };
+
+            let mut builder = self.client.post(format!("{}/chat/completions", self.base_url)).json(&body);
+            if let Some(key) = &self.api_key {
+                builder = builder.header("Authorization", format!("Bearer {}", key));
+            }
+
+            let result: ChatCompletionResponse = send_json(builder).await?;
+            if let Some(err) = &result.error {
+                return Err(ApiError::from_body(err));
+            }
+
+            let usage = result.usage
+                .map(|u| Usage { prompt_tokens: u.prompt_tokens, completion_tokens: u.completion_tokens, cost: u.cost })
+                .unwrap_or_default();
+
+            // An empty choice list is usually a provider hiccup rather than a real answer
+            let choice = result.choices
+                .and_then(|c| c.into_iter().next())
+                .ok_or_else(|| empty_response(FinishReason::Unknown))?;
+            let finish_reason = FinishReason::parse(choice.finish_reason.as_deref());
+            let logprob = choice.logprobs
+                .and_then(|l| l.content)
+                .filter(|tokens| !tokens.is_empty())
+                .map(|tokens| tokens.iter().map(|t| t.logprob).sum::<f64>() / tokens.len() as f64);
+            let text = choice.message
+                .and_then(|m| m.content)
+                .filter(|t| !t.is_empty())
+                .ok_or_else(|| empty_response(finish_reason))?;
+            Ok(Transcription { text, usage, finish_reason, logprob })
+        })
+    }
+}
+
+// --- Anthropic Messages API ---
+
+#[derive(Serialize)]
+struct AnthropicRequest {
+    model: String,
+    max_tokens: u32,
+    messages: Vec<AnthropicMessage>,
+}
+
+#[derive(Serialize)]
+struct AnthropicMessage {
+    role: String,
+    content: Vec<AnthropicContent>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicContent {
+    Text { text: String },
+    Image { source: AnthropicImageSource },
+}
+
+#[derive(Serialize)]
+struct AnthropicImageSource {
+    #[serde(rename = "type")]
+    source_type: String,
+    media_type: String,
+    data: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicResponse {
+    #[serde(default)]
+    content: Vec<AnthropicResponseBlock>,
+    usage: Option<AnthropicUsage>,
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicResponseBlock {
+    text: Option<String>,
+}
+
+struct Anthropic {
+    client: Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl Provider for Anthropic {
+    fn transcribe<'a>(&'a self, request: &'a PageRequest) -> BoxFuture<'a, Result<Transcription, ApiError>> {
+        Box::pin(async move {
+            let body = AnthropicRequest {
+                model: request.model.clone(),
+                max_tokens: MAX_OUTPUT_TOKENS,
+                messages: vec![AnthropicMessage {
+                    role: "user".to_string(),
+                    content: vec![
+                        AnthropicContent::Image {
+                            source: AnthropicImageSource {
+                                source_type: "base64".to_string(),
+                                media_type: request.mime_type.clone(),
+                                data: request.image_base64.clone(),
+                            },
+                        },
+                        AnthropicContent::Text { text: request.prompt.clone() },
+                    ],
+                }],
+            };
+
+            let builder = self.client
+                .post(format!("{}/messages", self.base_url))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", "2023-06-01")
+                .json(&body);
+
+            let result: AnthropicResponse = send_json(builder).await?;
+            let usage = result.usage
+                .map(|u| Usage { prompt_tokens: u.input_tokens, completion_tokens: u.output_tokens, cost: None })
+                .unwrap_or_default();
+            let finish_reason = FinishReason::parse(result.stop_reason.as_deref());
+            let text: String = result.content.into_iter().filter_map(|b| b.text).collect();
+            if text.is_empty() {
+                return Err(empty_response(finish_reason));
+            }
+            Ok(Transcription { text, usage, finish_reason, logprob: None })
+        })
+    }
+}
+
+// --- Gemini generateContent API ---
+
+#[derive(Serialize)]
+struct GeminiRequest {
+    contents: Vec<GeminiContent>,
+    #[serde(rename = "generationConfig", skip_serializing_if = "Option::is_none")]
+    generation_config: Option<GeminiGenerationConfig>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiGenerationConfig {
+    response_mime_type: String,
+    response_json_schema: serde_json::Value,
+}
+
+#[derive(Serialize)]
+struct GeminiContent {
+    parts: Vec<GeminiPart>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum GeminiPart {
+    Text(String),
+    InlineData { mime_type: String, data: String },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiResponse {
+    candidates: Option<Vec<GeminiCandidate>>,
+    prompt_feedback: Option<GeminiPromptFeedback>,
+    usage_metadata: Option<GeminiUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiUsage {
+    #[serde(default)]
+    prompt_token_count: u64,
+    #[serde(default)]
+    candidates_token_count: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiCandidate {
+    content: Option<GeminiResponseContent>,
+    finish_reason: Option<String>,
+    /// Mean token log probability, which Gemini reports without being asked
+    avg_logprobs: Option<f64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GeminiResponseContent {
+    #[serde(default)]
+    parts: Vec<GeminiResponsePart>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GeminiResponsePart {
+    text: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GeminiPromptFeedback {
+    block_reason: Option<String>,
+}
+
+struct Gemini {
+    client: Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl Provider for Gemini {
+    fn transcribe<'a>(&'a self, request: &'a PageRequest) -> BoxFuture<'a, Result<Transcription, ApiError>> {
+        Box::pin(async move {
+            let body = GeminiRequest {
+                contents: vec![GeminiContent {
+                    parts: vec![
+                        GeminiPart::Text(request.prompt.clone()),
+                        GeminiPart::InlineData {
+                            mime_type: request.mime_type.clone(),
+                            data: request.image_base64.clone(),
+                        },
+                    ],
+                }],
+                generation_config: request.response_schema.clone().map(|schema| GeminiGenerationConfig {
+                    response_mime_type: "application/json".to_string(),
+                    response_json_schema: schema,
+                }),
+            };
+
+            // Model IDs may be given as "models/gemini-..." or bare
+            let model = request.model.trim_start_matches("models/");
+            let builder = self.client
+                .post(format!("{}/models/{}:generateContent", self.base_url, model))
+                .header("x-goog-api-key", &self.api_key)
+                .json(&body);
+
+            let result: GeminiResponse = send_json(builder).await?;
+            if let Some(reason) = result.prompt_feedback.and_then(|f| f.block_reason) {
+                return Err(ApiError::Fatal(format!("Blocked by provider: {}", reason)));
+            }
+
+            let usage = result.usage_metadata
+                .map(|u| Usage { prompt_tokens: u.prompt_token_count, completion_tokens: u.candidates_token_count, cost: None })
+                .unwrap_or_default();
+            let candidate = result.candidates.and_then(|c| c.into_iter().next());
+            let finish_reason = FinishReason::parse(candidate.as_ref().and_then(|c| c.finish_reason.as_deref()));
+            let logprob = candidate.as_ref().and_then(|c| c.avg_logprobs);
+            let text: String = candidate
+                .and_then(|c| c.content)
+                .map(|c| c.parts.into_iter().filter_map(|p| p.text).collect())
+                .unwrap_or_default();
+            if text.is_empty() {
+                return Err(empty_response(finish_reason));
+            }
+            Ok(Transcription { text, usage, finish_reason, logprob })
+        })
+    }
+}
+
+// --- Ollama native chat API ---
+
+#[derive(Serialize)]
+struct OllamaRequest {
+    model: String,
+    stream: bool,
+    messages: Vec<OllamaMessage>,
+    /// JSON schema constraining the output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct OllamaMessage {
+    role: String,
+    content: String,
+    images: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaResponse {
+    message: Option<OllamaResponseMessage>,
+    error: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: u64,
+    #[serde(default)]
+    eval_count: u64,
+    done_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaResponseMessage {
+    content: String,
+}
+
+struct Ollama {
+    client: Client,
+    base_url: String,
+}
+
+impl Provider for Ollama {
+    fn transcribe<'a>(&'a self, request: &'a PageRequest) -> BoxFuture<'a, Result<Transcription, ApiError>> {
+        Box::pin(async move {
+            let body = OllamaRequest {
+                model: request.model.clone(),
+                stream: false,
+                messages: vec![OllamaMessage {
+                    role: "user".to_string(),
+                    content: request.prompt.clone(),
+                    images: vec![request.image_base64.clone()],
+                }],
+                format: request.response_schema.clone(),
+            };
+
+            let builder = self.client.post(format!("{}/api/chat", self.base_url)).json(&body);
+            let result: OllamaResponse = send_json(builder).await?;
+            if let Some(err) = result.error {
+                return Err(ApiError::Fatal(format!("API Error: {}", err)));
+            }
+
+            // Local models are free; report zero cost rather than unknown
+            let usage = Usage {
+                prompt_tokens: result.prompt_eval_count,
+                completion_tokens: result.eval_count,
+                cost: Some(0.0),
+            };
+            let finish_reason = FinishReason::parse(result.done_reason.as_deref());
+            let text = result.message
+                .map(|m| m.content)
+                .filter(|t| !t.is_empty())
+                .ok_or_else(|| empty_response(finish_reason))?;
+            Ok(Transcription { text, usage, finish_reason, logprob: None })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    fn is_retryable(err: &ApiError) -> bool {
+        matches!(err, ApiError::Retryable { .. })
+    }
+
+    #[test]
+    fn finish_reasons_from_every_api() {
+        for (reason, expected) in [
+            (Some("stop"), FinishReason::Complete),
+            (Some("end_turn"), FinishReason::Complete),
+            (Some("STOP"), FinishReason::Complete),
+            (Some("stop_sequence"), FinishReason::Complete),
+            (Some("length"), FinishReason::Truncated),
+            (Some("max_tokens"), FinishReason::Truncated),
+            (Some("MAX_TOKENS"), FinishReason::Truncated),
+            (Some("content_filter"), FinishReason::Filtered),
+            (Some("refusal"), FinishReason::Filtered),
+            (Some("SAFETY"), FinishReason::Filtered),
+            (Some("RECITATION"), FinishReason::Filtered),
+            (Some("PROHIBITED_CONTENT"), FinishReason::Filtered),
+            (Some("tool_calls"), FinishReason::Unknown),
+            (Some(""), FinishReason::Unknown),
+            (None, FinishReason::Unknown),
+        ] {
+            assert_eq!(FinishReason::parse(reason), expected, "{:?}", reason);
+        }
+    }
+
+    #[test]
+    fn classifies_http_statuses() {
+        let wait = Some(Duration::from_secs(7));
+        for (status, retryable) in [
+            (StatusCode::TOO_MANY_REQUESTS, true),
+            (StatusCode::REQUEST_TIMEOUT, true),
+            (StatusCode::INTERNAL_SERVER_ERROR, true),
+            (StatusCode::BAD_GATEWAY, true),
+            (StatusCode::SERVICE_UNAVAILABLE, true),
+            (StatusCode::BAD_REQUEST, false),
+            (StatusCode::UNAUTHORIZED, false),
+            (StatusCode::PAYMENT_REQUIRED, false),
+            (StatusCode::NOT_FOUND, false),
+        ] {
+            let err = ApiError::from_status(status, wait, " body \n");
+            assert_eq!(is_retryable(&err), retryable, "{}", status);
+            assert_eq!(err.to_string(), format!("API Error ({}): body", status));
+            if let ApiError::Retryable { retry_after, .. } = err {
+                assert_eq!(retry_after, wait);
+            }
+        }
+    }
+
+    #[test]
+    fn classifies_errors_in_the_body() {
+        for (json, retryable) in [
+            (r#"{"message": "Rate limited", "code": 429}"#, true),
+            (r#"{"message": "Upstream error", "code": 502}"#, true),
+            (r#"{"message": "Timed out", "code": 408}"#, true),
+            (r#"{"message": "Try again", "type": "overloaded_error"}"#, true),
+            (r#"{"message": "Model is Overloaded right now"}"#, true),
+            (r#"{"message": "Invalid model", "code": 400}"#, false),
+            (r#"{"message": "Insufficient credits", "code": 402}"#, false),
+            (r#"{"message": "Bad key", "type": "authentication_error", "code": "invalid_api_key"}"#, false),
+            (r#"{"message": "Something"}"#, false),
+        ] {
+            let err: OpenRouterError = serde_json::from_str(json).unwrap();
+            assert_eq!(is_retryable(&ApiError::from_body(&err)), retryable, "{}", json);
+        }
+    }
+
+    #[test]
+    fn only_unexplained_empty_responses_are_retried() {
+        assert!(is_retryable(&empty_response(FinishReason::Complete)));
+        assert!(is_retryable(&empty_response(FinishReason::Unknown)));
+        assert!(!is_retryable(&empty_response(FinishReason::Filtered)));
+        assert!(!is_retryable(&empty_response(FinishReason::Truncated)));
+    }
+
+    #[test]
+    fn reads_retry_after_seconds() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+        headers.insert(reqwest::header::RETRY_AFTER, " 30 ".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(30)));
+        // HTTP dates aren't supported; the backoff applies instead
+        headers.insert(reqwest::header::RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+}
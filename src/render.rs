@@ -0,0 +1,525 @@
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag, TagEnd};
+use regex::{Captures, Regex};
+use std::path::PathBuf;
+
+use crate::combine::{block_options, is_atx, parse_headings};
+
+/// Title page details for the e-book and HTML renderers
+#[derive(Debug, Clone)]
+pub struct BookMetadata {
+    pub title: String,
+    pub author: Option<String>,
+    pub language: String,
+    /// Image shown as the cover (the first page scan)
+    pub cover: Option<PathBuf>,
+}
+
+/// A slice of the combined book starting at a top-level heading
+#[derive(Debug, Clone)]
+pub struct Chapter {
+    pub title: String,
+    pub markdown: String,
+    /// Text before the first top-level heading
+    pub front_matter: bool,
+}
+
+/// Split the combined Markdown at its top-level headings (the shallowest level of the ATX
+/// headings outside block quotes and lists). Page anchors and page rules directly above a
+/// chapter heading move into that chapter, so `page_N` links land on the chapter's first page.
+/// Text before the first heading becomes a chapter titled `front_title`, unless it holds
+/// nothing but anchors.
+pub fn split_chapters(markdown: &str, front_title: &str) -> Vec<Chapter> {
+    let anchor = Regex::new(r"^\s*<a id='page_\d+'></a>\s*$").expect("valid regex");
+
+    let lines: Vec<&str> = markdown.lines().collect();
+    // Splitting inside a block quote or list would change how the rest of it parses
+    let starts: Vec<(usize, usize, String)> = parse_headings(markdown)
+        .into_iter()
+        .filter(|h| h.atx && !h.nested)
+        .map(|h| (markdown[..h.start].matches('\n').count(), h.level, h.text))
+        .collect();
+    let Some(top) = starts.iter().map(|(_, level, _)| *level).min() else {
+        return vec![Chapter { title: front_title.to_string(), markdown: markdown.to_string(), front_matter: true }];
+    };
+
+    // Each chapter is its carried-over anchors plus the lines from `start` up to the next split
+    let mut chapters = Vec::new();
+    let mut current = Chapter { title: front_title.to_string(), markdown: String::new(), front_matter: true };
+    let mut start = 0;
+    for (i, _, title) in starts.into_iter().filter(|(_, level, _)| *level == top) {
+        // Walk back over the blank lines, rules and anchors that lead into this heading
+        let mut lead = i;
+        while lead > start && {
+            let l = lines[lead - 1].trim();
+            l.is_empty() || l == "---" || anchor.is_match(l)
+        } {
+            lead -= 1;
+        }
+        current.markdown.push_str(&lines[start..lead].join("\n"));
+        chapters.push(current);
+
+        let anchors: Vec<&str> = lines[lead..i].iter().copied().filter(|l| anchor.is_match(l)).collect();
+        current = Chapter { title, markdown: String::new(), front_matter: false };
+        if !anchors.is_empty() {
+            current.markdown = anchors.join("\n") + "\n\n";
+        }
+        start = i;
+    }
+    // The final page rule closes nothing
+    let mut end = lines.len();
+    while end > start && matches!(lines[end - 1].trim(), "" | "---") {
+        end -= 1;
+    }
+    current.markdown.push_str(&lines[start..end].join("\n"));
+    chapters.push(current);
+
+    // Front matter with no text of its own just hands its anchors to the first chapter
+    let front_is_empty = chapters[0]
+        .markdown
+        .lines()
+        .all(|l| l.trim().is_empty() || l.trim() == "---" || anchor.is_match(l));
+    if front_is_empty {
+        let front = chapters.remove(0);
+        if let Some(first) = chapters.first_mut() {
+            let anchors: Vec<&str> = front.markdown.lines().filter(|l| anchor.is_match(l)).collect();
+            if !anchors.is_empty() {
+                first.markdown = anchors.join("\n") + "\n\n" + &first.markdown;
+            }
+        }
+    }
+    chapters
+}
+
+/// The readable text of a Markdown page, one line per line of prose, heading, list item,
+/// table row or code line. Markup and raw HTML are dropped; math keeps its LaTeX source.
+pub fn plain_text(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_MATH;
+    let mut text = String::new();
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Text(t) | Event::Code(t) | Event::InlineMath(t) | Event::DisplayMath(t) => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::End(TagEnd::TableCell) => text.push(' '),
+            Event::End(
+                TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item | TagEnd::TableHead | TagEnd::TableRow
+                | TagEnd::CodeBlock,
+            ) => text.push('\n'),
+            _ => {}
+        }
+    }
+    text
+}
+
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// HTML named character references models use, as code points. XML only predefines `amp`, `lt`,
+/// `gt`, `quot` and `apos`, so the rest become numeric references.
+const HTML_ENTITIES: &[(&str, u32)] = &[
+    ("nbsp", 160), ("iexcl", 161), ("cent", 162), ("pound", 163), ("curren", 164), ("yen", 165),
+    ("brvbar", 166), ("sect", 167), ("uml", 168), ("copy", 169), ("ordf", 170), ("laquo", 171),
+    ("not", 172), ("shy", 173), ("reg", 174), ("macr", 175), ("deg", 176), ("plusmn", 177),
+    ("sup2", 178), ("sup3", 179), ("acute", 180), ("micro", 181), ("para", 182), ("middot", 183),
+    ("cedil", 184), ("sup1", 185), ("ordm", 186), ("raquo", 187), ("frac14", 188), ("frac12", 189),
+    ("frac34", 190), ("iquest", 191), ("Agrave", 192), ("Aacute", 193), ("Acirc", 194), ("Atilde", 195),
+    ("Auml", 196), ("Aring", 197), ("AElig", 198), ("Ccedil", 199), ("Egrave", 200), ("Eacute", 201),
+    ("Ecirc", 202), ("Euml", 203), ("Igrave", 204), ("Iacute", 205), ("Icirc", 206), ("Iuml", 207),
+    ("ETH", 208), ("Ntilde", 209), ("Ograve", 210), ("Oacute", 211), ("Ocirc", 212), ("Otilde", 213),
+    ("Ouml", 214), ("times", 215), ("Oslash", 216), ("Ugrave", 217), ("Uacute", 218), ("Ucirc", 219),
+    ("Uuml", 220), ("Yacute", 221), ("THORN", 222), ("szlig", 223), ("agrave", 224), ("aacute", 225),
+    ("acirc", 226), ("atilde", 227), ("auml", 228), ("aring", 229), ("aelig", 230), ("ccedil", 231),
+    ("egrave", 232), ("eacute", 233), ("ecirc", 234), ("euml", 235), ("igrave", 236), ("iacute", 237),
+    ("icirc", 238), ("iuml", 239), ("eth", 240), ("ntilde", 241), ("ograve", 242), ("oacute", 243),
+    ("ocirc", 244), ("otilde", 245), ("ouml", 246), ("divide", 247), ("oslash", 248), ("ugrave", 249),
+    ("uacute", 250), ("ucirc", 251), ("uuml", 252), ("yacute", 253), ("thorn", 254), ("yuml", 255),
+    ("OElig", 338), ("oelig", 339), ("Scaron", 352), ("scaron", 353), ("Yuml", 376), ("fnof", 402),
+    ("circ", 710), ("tilde", 732), ("Alpha", 913), ("Beta", 914), ("Gamma", 915), ("Delta", 916),
+    ("Epsilon", 917), ("Zeta", 918), ("Eta", 919), ("Theta", 920), ("Iota", 921), ("Kappa", 922),
+    ("Lambda", 923), ("Mu", 924), ("Nu", 925), ("Xi", 926), ("Omicron", 927), ("Pi", 928), ("Rho", 929),
+    ("Sigma", 931), ("Tau", 932), ("Upsilon", 933), ("Phi", 934), ("Chi", 935), ("Psi", 936),
+    ("Omega", 937), ("alpha", 945), ("beta", 946), ("gamma", 947), ("delta", 948), ("epsilon", 949),
+    ("zeta", 950), ("eta", 951), ("theta", 952), ("iota", 953), ("kappa", 954), ("lambda", 955),
+    ("mu", 956), ("nu", 957), ("xi", 958), ("omicron", 959), ("pi", 960), ("rho", 961), ("sigmaf", 962),
+    ("sigma", 963), ("tau", 964), ("upsilon", 965), ("phi", 966), ("chi", 967), ("psi", 968),
+    ("omega", 969), ("thetasym", 977), ("upsih", 978), ("piv", 982), ("ensp", 8194), ("emsp", 8195),
+    ("thinsp", 8201), ("zwnj", 8204), ("zwj", 8205), ("lrm", 8206), ("rlm", 8207), ("ndash", 8211),
+    ("mdash", 8212), ("lsquo", 8216), ("rsquo", 8217), ("sbquo", 8218), ("ldquo", 8220), ("rdquo", 8221),
+    ("bdquo", 8222), ("dagger", 8224), ("Dagger", 8225), ("bull", 8226), ("hellip", 8230), ("permil", 8240),
+    ("prime", 8242), ("Prime", 8243), ("lsaquo", 8249), ("rsaquo", 8250), ("oline", 8254), ("frasl", 8260),
+    ("euro", 8364), ("image", 8465), ("weierp", 8472), ("real", 8476), ("trade", 8482), ("alefsym", 8501),
+    ("larr", 8592), ("uarr", 8593), ("rarr", 8594), ("darr", 8595), ("harr", 8596), ("crarr", 8629),
+    ("lArr", 8656), ("uArr", 8657), ("rArr", 8658), ("dArr", 8659), ("hArr", 8660), ("forall", 8704),
+    ("part", 8706), ("exist", 8707), ("empty", 8709), ("nabla", 8711), ("isin", 8712), ("notin", 8713),
+    ("ni", 8715), ("prod", 8719), ("sum", 8721), ("minus", 8722), ("lowast", 8727), ("radic", 8730),
+    ("prop", 8733), ("infin", 8734), ("ang", 8736), ("and", 8743), ("or", 8744), ("cap", 8745),
+    ("cup", 8746), ("int", 8747), ("there4", 8756), ("sim", 8764), ("cong", 8773), ("asymp", 8776),
+    ("ne", 8800), ("equiv", 8801), ("le", 8804), ("ge", 8805), ("sub", 8834), ("sup", 8835),
+    ("nsub", 8836), ("sube", 8838), ("supe", 8839), ("oplus", 8853), ("otimes", 8855), ("perp", 8869),
+    ("sdot", 8901), ("lceil", 8968), ("rceil", 8969), ("lfloor", 8970), ("rfloor", 8971), ("lang", 9001),
+    ("rang", 9002), ("loz", 9674), ("spades", 9824), ("clubs", 9827), ("hearts", 9829), ("diams", 9830),
+];
+
+/// Elements without content, written self-closed
+const VOID_ELEMENTS: &[&str] =
+    &["area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track", "wbr"];
+
+/// Block elements that end an open `<p>`, as they do in HTML
+const CLOSES_PARAGRAPH: &[&str] = &[
+    "address", "blockquote", "div", "dl", "figure", "footer", "h1", "h2", "h3", "h4", "h5", "h6", "header",
+    "hr", "ol", "p", "pre", "section", "table", "ul",
+];
+
+/// Escape text for XML, keeping character references that XML understands and turning HTML's
+/// named ones into numeric references. Any other `&` is a literal ampersand.
+fn xml_text(text: &str) -> String {
+    let reference = Regex::new(r"&(#[0-9]{1,7};|#[xX][0-9a-fA-F]{1,6};|[A-Za-z][A-Za-z0-9]*;)?").expect("valid regex");
+    let text = reference.replace_all(text, |c: &Captures| {
+        let Some(name) = c.get(1).map(|m| m.as_str().trim_end_matches(';')) else {
+            return "&amp;".to_string();
+        };
+        if name.starts_with('#') || matches!(name, "amp" | "lt" | "gt" | "quot" | "apos") {
+            return c[0].to_string();
+        }
+        match HTML_ENTITIES.iter().find(|(entity, _)| *entity == name) {
+            Some((_, code)) => format!("&#{};", code),
+            None => format!("&amp;{};", name),
+        }
+    });
+    text.replace('<', "&lt;")
+}
+
+/// Raw HTML from the model, made well-formed XML piece by piece. Tags are kept balanced across
+/// the pieces: a stray end tag is dropped, and whatever is left open can be closed later.
+#[derive(Default)]
+struct HtmlRepair {
+    open: Vec<String>,
+}
+
+impl HtmlRepair {
+    fn repair(&mut self, html: &str) -> String {
+        let markup = Regex::new(
+            r#"(?s)<!--.*?-->|<!\[CDATA\[.*?\]\]>|<[!?][^>]*>|<(/?)([A-Za-z][A-Za-z0-9-]*)((?:[^>"']|"[^"]*"|'[^']*')*)>"#,
+        )
+        .expect("valid regex");
+        let mut out = String::with_capacity(html.len());
+        let mut last = 0;
+        for c in markup.captures_iter(html) {
+            let whole = c.get(0).expect("match");
+            out.push_str(&xml_text(&html[last..whole.start()]));
+            last = whole.end();
+            let Some(name) = c.get(2) else {
+                // Comments and declarations pass through as they are
+                out.push_str(whole.as_str());
+                continue;
+            };
+            let name = name.as_str().to_ascii_lowercase();
+            if !c[1].is_empty() {
+                out.push_str(&self.close(&name));
+                continue;
+            }
+            let attrs = &c[3];
+            let self_closed = attrs.trim_end().ends_with('/');
+            out.push_str(&self.implied_ends(&name));
+            out.push_str(&format!("<{}{}", name, xml_attributes(attrs.trim_end().trim_end_matches('/'))));
+            if self_closed || VOID_ELEMENTS.contains(&name.as_str()) {
+                out.push_str("/>");
+            } else {
+                out.push('>');
+                self.open.push(name);
+            }
+        }
+        out.push_str(&xml_text(&html[last..]));
+        out
+    }
+
+    /// End tags HTML would infer before a `name` start tag: a new paragraph, list item or
+    /// table cell ends the one before it
+    fn implied_ends(&mut self, name: &str) -> String {
+        let Some(top) = self.open.last() else { return String::new() };
+        let ends = match name {
+            "li" => top == "li",
+            "dt" | "dd" => top == "dt" || top == "dd",
+            "tr" => top == "tr" || top == "td" || top == "th",
+            "td" | "th" => top == "td" || top == "th",
+            _ => top == "p" && CLOSES_PARAGRAPH.contains(&name),
+        };
+        if !ends {
+            return String::new();
+        }
+        let mut out = String::new();
+        while let Some(top) = self.open.last() {
+            let closes = match name {
+                "tr" => matches!(top.as_str(), "tr" | "td" | "th"),
+                _ => out.is_empty(),
+            };
+            if !closes {
+                break;
+            }
+            out.push_str(&format!("</{}>", self.open.pop().expect("open element")));
+        }
+        out
+    }
+
+    /// The end tag for `name`, closing any elements left open inside it; nothing if it isn't open
+    fn close(&mut self, name: &str) -> String {
+        let Some(pos) = self.open.iter().rposition(|open| open == name) else {
+            return String::new();
+        };
+        self.open.drain(pos..).rev().map(|open| format!("</{}>", open)).collect()
+    }
+
+    /// End tags for every element still open
+    fn close_all(&mut self) -> String {
+        self.open.drain(..).rev().map(|open| format!("</{}>", open)).collect()
+    }
+}
+
+/// Attributes with lowercase names and double-quoted, escaped values; a bare attribute such as
+/// `disabled` gets its own name as its value
+fn xml_attributes(attrs: &str) -> String {
+    let attribute =
+        Regex::new(r#"([^\s"'=<>/]+)(?:\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'=<>`]+)))?"#).expect("valid regex");
+    let mut out = String::new();
+    let mut seen = Vec::new();
+    for c in attribute.captures_iter(attrs) {
+        let name = c[1].to_ascii_lowercase();
+        // A repeated attribute is an XML error; HTML keeps the first
+        if seen.contains(&name) {
+            continue;
+        }
+        let value = c.get(2).or(c.get(3)).or(c.get(4)).map_or(name.as_str(), |m| m.as_str());
+        out.push_str(&format!(" {}=\"{}\"", name, xml_text(value).replace('"', "&quot;")));
+        seen.push(name);
+    }
+    out
+}
+
+/// Make a complete piece of raw HTML from the model well-formed XML: balanced tags, self-closed
+/// void elements, quoted attributes, and only the character references XML defines
+fn xhtml_fragment(html: &str) -> String {
+    let mut repair = HtmlRepair::default();
+    let mut out = repair.repair(html);
+    out.push_str(&repair.close_all());
+    out
+}
+
+/// Parse Markdown for rendering. ATX headings take their IDs from `slugs` in order; they are
+/// found with the same parser and block options as `collect_headings`, so TOC links built from
+/// its headings resolve. Raw HTML is made well-formed: an HTML block as a whole, and inline HTML
+/// within the block holding it. Returns the events and every ID they define.
+pub fn parse_markdown<'a>(
+    markdown: &'a str,
+    options: Options,
+    slugs: &mut impl Iterator<Item = String>,
+) -> (Vec<Event<'a>>, Vec<String>) {
+    let id_attr = Regex::new(r#"\bid=['"]([^'"]+)['"]"#).expect("valid regex");
+
+    let mut ids = Vec::new();
+    let mut events = Vec::new();
+    // An HTML block arrives a line at a time
+    let mut html_block: Option<String> = None;
+    let mut inline = HtmlRepair::default();
+    for (event, range) in Parser::new_ext(markdown, options | block_options()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading { level, classes, attrs, .. }) if is_atx(&markdown[range]) => {
+                let id = slugs.next().map(|slug| {
+                    ids.push(slug.clone());
+                    CowStr::from(slug)
+                });
+                events.push(Event::Start(Tag::Heading { level, id, classes, attrs }));
+            }
+            Event::Start(Tag::HtmlBlock) => {
+                html_block = Some(String::new());
+                events.push(Event::Start(Tag::HtmlBlock));
+            }
+            Event::Html(raw) => {
+                ids.extend(id_attr.captures_iter(&raw).map(|c| c[1].to_string()));
+                match &mut html_block {
+                    Some(block) => block.push_str(&raw),
+                    None => events.push(Event::Html(CowStr::from(xhtml_fragment(&raw)))),
+                }
+            }
+            Event::End(TagEnd::HtmlBlock) => {
+                if let Some(block) = html_block.take() {
+                    events.push(Event::Html(CowStr::from(xhtml_fragment(&block))));
+                }
+                events.push(Event::End(TagEnd::HtmlBlock));
+            }
+            Event::InlineHtml(raw) => {
+                ids.extend(id_attr.captures_iter(&raw).map(|c| c[1].to_string()));
+                events.push(Event::InlineHtml(CowStr::from(inline.repair(&raw))));
+            }
+            Event::End(
+                end @ (TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::Item
+                | TagEnd::TableCell
+                | TagEnd::BlockQuote(_)
+                | TagEnd::FootnoteDefinition),
+            ) => {
+                // Inline HTML left open can't run past the block it is in
+                let closing = inline.close_all();
+                if !closing.is_empty() {
+                    events.push(Event::InlineHtml(CowStr::from(closing)));
+                }
+                events.push(Event::End(end));
+            }
+            event => events.push(event),
+        }
+    }
+    (events, ids)
+}
+
+/// Render Markdown to an XHTML body fragment, with heading IDs as in `parse_markdown`
+pub fn markdown_to_xhtml(markdown: &str, slugs: &mut impl Iterator<Item = String>) -> (String, Vec<String>) {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_FOOTNOTES;
+    let (events, ids) = parse_markdown(markdown, options, slugs);
+    let mut body = String::new();
+    html::push_html(&mut body, events.into_iter());
+    (body, ids)
+}
+
+/// Nested `<tag>` lists for a table of contents of `(level, title, href)` entries. A level can
+/// only go one deeper than the entry before it, so every sublist sits inside an item.
+pub fn nested_list(tag: &str, entries: &[(usize, String, String)]) -> String {
+    let mut list = String::new();
+    let mut depth = 0;
+    for (level, title, href) in entries {
+        let level = (*level).clamp(1, depth + 1);
+        if level > depth {
+            list.push_str(&format!("\n<{}>", tag));
+            depth = level;
+        } else {
+            list.push_str("</li>");
+            while depth > level {
+                list.push_str(&format!("\n</{}></li>", tag));
+                depth -= 1;
+            }
+        }
+        list.push_str(&format!("\n<li><a href=\"{}\">{}</a>", escape_xml(href), escape_xml(title)));
+    }
+    if depth > 0 {
+        list.push_str("</li>");
+        while depth > 1 {
+            list.push_str(&format!("\n</{}></li>", tag));
+            depth -= 1;
+        }
+        list.push_str(&format!("\n</{}>", tag));
+    }
+    list
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::combine::{collect_headings, join_pages, AnchorStyle, Slugger};
+
+    /// Panic unless `xml` is well-formed: balanced tags, quoted attributes, and only the
+    /// character references XML defines
+    pub(crate) fn assert_well_formed(xml: &str) {
+        let markup = Regex::new(r"(?s)<!--.*?-->|<[!?][^>]*>|<[^>]*>").unwrap();
+        let start = Regex::new(r#"^<([A-Za-z][\w:.-]*)(?:\s+[\w:.-]+="[^"<]*")*\s*(/?)>$"#).unwrap();
+        let end = Regex::new(r"^</([A-Za-z][\w:.-]*)\s*>$").unwrap();
+        let reference = Regex::new(r"&(?:amp|lt|gt|quot|apos|#[0-9]+|#x[0-9a-fA-F]+);").unwrap();
+        assert!(!reference.replace_all(xml, "").contains('&'), "stray & in {xml}");
+        let mut open = Vec::new();
+        let mut last = 0;
+        for tag in markup.find_iter(xml) {
+            assert!(!xml[last..tag.start()].contains('<'), "stray < in {xml}");
+            last = tag.end();
+            let tag = tag.as_str();
+            if tag.starts_with("<!") || tag.starts_with("<?") {
+                continue;
+            }
+            if let Some(c) = end.captures(tag) {
+                assert_eq!(open.pop().as_deref(), Some(&c[1]), "unbalanced {tag} in {xml}");
+            } else if let Some(c) = start.captures(tag) {
+                if c[2].is_empty() {
+                    open.push(c[1].to_string());
+                }
+            } else {
+                panic!("malformed tag {tag} in {xml}");
+            }
+        }
+        assert!(open.is_empty(), "unclosed {open:?} in {xml}");
+    }
+
+    #[test]
+    fn named_entities_become_numeric_references() {
+        assert_eq!(
+            xhtml_fragment("<p>A&nbsp;B &mdash; &copy; 2024 &amp; &#8212; &#x2014; &lt;tag&gt;</p>"),
+            "<p>A&#160;B &#8212; &#169; 2024 &amp; &#8212; &#x2014; &lt;tag&gt;</p>"
+        );
+    }
+
+    #[test]
+    fn bare_ampersands_and_angle_brackets_are_escaped() {
+        assert_eq!(xhtml_fragment("<p>Smith & Sons</p>"), "<p>Smith &amp; Sons</p>");
+        assert_eq!(xhtml_fragment("<p>&unknown; & x < y</p>"), "<p>&amp;unknown; &amp; x &lt; y</p>");
+        assert_eq!(xhtml_fragment("<a href=\"?a=1&b=2\">x</a>"), "<a href=\"?a=1&amp;b=2\">x</a>");
+    }
+
+    #[test]
+    fn void_elements_and_attributes_are_xml() {
+        assert_eq!(
+            xhtml_fragment("<BR><img SRC=a.png alt='a \"b\"'><input disabled><hr/>"),
+            "<br/><img src=\"a.png\" alt=\"a &quot;b&quot;\"/><input disabled=\"disabled\"/><hr/>"
+        );
+    }
+
+    #[test]
+    fn unclosed_elements_are_closed() {
+        assert_eq!(xhtml_fragment("<p>One<p>Two"), "<p>One</p><p>Two</p>");
+        assert_eq!(xhtml_fragment("<ul><li>a<li>b</ul>"), "<ul><li>a</li><li>b</li></ul>");
+        assert_eq!(
+            xhtml_fragment("<table><tr><td>1<td>2<tr><td>3</table>"),
+            "<table><tr><td>1</td><td>2</td></tr><tr><td>3</td></tr></table>"
+        );
+        assert_eq!(xhtml_fragment("<p>Text <b>bold</p>"), "<p>Text <b>bold</b></p>");
+        assert_eq!(xhtml_fragment("<div>x</span></div>"), "<div>x</div>");
+    }
+
+    #[test]
+    fn comments_pass_through() {
+        assert_eq!(xhtml_fragment("<!-- a & b --><p>x</p>"), "<!-- a & b --><p>x</p>");
+    }
+
+    #[test]
+    fn raw_html_in_markdown_renders_well_formed() {
+        let markdown = "Fish &amp; chips & peas &mdash; <b>bold\n\nNext <i>para</i></b>\n\n\
+                        <div>\n<p>Block &copy;\n<p>Second\n</div>\n\n- item <span>open\n- two";
+        let (body, _) = markdown_to_xhtml(markdown, &mut std::iter::empty());
+        assert_well_formed(&body);
+        assert!(body.contains("peas — <b>bold</b></p>"));
+        assert!(body.contains("<div>\n<p>Block &#169;\n</p><p>Second\n</p></div>"));
+    }
+
+    #[test]
+    fn heading_ids_follow_the_collected_headings() {
+        let pages = vec![
+            (1, "> # Quoted\n\nBody text.".to_string()),
+            (2, "# Normal\n\n   # Indented\n\n- # Listed".to_string()),
+        ];
+        let headings = collect_headings(&pages, &mut Slugger::new(AnchorStyle::Github));
+        let (markdown, _) = join_pages(&pages);
+        let (body, ids) = markdown_to_xhtml(&markdown, &mut headings.iter().map(|h| h.slug.clone()));
+        let heading_ids: Vec<&str> = ids.iter().map(String::as_str).filter(|id| !id.starts_with("page_")).collect();
+        assert_eq!(heading_ids, vec!["quoted", "normal", "indented", "listed"]);
+        assert!(body.contains("<h1 id=\"quoted\">Quoted</h1>"));
+        assert!(body.contains("<h1 id=\"normal\">Normal</h1>"));
+    }
+
+    #[test]
+    fn splits_chapters_at_top_level_headings_only() {
+        let markdown = "# One\n\nText.\n\n> # Quoted\n\n```\n# not a heading\n```\n\n# Two\n\nMore.";
+        let chapters = split_chapters(markdown, "Front");
+        let titles: Vec<&str> = chapters.iter().map(|c| c.title.as_str()).collect();
+        assert_eq!(titles, vec!["One", "Two"]);
+        assert!(chapters[0].markdown.contains("> # Quoted"));
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+/// What a block of the page is
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockType {
+    Heading,
+    Paragraph,
+    Table,
+    Code,
+    Figure,
+    Footnote,
+    Caption,
+}
+
+/// One block of a page. `text` is the block's content as Markdown (tables as Markdown
+/// tables, math as LaTeX) without the block-level markup: no `#` on headings, no fences on code.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    #[serde(rename = "type")]
+    pub kind: BlockType,
+    /// Position in reading order, starting at 1
+    pub order: u32,
+    /// Heading level 1-6; null for other blocks
+    pub level: Option<u8>,
+    pub text: String,
+}
+
+/// Structured transcription of a page (`page_NNNN.json`)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PageBlocks {
+    pub blocks: Vec<Block>,
+}
+
+/// JSON schema for `PageBlocks`, in the subset OpenAI's strict mode accepts
+/// (every property required, no additional properties)
+pub fn page_schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "blocks": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "type": {
+                            "type": "string",
+                            "enum": ["heading", "paragraph", "table", "code", "figure", "footnote", "caption"]
+                        },
+                        "order": { "type": "integer" },
+                        "level": { "type": ["integer", "null"] },
+                        "text": { "type": "string" }
+                    },
+                    "required": ["type", "order", "level", "text"],
+                    "additionalProperties": false
+                }
+            }
+        },
+        "required": ["blocks"],
+        "additionalProperties": false
+    })
+}
+
+/// Appended to the page prompt. The schema is spelled out for providers without native
+/// JSON-schema support (Anthropic), and overrides the template's Markdown instructions.
+pub fn structured_instructions() -> String {
+    format!(
+        "\n\nReturn the transcription as a single JSON object instead of a Markdown document, \
+        with no text before or after it. Split the page into blocks (heading, paragraph, table, code, \
+        figure, footnote, caption) numbered 1, 2, 3... by `order` in reading order. Each block's `text` holds its content \
+        as Markdown: tables as Markdown tables, equations as LaTeX, emphasis and links inline, but no `#` \
+        marks on headings and no fences around code. Give headings their level (1-6) in `level` and use null \
+        for every other block. For figures, transcribe any text inside the figure or describe it briefly. \
+        The object must match this JSON schema:\n{}",
+        page_schema()
+    )
+}
+
+impl PageBlocks {
+    /// Parse and check a model's structured response. Blocks come back sorted by `order`, which
+    /// must number them 1, 2, 3...: duplicate or skipped positions are rejected, since the reading
+    /// order would be ambiguous or a block may have been left out. An empty list is a blank page.
+    pub fn parse(text: &str) -> Result<PageBlocks> {
+        let text = text.trim();
+        // Some models fence the JSON even when asked not to
+        let text = match text.strip_prefix("```") {
+            Some(rest) => rest
+                .split_once('\n')
+                .map_or(rest, |(_, body)| body)
+                .trim_end()
+                .trim_end_matches("```"),
+            None => text,
+        };
+        let mut page: PageBlocks = serde_json::from_str(text).context("Response does not match the page schema")?;
+        page.blocks.sort_by_key(|b| b.order);
+        if let Some(pair) = page.blocks.windows(2).find(|pair| pair[0].order == pair[1].order) {
+            anyhow::bail!("Two blocks share reading order {}", pair[0].order);
+        }
+        if let Some((i, block)) = page.blocks.iter().enumerate().find(|(i, b)| b.order as usize != i + 1) {
+            anyhow::bail!("Block {} in reading order is numbered {}", i + 1, block.order);
+        }
+        page.blocks.retain(|b| !b.text.trim().is_empty());
+        Ok(page)
+    }
+
+    /// Paragraph blocks from plain text such as a PDF text layer, one per blank-line-separated block
+    pub fn from_plain_text(text: &str) -> PageBlocks {
+        let blocks = text
+            .split("\n\n")
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .enumerate()
+            .map(|(i, t)| Block { kind: BlockType::Paragraph, order: i as u32 + 1, level: None, text: t.to_string() })
+            .collect();
+        PageBlocks { blocks }
+    }
+
+    /// The page as Markdown, in the same shape the plain Markdown mode produces
+    pub fn to_markdown(&self) -> String {
+        let parts: Vec<String> = self
+            .blocks
+            .iter()
+            .map(|b| {
+                let text = b.text.trim();
+                match b.kind {
+                    BlockType::Heading => {
+                        format!("{} {}", "#".repeat(b.level.unwrap_or(2).clamp(1, 6) as usize), text)
+                    }
+                    BlockType::Code if !text.starts_with("```") => format!("```\n{}\n```", text),
+                    BlockType::Figure => format!("[Figure: {}]", text),
+                    _ => text.to_string(),
+                }
+            })
+            .collect();
+        parts.join("\n\n")
+    }
+
+    pub fn write(&self, output_dir: &Path, file_stem: &str) -> Result<()> {
+        use std::io::Write;
+        let mut tmp_file = NamedTempFile::new_in(output_dir)?;
+        tmp_file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        tmp_file.persist(output_dir.join(format!("{}.json", file_stem)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(kind: BlockType, order: u32, level: Option<u8>, text: &str) -> Block {
+        Block { kind, order, level, text: text.to_string() }
+    }
+
+    fn orders(page: &PageBlocks) -> Vec<u32> {
+        page.blocks.iter().map(|b| b.order).collect()
+    }
+
+    #[test]
+    fn parses_blocks_in_reading_order() {
+        let page = PageBlocks::parse(
+            r#"{"blocks": [
+                {"type": "paragraph", "order": 2, "level": null, "text": "Body."},
+                {"type": "heading", "order": 1, "level": 1, "text": "Title"},
+                {"type": "footnote", "order": 3, "level": null, "text": "  "}
+            ]}"#,
+        )
+        .unwrap();
+        // Sorted, and the blank block dropped
+        assert_eq!(orders(&page), vec![1, 2]);
+        assert_eq!(page.blocks[0].kind, BlockType::Heading);
+    }
+
+    #[test]
+    fn strips_code_fences_around_the_json() {
+        let json = r#"{"blocks": [{"type": "paragraph", "order": 1, "level": null, "text": "Body."}]}"#;
+        for text in [format!("```json\n{}\n```", json), format!("  ```\n{}\n```\n", json)] {
+            let page = PageBlocks::parse(&text).unwrap();
+            assert_eq!(page.blocks[0].text, "Body.");
+        }
+    }
+
+    #[test]
+    fn rejects_ambiguous_or_incomplete_reading_orders() {
+        let duplicate = r#"{"blocks": [
+            {"type": "paragraph", "order": 1, "level": null, "text": "One."},
+            {"type": "paragraph", "order": 1, "level": null, "text": "Also one."}
+        ]}"#;
+        assert!(PageBlocks::parse(duplicate).unwrap_err().to_string().contains("share reading order 1"));
+
+        let gap = r#"{"blocks": [
+            {"type": "paragraph", "order": 1, "level": null, "text": "One."},
+            {"type": "paragraph", "order": 3, "level": null, "text": "Three."}
+        ]}"#;
+        assert!(PageBlocks::parse(gap).unwrap_err().to_string().contains("Block 2 in reading order is numbered 3"));
+
+        let from_zero = r#"{"blocks": [{"type": "paragraph", "order": 0, "level": null, "text": "Zero."}]}"#;
+        assert!(PageBlocks::parse(from_zero).is_err());
+    }
+
+    #[test]
+    fn rejects_responses_off_the_schema() {
+        assert!(PageBlocks::parse("Just some Markdown.").is_err());
+        assert!(PageBlocks::parse(r#"{"blocks": [{"type": "sidebar", "order": 1, "level": null, "text": "x"}]}"#).is_err());
+        assert!(PageBlocks::parse(r#"{"blocks": [{"type": "paragraph", "order": 1, "level": null}]}"#).is_err());
+    }
+
+    #[test]
+    fn empty_block_list_is_a_blank_page() {
+        let page = PageBlocks::parse(r#"{"blocks": []}"#).unwrap();
+        assert!(page.blocks.is_empty());
+        assert_eq!(page.to_markdown(), "");
+    }
+
+    #[test]
+    fn renders_each_block_kind_as_markdown() {
+        let page = PageBlocks {
+            blocks: vec![
+                block(BlockType::Heading, 1, Some(2), "Section"),
+                block(BlockType::Heading, 2, None, "No level"),
+                block(BlockType::Heading, 3, Some(9), "Too deep"),
+                block(BlockType::Paragraph, 4, None, " Some *text*. "),
+                block(BlockType::Table, 5, None, "| a | b |\n|---|---|\n| 1 | 2 |"),
+                block(BlockType::Code, 6, None, "fn main() {}"),
+                block(BlockType::Code, 7, None, "```rust\nlet x = 1;\n```"),
+                block(BlockType::Figure, 8, None, "A map of the coast"),
+                block(BlockType::Footnote, 9, None, "[^1]: A note."),
+                block(BlockType::Caption, 10, None, "Figure 1. The coast."),
+            ],
+        };
+        let expected = [
+            "## Section",
+            "## No level",
+            "###### Too deep",
+            "Some *text*.",
+            "| a | b |\n|---|---|\n| 1 | 2 |",
+            "```\nfn main() {}\n```",
+            "```rust\nlet x = 1;\n```",
+            "[Figure: A map of the coast]",
+            "[^1]: A note.",
+            "Figure 1. The coast.",
+        ];
+        assert_eq!(page.to_markdown(), expected.join("\n\n"));
+    }
+
+    #[test]
+    fn plain_text_becomes_numbered_paragraphs() {
+        let page = PageBlocks::from_plain_text("First paragraph\nwrapped.\n\n\n\nSecond.\n\n  ");
+        assert_eq!(orders(&page), vec![1, 2]);
+        assert!(page.blocks.iter().all(|b| b.kind == BlockType::Paragraph && b.level.is_none()));
+        assert_eq!(page.blocks[0].text, "First paragraph\nwrapped.");
+        assert_eq!(page.to_markdown(), "First paragraph\nwrapped.\n\nSecond.");
+    }
+}
@@ -0,0 +1,272 @@
+use crate::provider::FinishReason;
+use std::collections::HashSet;
+
+/// Sanity checks applied to every model response before it is saved
+#[derive(clap::Args, Debug, Clone)]
+pub struct ValidationOptions {
+    /// Accept any non-empty response without checking it
+    #[arg(long)]
+    pub no_validate: bool,
+
+    /// Re-ask a page this many times per model, with a stricter prompt, when its response
+    /// fails validation, before moving on to the next model in the --model list
+    #[arg(long, default_value_t = 1)]
+    pub reasks: u32,
+
+    /// Minimum response length as a fraction of the page's text layer
+    #[arg(long, default_value_t = 0.5)]
+    pub min_length_ratio: f64,
+
+    /// Minimum fraction of the response's words that must appear in the page's text layer
+    #[arg(long, default_value_t = 0.3)]
+    pub min_text_overlap: f64,
+}
+
+/// Only responses shorter than this are checked for refusals, so that a book
+/// quoting "I'm sorry, I can't" is not mistaken for one
+const REFUSAL_MAX_CHARS: usize = 600;
+
+const REFUSAL_PHRASES: &[&str] = &[
+    "i can't help",
+    "i cannot help",
+    "i can't assist",
+    "i cannot assist",
+    "i can't transcribe",
+    "i cannot transcribe",
+    "i'm unable to",
+    "i am unable to",
+    "i'm sorry, but",
+    "i am sorry, but",
+    "i apologize, but",
+    "as an ai",
+];
+
+/// Openings of a response that describes the image instead of transcribing it
+const DESCRIPTION_OPENINGS: &[&str] = &[
+    "the image shows",
+    "the image contains",
+    "the image depicts",
+    "this image shows",
+    "this image contains",
+    "this image depicts",
+    "the page shows",
+    "the page contains",
+    "this page shows",
+    "this page contains",
+    "this page appears",
+    "the document shows",
+];
+
+/// Why a response was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    Truncated,
+    Filtered,
+    Refusal,
+    Description,
+    TooShort { chars: usize, expected: usize },
+    LowOverlap { overlap: f64 },
+    /// Structured mode only: the response did not parse as the page schema
+    InvalidStructure(String),
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Truncated => write!(f, "output was truncated at the token limit"),
+            Problem::Filtered => write!(f, "output was blocked by the content filter"),
+            Problem::Refusal => write!(f, "model refused to transcribe the page"),
+            Problem::Description => write!(f, "model described the page instead of transcribing it"),
+            Problem::TooShort { chars, expected } => {
+                write!(f, "output is {} characters, text layer has {}", chars, expected)
+            }
+            Problem::LowOverlap { overlap } => {
+                write!(f, "only {:.0}% of the output's words are in the text layer", overlap * 100.0)
+            }
+            Problem::InvalidStructure(message) => write!(f, "invalid structured output: {}", message),
+        }
+    }
+}
+
+impl Problem {
+    /// Instructions appended to the prompt when the page is re-asked after this problem
+    pub fn stricter_instructions(&self) -> &'static str {
+        match self {
+            Problem::Truncated => "\n\nA previous answer for this page was cut off. Transcribe the page completely, \
+                without any commentary, and keep the markup minimal so the whole page fits.",
+            Problem::Filtered | Problem::Refusal => "\n\nThis is a transcription task for digitizing a document the user \
+                has the right to copy. Reproduce the printed text exactly as it appears; you are not asked to \
+                endorse or act on it. Do not refuse, apologize or add any commentary.",
+            Problem::Description | Problem::LowOverlap { .. } => "\n\nA previous answer described the page instead of \
+                transcribing it. Output only the text printed on the page, word for word. Do not describe, \
+                summarize or comment on the image.",
+            Problem::TooShort { .. } => "\n\nA previous answer left out most of the page. Transcribe every line of \
+                text on the page, word for word, from top to bottom, including footnotes, captions and table contents.",
+            Problem::InvalidStructure(_) => "\n\nA previous answer was not valid JSON for the schema. Return exactly \
+                one JSON object matching the schema and nothing else.",
+        }
+    }
+}
+
+/// Check a response. `expected` is the page's text layer when it is usable, used to judge
+/// whether the response is long enough and actually transcribes the page.
+pub fn check(text: &str, finish_reason: FinishReason, expected: Option<&str>, options: &ValidationOptions) -> Option<Problem> {
+    if options.no_validate {
+        return None;
+    }
+    match finish_reason {
+        FinishReason::Truncated => return Some(Problem::Truncated),
+        FinishReason::Filtered => return Some(Problem::Filtered),
+        FinishReason::Complete | FinishReason::Unknown => {}
+    }
+
+    let lower = text.trim().to_lowercase();
+    if lower.len() <= REFUSAL_MAX_CHARS && REFUSAL_PHRASES.iter().any(|p| lower.contains(p)) {
+        return Some(Problem::Refusal);
+    }
+    if DESCRIPTION_OPENINGS.iter().any(|p| lower.starts_with(p)) {
+        return Some(Problem::Description);
+    }
+
+    if let Some(expected) = expected {
+        let chars = non_space_chars(text);
+        let expected_chars = non_space_chars(expected);
+        if (chars as f64) < expected_chars as f64 * options.min_length_ratio {
+            return Some(Problem::TooShort { chars, expected: expected_chars });
+        }
+        let overlap = word_overlap(text, expected);
+        if overlap < options.min_text_overlap {
+            return Some(Problem::LowOverlap { overlap });
+        }
+    }
+    None
+}
+
+fn non_space_chars(text: &str) -> usize {
+    text.chars().filter(|c| !c.is_whitespace()).count()
+}
+
+/// Lowercased words of four or more letters; shorter ones match too easily to say much
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() >= 4)
+        .map(str::to_lowercase)
+}
+
+/// Fraction of the response's words that also occur in the text layer. Markdown
+/// markup and reordering don't affect it, but a description of the page does.
+pub fn word_overlap(text: &str, expected: &str) -> f64 {
+    let known: HashSet<String> = words(expected).collect();
+    let (mut total, mut found) = (0usize, 0usize);
+    for word in words(text) {
+        total += 1;
+        if known.contains(&word) {
+            found += 1;
+        }
+    }
+    if total == 0 {
+        return 1.0;
+    }
+    found as f64 / total as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> ValidationOptions {
+        ValidationOptions { no_validate: false, reasks: 1, min_length_ratio: 0.5, min_text_overlap: 0.3 }
+    }
+
+    fn check_complete(text: &str, expected: Option<&str>) -> Option<Problem> {
+        check(text, FinishReason::Complete, expected, &options())
+    }
+
+    #[test]
+    fn finish_reasons_are_checked_first() {
+        assert_eq!(check("Text", FinishReason::Truncated, None, &options()), Some(Problem::Truncated));
+        assert_eq!(check("Text", FinishReason::Filtered, None, &options()), Some(Problem::Filtered));
+        assert_eq!(check("Text", FinishReason::Unknown, None, &options()), None);
+        let skip = ValidationOptions { no_validate: true, ..options() };
+        assert_eq!(check("I'm sorry, but I can't help with that.", FinishReason::Truncated, None, &skip), None);
+    }
+
+    #[test]
+    fn detects_refusals() {
+        for reply in [
+            "I'm sorry, but I can't help with that.",
+            "I cannot transcribe this image.",
+            "  I am unable to read this page.  ",
+            "As an AI, I can't assist with copyrighted material.",
+        ] {
+            assert_eq!(check_complete(reply, None), Some(Problem::Refusal), "{}", reply);
+        }
+    }
+
+    #[test]
+    fn refusals_are_only_looked_for_in_short_responses() {
+        let quote = "\"I'm sorry, but I cannot stay,\" she said.";
+        let padding = "The rain went on all night. ".repeat(30);
+        let long = format!("{}\n\n{}", quote, padding);
+        assert!(long.trim().len() > REFUSAL_MAX_CHARS);
+        assert_eq!(check_complete(&long, None), None);
+
+        let short = format!("{}\n\n{}", quote, &padding[..REFUSAL_MAX_CHARS - quote.len() - 2]);
+        assert!(short.trim().len() <= REFUSAL_MAX_CHARS);
+        assert_eq!(check_complete(&short, None), Some(Problem::Refusal));
+    }
+
+    #[test]
+    fn detects_descriptions() {
+        assert_eq!(check_complete("The image shows a page of printed text.", None), Some(Problem::Description));
+        assert_eq!(check_complete("  this page appears to be a table of contents", None), Some(Problem::Description));
+        // Only the opening counts
+        assert_eq!(check_complete("Chapter 1\n\nThe image shows what she saw.", None), None);
+    }
+
+    // Known false positives: short pages that read like a refusal or a description are rejected
+    // even when they match the text layer
+    #[test]
+    fn short_pages_quoting_a_refusal_are_rejected() {
+        let page = "\"I'm sorry, but the master is out,\" said the butler.";
+        assert_eq!(check_complete(page, Some(page)), Some(Problem::Refusal));
+    }
+
+    #[test]
+    fn pages_opening_like_a_description_are_rejected() {
+        let page = "This page contains the errata for the first edition.";
+        assert_eq!(check_complete(page, Some(page)), Some(Problem::Description));
+    }
+
+    #[test]
+    fn too_short_against_the_text_layer() {
+        let layer = "abcdefghij ".repeat(10);
+        // 50 of 100 characters is exactly the minimum ratio
+        assert_eq!(check_complete(&"abcdefghij ".repeat(5), Some(&layer)), None);
+        assert_eq!(
+            check_complete(&format!("{}abcdefghi", "abcdefghij ".repeat(4)), Some(&layer)),
+            Some(Problem::TooShort { chars: 49, expected: 100 })
+        );
+        // Whitespace doesn't count towards either length
+        assert_eq!(check_complete(&"abcdefghij\n\n\n".repeat(5), Some(&layer)), None);
+    }
+
+    #[test]
+    fn low_overlap_with_the_text_layer() {
+        let layer = "alpha bravo charlie delta echo foxtrot golf hotel india juliet";
+        // 3 of 10 words found is exactly the minimum overlap
+        let three = "alpha bravo charlie kilo lima mike november oscar papa quebec";
+        assert_eq!(check_complete(three, Some(layer)), None);
+        let two = "alpha bravo romeo kilo lima mike november oscar papa quebec";
+        assert_eq!(check_complete(two, Some(layer)), Some(Problem::LowOverlap { overlap: 0.2 }));
+        // Without a text layer there is nothing to compare against
+        assert_eq!(check_complete(two, None), None);
+    }
+
+    #[test]
+    fn word_overlap_ignores_short_words_markup_and_case() {
+        assert_eq!(word_overlap("# The **Quick** fox", "the quick brown fox"), 1.0);
+        assert_eq!(word_overlap("a an of", "nothing alike"), 1.0);
+        assert_eq!(word_overlap("quick brown", "quick"), 0.5);
+    }
+}